@@ -0,0 +1,704 @@
+//! Negotiates IRCv3 capabilities with upstream networks, both while
+//! registering and as the server adds and removes them later, and offers
+//! capabilities to downstream clients.
+//!
+//! Each client enables its own set of capabilities independently of the
+//! upstream connection and of other clients, so messages from the server
+//! are translated for each client: tags it hasn't asked for are stripped,
+//! and extensions it has asked for that the server lacks are synthesized
+//! where possible.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use log::{debug, info, warn};
+
+use super::client::BOUNCER_PREFIX;
+use super::config::Sasl;
+use super::irc::{Message, Tag};
+use super::sasl;
+use super::state::NetworkState;
+
+/// Capabilities the bouncer understands and requests whenever they're
+/// offered. SASL is requested separately, and only when configured.
+pub const SUPPORTED: &[&str] = &[
+    "server-time",
+    "message-tags",
+    "multi-prefix",
+    "away-notify",
+    "account-notify",
+    "extended-join",
+    "chghost",
+    "batch",
+    "echo-message",
+    "cap-notify",
+];
+
+/// The capabilities a server has advertised and which of them are enabled
+/// on the current connection.
+#[derive(Debug, Default)]
+pub struct Capabilities {
+    /// Advertised capabilities and their values, e.g. the mechanisms listed
+    /// in `sasl=PLAIN,EXTERNAL`.
+    available: BTreeMap<String, Option<String>>,
+    enabled: BTreeSet<String>,
+}
+
+impl Capabilities {
+    pub fn is_enabled(&self, name: &str) -> bool {
+        self.enabled.contains(name)
+    }
+
+    pub fn is_available(&self, name: &str) -> bool {
+        self.available.contains_key(name)
+    }
+
+    pub fn value(&self, name: &str) -> Option<&str> {
+        self.available.get(name).and_then(|value| value.as_deref())
+    }
+
+    /// Updates the advertised and enabled capabilities from a CAP message.
+    pub fn update(&mut self, message: &Message) {
+        let params = message.params();
+        if message.command() != "CAP" || params.len() < 3 {
+            return;
+        }
+        let caps = params[params.len() - 1].split_whitespace();
+
+        match params[1].as_str() {
+            "LS" | "NEW" => {
+                for cap in caps {
+                    let (name, value) = match cap.split_once('=') {
+                        Some((name, value)) => (name, Some(value.to_string())),
+                        None => (cap, None),
+                    };
+                    self.available.insert(name.to_string(), value);
+                }
+            }
+            "ACK" => {
+                for cap in caps {
+                    match cap.strip_prefix('-') {
+                        Some(name) => self.enabled.remove(name),
+                        None => self.enabled.insert(cap.to_string()),
+                    };
+                }
+            }
+            "DEL" => {
+                for cap in caps {
+                    self.available.remove(cap);
+                    self.enabled.remove(cap);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Returns whether `message` belongs to capability negotiation or SASL,
+/// neither of which clients should see from the upstream connection.
+pub fn is_negotiation(message: &Message) -> bool {
+    match message.command() {
+        "CAP" | "AUTHENTICATE" => true,
+        // RPL_LOGGEDIN through RPL_SASLMECHS
+        "900" | "901" | "902" | "903" | "904" | "905" | "906" | "907" | "908" => true,
+        _ => false,
+    }
+}
+
+/// Drives capability negotiation for a single connection. Registration is
+/// held open with `CAP LS` until every request has been answered and SASL,
+/// if configured, has finished.
+pub struct Negotiation {
+    sasl: Option<sasl::Authentication>,
+    /// REQs sent that the server hasn't yet ACKed or NAKed.
+    pending: usize,
+    /// Whether `CAP END` has been sent or the server ignored CAP entirely.
+    finished: bool,
+}
+
+impl Negotiation {
+    pub fn new(sasl: Option<&Sasl>) -> Self {
+        Negotiation {
+            sasl: sasl.map(sasl::Authentication::new),
+            pending: 0,
+            finished: false,
+        }
+    }
+
+    /// The message that starts negotiation, sent before NICK and USER so
+    /// that the server holds registration until it's over.
+    pub fn start() -> Message {
+        Message::new(None, "CAP", vec!["LS".to_string(), "302".to_string()])
+    }
+
+    fn request(&mut self, caps: &[&str]) -> Message {
+        self.pending += 1;
+        Message::new(None, "CAP", vec!["REQ".to_string(), caps.join(" ")])
+    }
+
+    fn fail_sasl(&mut self, reason: &str) -> Result<()> {
+        match &mut self.sasl {
+            Some(sasl) => sasl.fail(reason),
+            None => Ok(()),
+        }
+    }
+
+    /// Ends negotiation once nothing is outstanding.
+    fn end_if_done(&mut self, replies: &mut Vec<Message>) {
+        let authenticating = self.sasl.as_ref().is_some_and(|sasl| !sasl.is_finished());
+        if !self.finished && self.pending == 0 && !authenticating {
+            self.finished = true;
+            replies.push(Message::new(None, "CAP", vec!["END".to_string()]));
+        }
+    }
+
+    /// Handles a message from the server after it has been applied to the
+    /// network state, returning the replies to send.
+    pub fn handle(&mut self, message: &Message, state: &NetworkState) -> Result<Vec<Message>> {
+        let params = message.params();
+        let caps = state.caps();
+        let mut replies = Vec::new();
+
+        match (message.command(), params.get(1).map(|s| s.as_str())) {
+            // A "*" before the list means more lines follow
+            ("CAP", Some("LS")) if params.len() > 3 && params[2] == "*" => {}
+            ("CAP", Some("LS")) if !self.finished => {
+                let wanted: Vec<&str> = SUPPORTED
+                    .iter()
+                    .copied()
+                    .filter(|cap| caps.is_available(cap))
+                    .collect();
+                if !wanted.is_empty() {
+                    replies.push(self.request(&wanted));
+                }
+
+                // Requested on its own so that a NAK of any other
+                // capability doesn't take SASL down with it
+                match self.sasl.as_ref().map(|sasl| sasl.check_available(caps)) {
+                    Some(Ok(())) => replies.push(self.request(&["sasl"])),
+                    Some(Err(reason)) => self.fail_sasl(&reason)?,
+                    None => {}
+                }
+
+                self.end_if_done(&mut replies);
+            }
+            ("CAP", Some("ACK")) | ("CAP", Some("NAK")) => {
+                let acked = params[1] == "ACK";
+                let list = params.last().map(|s| s.as_str()).unwrap_or_default();
+                self.pending = self.pending.saturating_sub(1);
+
+                if acked {
+                    debug!("enabled capabilities: {}", list);
+                } else {
+                    warn!("server refused capabilities: {}", list);
+                }
+
+                if let Some(sasl) = &mut self.sasl {
+                    if !sasl.is_finished() && list.split_whitespace().any(|cap| cap == "sasl") {
+                        if acked {
+                            replies.push(sasl.begin());
+                        } else {
+                            sasl.fail("server refused the sasl capability")?;
+                        }
+                    }
+                }
+
+                self.end_if_done(&mut replies);
+            }
+            ("CAP", Some("NEW")) => {
+                let wanted: Vec<&str> = params
+                    .last()
+                    .map(|s| s.as_str())
+                    .unwrap_or_default()
+                    .split_whitespace()
+                    .map(|cap| cap.split('=').next().unwrap_or_default())
+                    .filter(|cap| SUPPORTED.contains(cap) && !caps.is_enabled(cap))
+                    .collect();
+                if !wanted.is_empty() {
+                    info!("server offered new capabilities: {}", wanted.join(" "));
+                    replies.push(self.request(&wanted));
+                }
+            }
+            ("CAP", Some("DEL")) => {
+                info!(
+                    "server removed capabilities: {}",
+                    params.last().map(|s| s.as_str()).unwrap_or_default()
+                );
+            }
+            // Registration finishing means the server ignored CAP entirely
+            ("001", _) if !self.finished => {
+                self.finished = true;
+                if let Some(sasl) = &mut self.sasl {
+                    if !sasl.is_finished() {
+                        sasl.fail("server doesn't support capability negotiation")?;
+                    }
+                }
+            }
+            _ => {
+                if let Some(sasl) = &mut self.sasl {
+                    replies.extend(sasl.handle(message)?);
+                    self.end_if_done(&mut replies);
+                }
+            }
+        }
+
+        Ok(replies)
+    }
+}
+
+/// Formats `time` as a `time` tag value.
+pub fn format_server_time(time: &DateTime<Utc>) -> String {
+    time.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string()
+}
+
+/// Parses a `time` tag value, which servers may send in any timezone.
+pub fn parse_server_time(time: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(time)
+        .ok()
+        .map(|time| time.with_timezone(&Utc))
+}
+
+/// Capabilities offered to clients. The bouncer provides the first few
+/// itself; the rest are only acted upon when the server supports them.
+pub const OFFERED: &[&str] = &[
+    "server-time",
+    "message-tags",
+    "batch",
+    "echo-message",
+    "multi-prefix",
+    "cap-notify",
+    "extended-join",
+    "away-notify",
+    "account-notify",
+    "chghost",
+    "draft/chathistory",
+    "soju.im/search",
+];
+
+/// The capabilities a single client has enabled, and the client's side of
+/// the negotiation.
+#[derive(Debug, Default)]
+pub struct ClientCapabilities {
+    enabled: BTreeSet<String>,
+    /// Whether the client has sent `CAP LS` or `CAP REQ` but not `CAP END`,
+    /// which holds registration open.
+    negotiating: bool,
+}
+
+impl ClientCapabilities {
+    pub fn is_enabled(&self, name: &str) -> bool {
+        self.enabled.contains(name)
+    }
+
+    pub fn is_negotiating(&self) -> bool {
+        self.negotiating
+    }
+
+    fn reply(nick: &str, subcommand: &str, caps: &str) -> Message {
+        Message::new(
+            BOUNCER_PREFIX.parse().ok(),
+            "CAP",
+            vec![nick.to_string(), subcommand.to_string(), caps.to_string()],
+        )
+    }
+
+    /// Handles a CAP command from the client, returning the replies.
+    /// `nick` is `None` until the client has sent NICK.
+    pub fn handle(&mut self, message: &Message, nick: Option<&str>) -> Vec<Message> {
+        let params = message.params();
+        let nick = nick.unwrap_or("*");
+        let subcommand = params.first().map(|s| s.to_ascii_uppercase());
+
+        match subcommand.as_deref() {
+            Some("LS") => {
+                self.negotiating = true;
+                // Version 302 and later implicitly enable cap-notify
+                if params.get(1).and_then(|v| v.parse::<u32>().ok()) >= Some(302) {
+                    self.enabled.insert("cap-notify".to_string());
+                }
+                vec![ClientCapabilities::reply(nick, "LS", &OFFERED.join(" "))]
+            }
+            Some("LIST") => {
+                let enabled: Vec<&str> = self.enabled.iter().map(|cap| cap.as_str()).collect();
+                vec![ClientCapabilities::reply(nick, "LIST", &enabled.join(" "))]
+            }
+            Some("REQ") => {
+                self.negotiating = true;
+                let requested = params.get(1).map(|s| s.as_str()).unwrap_or_default();
+
+                // Requests are all or nothing
+                let known = requested
+                    .split_whitespace()
+                    .all(|cap| OFFERED.contains(&cap.trim_start_matches('-')));
+                if !known {
+                    return vec![ClientCapabilities::reply(nick, "NAK", requested)];
+                }
+
+                for cap in requested.split_whitespace() {
+                    match cap.strip_prefix('-') {
+                        Some(name) => self.enabled.remove(name),
+                        None => self.enabled.insert(cap.to_string()),
+                    };
+                }
+                vec![ClientCapabilities::reply(nick, "ACK", requested)]
+            }
+            Some("END") => {
+                self.negotiating = false;
+                Vec::new()
+            }
+            _ => {
+                let subcommand = params.first().map(|s| s.as_str()).unwrap_or_default();
+                vec![Message::new(
+                    BOUNCER_PREFIX.parse().ok(),
+                    // ERR_INVALIDCAPCMD
+                    "410",
+                    vec![
+                        nick.to_string(),
+                        subcommand.to_string(),
+                        "Invalid CAP command".to_string(),
+                    ],
+                )]
+            }
+        }
+    }
+
+    /// Returns whether the client may be sent `tag`.
+    fn accepts_tag(&self, tag: &Tag) -> bool {
+        match tag.key() {
+            "time" => self.is_enabled("server-time"),
+            "batch" => self.is_enabled("batch"),
+            _ => self.is_enabled("message-tags"),
+        }
+    }
+
+    /// Adapts a message from the server to the capabilities the client has
+    /// enabled, returning `None` if the client shouldn't see it at all.
+    /// `prefix_symbols` are the server's channel membership prefixes, from
+    /// highest to lowest.
+    pub fn translate(&self, mut message: Message, prefix_symbols: &str) -> Option<Message> {
+        message.retain_tags(|tag| self.accepts_tag(tag));
+
+        let enabled = match message.command() {
+            "TAGMSG" => self.is_enabled("message-tags"),
+            "BATCH" => self.is_enabled("batch"),
+            "AWAY" => self.is_enabled("away-notify"),
+            "ACCOUNT" => self.is_enabled("account-notify"),
+            "CHGHOST" => self.is_enabled("chghost"),
+            _ => true,
+        };
+        if !enabled {
+            return None;
+        }
+
+        let extended_join = self.is_enabled("extended-join");
+        let multi_prefix = self.is_enabled("multi-prefix");
+        let command = message.command().to_string();
+        let params = message.params_mut();
+
+        match command.as_str() {
+            "JOIN" if extended_join && params.len() == 1 => {
+                // The account and real name are unknown without the
+                // server's help
+                params.push("*".to_string());
+                params.push(String::new());
+            }
+            "JOIN" if !extended_join => params.truncate(1),
+            "353" if !multi_prefix && params.len() >= 4 => {
+                params[3] = params[3]
+                    .split_whitespace()
+                    .map(|name| {
+                        let nick = name.trim_start_matches(|c| prefix_symbols.contains(c));
+                        match name.chars().next() {
+                            Some(highest) if nick.len() < name.len() => {
+                                format!("{}{}", highest, nick)
+                            }
+                            _ => nick.to_string(),
+                        }
+                    })
+                    .collect::<Vec<String>>()
+                    .join(" ");
+            }
+            _ => {}
+        }
+
+        Some(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::str::FromStr;
+
+    use anyhow::Result;
+
+    use crate::config::{SaslFailurePolicy, SaslMechanism};
+
+    fn sasl_config(on_failure: SaslFailurePolicy) -> Sasl {
+        Sasl {
+            mechanism: SaslMechanism::Plain,
+            account: Some("jay".to_string()),
+            password: Some("hunter2".to_string()),
+            on_failure,
+        }
+    }
+
+    /// Feeds `line` through the state and negotiation as the read worker
+    /// does, returning the replies as strings.
+    fn handle(
+        negotiation: &mut Negotiation,
+        state: &mut NetworkState,
+        line: &str,
+    ) -> Result<Vec<String>> {
+        let message = Message::from_str(line)?;
+        state.update(&message);
+        Ok(negotiation
+            .handle(&message, state)?
+            .iter()
+            .map(|message| message.to_string())
+            .collect())
+    }
+
+    #[test]
+    fn test_negotiation_without_sasl() -> Result<()> {
+        let mut negotiation = Negotiation::new(None);
+        let mut state = NetworkState::default();
+
+        assert!(handle(
+            &mut negotiation,
+            &mut state,
+            ":irc.test CAP * LS * :multi-prefix sasl=PLAIN unknown-cap",
+        )?
+        .is_empty());
+        assert_eq!(
+            handle(
+                &mut negotiation,
+                &mut state,
+                ":irc.test CAP * LS :server-time away-notify",
+            )?,
+            vec!["CAP REQ :server-time multi-prefix away-notify"],
+        );
+        assert_eq!(
+            handle(
+                &mut negotiation,
+                &mut state,
+                ":irc.test CAP * ACK :server-time multi-prefix away-notify",
+            )?,
+            vec!["CAP END"],
+        );
+        assert!(state.caps().is_enabled("multi-prefix"));
+        assert!(!state.caps().is_enabled("sasl"));
+
+        // Negotiated capabilities outlive the welcome
+        handle(&mut negotiation, &mut state, ":irc.test 001 jay :Welcome")?;
+        assert!(state.caps().is_enabled("server-time"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_negotiation_with_sasl() -> Result<()> {
+        let mut negotiation = Negotiation::new(Some(&sasl_config(SaslFailurePolicy::Disconnect)));
+        let mut state = NetworkState::default();
+
+        assert_eq!(
+            handle(
+                &mut negotiation,
+                &mut state,
+                ":irc.test CAP * LS :batch sasl=PLAIN,EXTERNAL",
+            )?,
+            vec!["CAP REQ batch", "CAP REQ sasl"],
+        );
+        assert!(handle(&mut negotiation, &mut state, ":irc.test CAP * NAK :batch")?.is_empty());
+        assert_eq!(
+            handle(&mut negotiation, &mut state, ":irc.test CAP * ACK :sasl")?,
+            vec!["AUTHENTICATE PLAIN"],
+        );
+        assert_eq!(
+            handle(&mut negotiation, &mut state, "AUTHENTICATE +")?,
+            vec!["AUTHENTICATE amF5AGpheQBodW50ZXIy"],
+        );
+        assert_eq!(
+            handle(
+                &mut negotiation,
+                &mut state,
+                ":irc.test 903 * :SASL authentication successful",
+            )?,
+            vec!["CAP END"],
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sasl_unavailable() -> Result<()> {
+        let mut negotiation = Negotiation::new(Some(&sasl_config(SaslFailurePolicy::Disconnect)));
+        let mut state = NetworkState::default();
+        assert!(handle(&mut negotiation, &mut state, ":irc.test CAP * LS :batch").is_err());
+
+        let mut negotiation = Negotiation::new(Some(&sasl_config(SaslFailurePolicy::Continue)));
+        let mut state = NetworkState::default();
+        assert_eq!(
+            handle(
+                &mut negotiation,
+                &mut state,
+                ":irc.test CAP * LS :sasl=EXTERNAL"
+            )?,
+            vec!["CAP END"],
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_new_and_del() -> Result<()> {
+        let mut negotiation = Negotiation::new(None);
+        let mut state = NetworkState::default();
+
+        handle(
+            &mut negotiation,
+            &mut state,
+            ":irc.test CAP * LS :cap-notify",
+        )?;
+        handle(
+            &mut negotiation,
+            &mut state,
+            ":irc.test CAP * ACK :cap-notify",
+        )?;
+        assert_eq!(
+            handle(
+                &mut negotiation,
+                &mut state,
+                ":irc.test CAP jay NEW :away-notify cap-notify draft/unknown",
+            )?,
+            vec!["CAP REQ away-notify"],
+        );
+        handle(
+            &mut negotiation,
+            &mut state,
+            ":irc.test CAP jay ACK :away-notify",
+        )?;
+        assert!(state.caps().is_enabled("away-notify"));
+
+        handle(
+            &mut negotiation,
+            &mut state,
+            ":irc.test CAP jay DEL :away-notify",
+        )?;
+        assert!(!state.caps().is_enabled("away-notify"));
+        assert!(!state.caps().is_available("away-notify"));
+
+        Ok(())
+    }
+
+    fn client_lines(messages: Vec<Message>) -> Vec<String> {
+        messages.iter().map(|message| message.to_string()).collect()
+    }
+
+    #[test]
+    fn test_client_negotiation() -> Result<()> {
+        let mut caps = ClientCapabilities::default();
+
+        let ls = client_lines(caps.handle(&Message::from_str("CAP LS 302")?, None));
+        assert_eq!(ls.len(), 1);
+        assert!(ls[0].starts_with(":bounce CAP * LS :server-time message-tags"));
+        assert!(caps.is_negotiating());
+        assert!(caps.is_enabled("cap-notify"));
+
+        assert_eq!(
+            client_lines(caps.handle(
+                &Message::from_str("CAP REQ :server-time sasl")?,
+                Some("jay")
+            )),
+            vec![":bounce CAP jay NAK :server-time sasl"],
+        );
+        assert!(!caps.is_enabled("server-time"));
+
+        assert_eq!(
+            client_lines(caps.handle(
+                &Message::from_str("CAP REQ :server-time -cap-notify")?,
+                Some("jay")
+            )),
+            vec![":bounce CAP jay ACK :server-time -cap-notify"],
+        );
+        assert_eq!(
+            client_lines(caps.handle(&Message::from_str("CAP LIST")?, Some("jay"))),
+            vec![":bounce CAP jay LIST server-time"],
+        );
+
+        assert!(caps
+            .handle(&Message::from_str("CAP END")?, Some("jay"))
+            .is_empty());
+        assert!(!caps.is_negotiating());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_translate_tags() -> Result<()> {
+        let message = Message::from_str(
+            "@time=2020-04-01T12:00:00.000Z;msgid=abc;+typing=active :jay PRIVMSG #test :hi",
+        )?;
+
+        let mut caps = ClientCapabilities::default();
+        assert_eq!(
+            caps.translate(message.clone(), "@+")
+                .map(|message| message.to_string()),
+            Some(":jay PRIVMSG #test hi".to_string()),
+        );
+
+        caps.handle(&Message::from_str("CAP REQ server-time")?, None);
+        assert_eq!(
+            caps.translate(message.clone(), "@+")
+                .map(|message| message.to_string()),
+            Some("@time=2020-04-01T12:00:00.000Z :jay PRIVMSG #test hi".to_string()),
+        );
+
+        caps.handle(&Message::from_str("CAP REQ message-tags")?, None);
+        assert_eq!(caps.translate(message.clone(), "@+"), Some(message));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_translate_commands() -> Result<()> {
+        let mut caps = ClientCapabilities::default();
+        let translate = |caps: &ClientCapabilities, line: &str| -> Result<Option<String>> {
+            Ok(caps
+                .translate(Message::from_str(line)?, "@%+")
+                .map(|message| message.to_string()))
+        };
+
+        assert_eq!(translate(&caps, "@+typing=active :jay TAGMSG #test")?, None);
+        assert_eq!(translate(&caps, ":jay AWAY :lunch")?, None);
+        assert_eq!(
+            translate(&caps, ":jay!j@h JOIN #test jay :Jay")?,
+            Some(":jay!j@h JOIN #test".to_string()),
+        );
+        assert_eq!(
+            translate(&caps, ":irc.test 353 jay = #test :@%op +voiced plain")?,
+            Some(":irc.test 353 jay = #test :@op +voiced plain".to_string()),
+        );
+
+        caps.handle(
+            &Message::from_str("CAP REQ :away-notify extended-join multi-prefix")?,
+            None,
+        );
+        assert_eq!(
+            translate(&caps, ":jay AWAY :lunch")?,
+            Some(":jay AWAY lunch".to_string()),
+        );
+        assert_eq!(
+            translate(&caps, ":jay!j@h JOIN #test")?,
+            Some(":jay!j@h JOIN #test * :".to_string()),
+        );
+        assert_eq!(
+            translate(&caps, ":irc.test 353 jay = #test :@%op")?,
+            Some(":irc.test 353 jay = #test @%op".to_string()),
+        );
+
+        Ok(())
+    }
+}
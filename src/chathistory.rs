@@ -0,0 +1,600 @@
+//! Serves the `draft/chathistory` extension, which lets clients fetch
+//! history from the logs on demand instead of relying on replay.
+
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use chrono::{DateTime, Utc};
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+
+use super::cap::{format_server_time, parse_server_time};
+use super::client::BOUNCER_PREFIX;
+use super::irc::{Message, Prefix};
+use super::state::NetworkState;
+
+/// The most messages returned for a single request, advertised to clients
+/// in ISUPPORT.
+pub const MAX_LIMIT: usize = 100;
+
+/// Commands stored messages must have to be part of history.
+const HISTORY_COMMANDS: &[&str] = &["PRIVMSG", "NOTICE"];
+
+/// Points in history that requests are relative to.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Reference {
+    Timestamp(DateTime<Utc>),
+    MsgId(String),
+}
+
+impl FromStr for Reference {
+    type Err = Fail;
+
+    fn from_str(reference: &str) -> Result<Self, Self::Err> {
+        match reference.split_once('=') {
+            Some(("timestamp", timestamp)) => parse_server_time(timestamp)
+                .map(Reference::Timestamp)
+                .ok_or_else(|| Fail::invalid_params(reference, "Invalid timestamp")),
+            Some(("msgid", msgid)) if !msgid.is_empty() => Ok(Reference::MsgId(msgid.to_string())),
+            _ => Err(Fail::invalid_params(reference, "Invalid message reference")),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Query {
+    /// The most recent messages, optionally only those after a reference.
+    Latest {
+        target: String,
+        after: Option<Reference>,
+        limit: usize,
+    },
+    Before {
+        target: String,
+        reference: Reference,
+        limit: usize,
+    },
+    After {
+        target: String,
+        reference: Reference,
+        limit: usize,
+    },
+    Around {
+        target: String,
+        reference: Reference,
+        limit: usize,
+    },
+    Between {
+        target: String,
+        start: Reference,
+        end: Reference,
+        limit: usize,
+    },
+    /// The conversations with messages between two times.
+    Targets {
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        limit: usize,
+    },
+}
+
+/// A request that couldn't be served, reported to the client as a
+/// standard `FAIL` reply.
+#[derive(Debug, PartialEq)]
+pub struct Fail {
+    command: &'static str,
+    code: &'static str,
+    context: Vec<String>,
+    description: &'static str,
+}
+
+impl Fail {
+    pub fn new(
+        command: &'static str,
+        code: &'static str,
+        context: Vec<String>,
+        description: &'static str,
+    ) -> Self {
+        Fail {
+            command,
+            code,
+            context,
+            description,
+        }
+    }
+
+    fn invalid_params(param: &str, description: &'static str) -> Self {
+        Fail::new(
+            "CHATHISTORY",
+            "INVALID_PARAMS",
+            vec![param.to_string()],
+            description,
+        )
+    }
+
+    pub fn to_message(&self) -> Message {
+        let mut params = vec![self.command.to_string(), self.code.to_string()];
+        params.extend(self.context.iter().cloned());
+        params.push(self.description.to_string());
+
+        Message::new(BOUNCER_PREFIX.parse().ok(), "FAIL", params)
+    }
+}
+
+/// Returns when a stored message was received, from its `time` tag.
+fn message_time(message: &Message) -> Option<DateTime<Utc>> {
+    message
+        .tag("time")
+        .and_then(|tag| tag.value())
+        .and_then(parse_server_time)
+}
+
+fn parse_limit(limit: &str) -> Result<usize, Fail> {
+    match limit.parse::<usize>() {
+        Ok(limit) if limit > 0 => Ok(limit.min(MAX_LIMIT)),
+        _ => Err(Fail::invalid_params(limit, "Invalid limit")),
+    }
+}
+
+impl Query {
+    /// Parses a CHATHISTORY command from a client.
+    pub fn parse(message: &Message) -> Result<Query, Fail> {
+        let params = message.params();
+        let subcommand = params
+            .first()
+            .map(|s| s.to_ascii_uppercase())
+            .unwrap_or_default();
+
+        let needed = match subcommand.as_str() {
+            "LATEST" | "BEFORE" | "AFTER" | "AROUND" | "TARGETS" => 4,
+            "BETWEEN" => 5,
+            _ => {
+                return Err(Fail::new(
+                    "CHATHISTORY",
+                    "INVALID_PARAMS",
+                    vec![subcommand],
+                    "Unknown subcommand",
+                ))
+            }
+        };
+        if params.len() < needed {
+            return Err(Fail::new(
+                "CHATHISTORY",
+                "NEED_MORE_PARAMS",
+                vec![subcommand],
+                "Missing parameters",
+            ));
+        }
+
+        let target = params[1].clone();
+        let limit = parse_limit(&params[needed - 1])?;
+
+        Ok(match subcommand.as_str() {
+            "LATEST" => Query::Latest {
+                target,
+                after: match params[2].as_str() {
+                    "*" => None,
+                    reference => Some(reference.parse()?),
+                },
+                limit,
+            },
+            "BEFORE" => Query::Before {
+                target,
+                reference: params[2].parse()?,
+                limit,
+            },
+            "AFTER" => Query::After {
+                target,
+                reference: params[2].parse()?,
+                limit,
+            },
+            "AROUND" => Query::Around {
+                target,
+                reference: params[2].parse()?,
+                limit,
+            },
+            "BETWEEN" => Query::Between {
+                target,
+                start: params[2].parse()?,
+                end: params[3].parse()?,
+                limit,
+            },
+            _ => match (params[1].parse()?, params[2].parse()?) {
+                (Reference::Timestamp(start), Reference::Timestamp(end)) => Query::Targets {
+                    start: start.min(end),
+                    end: start.max(end),
+                    limit,
+                },
+                _ => {
+                    return Err(Fail::invalid_params(
+                        &params[1],
+                        "TARGETS only accepts timestamps",
+                    ))
+                }
+            },
+        })
+    }
+
+    /// Returns the references that no message before the earliest of can
+    /// be part of the results.
+    pub fn lower_bounds(&self) -> Vec<&Reference> {
+        match self {
+            Query::Latest {
+                after: Some(reference),
+                ..
+            }
+            | Query::After { reference, .. } => vec![reference],
+            Query::Between { start, end, .. } => vec![start, end],
+            _ => Vec::new(),
+        }
+    }
+
+    /// For queries for the last messages up to a reference, or up to now
+    /// if it's `None`, returns that reference and how many messages they
+    /// want. These can be answered by reading back from the reference
+    /// instead of from the start of history.
+    pub fn reads_back(&self) -> Option<(Option<&Reference>, usize)> {
+        match self {
+            Query::Latest {
+                after: None, limit, ..
+            } => Some((None, *limit)),
+            Query::Before {
+                reference, limit, ..
+            } => Some((Some(reference), *limit)),
+            _ => None,
+        }
+    }
+
+    pub fn target(&self) -> Option<&str> {
+        match self {
+            Query::Latest { target, .. }
+            | Query::Before { target, .. }
+            | Query::After { target, .. }
+            | Query::Around { target, .. }
+            | Query::Between { target, .. } => Some(target),
+            Query::Targets { .. } => None,
+        }
+    }
+}
+
+/// Returns the channel or nick a stored message was part of a
+/// conversation with.
+fn conversation(message: &Message, state: &NetworkState) -> Option<String> {
+    if !HISTORY_COMMANDS.contains(&message.command()) {
+        return None;
+    }
+
+    let target = message.params().first()?;
+    let source = message.prefix().map(|prefix| prefix.entity())?;
+
+    if state.is_channel(target) || state.is_own_nick(source) {
+        Some(target.clone())
+    } else {
+        Some(source.to_string())
+    }
+}
+
+/// Returns the index of the first message after everything before
+/// `reference` and the index of the first message after `reference`
+/// itself, or `None` if a msgid doesn't match any message.
+fn bounds(messages: &[&Message], reference: &Reference) -> Option<(usize, usize)> {
+    match reference {
+        Reference::Timestamp(time) => Some((
+            messages.partition_point(|m| message_time(m).is_none_or(|t| t < *time)),
+            messages.partition_point(|m| message_time(m).is_none_or(|t| t <= *time)),
+        )),
+        Reference::MsgId(msgid) => messages
+            .iter()
+            .position(|m| m.tag("msgid").and_then(|tag| tag.value()) == Some(msgid))
+            .map(|index| (index, index + 1)),
+    }
+}
+
+fn first(messages: &[&Message], limit: usize) -> Vec<Message> {
+    messages.iter().take(limit).map(|m| (*m).clone()).collect()
+}
+
+fn last(messages: &[&Message], limit: usize) -> Vec<Message> {
+    messages[messages.len().saturating_sub(limit)..]
+        .iter()
+        .map(|m| (*m).clone())
+        .collect()
+}
+
+/// Runs `query` against `messages`, which must be in the order they were
+/// received. Returns history in the same order, or for TARGETS a
+/// `CHATHISTORY TARGETS` line per conversation.
+pub fn run(query: &Query, messages: &[Message], state: &NetworkState) -> Vec<Message> {
+    let casemapping = state.casemapping();
+
+    if let Query::Targets { start, end, limit } = query {
+        // Keyed by folded name, holding the name and latest time
+        let mut latest: BTreeMap<String, (String, DateTime<Utc>)> = BTreeMap::new();
+        for message in messages {
+            let time = match message_time(message) {
+                Some(time) if time > *start && time < *end => time,
+                _ => continue,
+            };
+            if let Some(name) = conversation(message, state) {
+                latest.insert(casemapping.fold(&name), (name, time));
+            }
+        }
+
+        let mut targets: Vec<(String, DateTime<Utc>)> = latest.into_values().collect();
+        targets.sort_by_key(|(_, time)| *time);
+
+        return targets
+            .into_iter()
+            .take(*limit)
+            .map(|(name, time)| {
+                Message::new(
+                    BOUNCER_PREFIX.parse().ok(),
+                    "CHATHISTORY",
+                    vec!["TARGETS".to_string(), name, format_server_time(&time)],
+                )
+            })
+            .collect();
+    }
+
+    let target = casemapping.fold(query.target().unwrap_or_default());
+    let messages: Vec<&Message> = messages
+        .iter()
+        .filter(|m| {
+            conversation(m, state).map(|name| casemapping.fold(&name)) == Some(target.clone())
+        })
+        .collect();
+
+    match query {
+        Query::Latest { after, limit, .. } => match after {
+            None => last(&messages, *limit),
+            Some(after) => match bounds(&messages, after) {
+                Some((_, start)) => last(&messages[start..], *limit),
+                None => Vec::new(),
+            },
+        },
+        Query::Before {
+            reference, limit, ..
+        } => match bounds(&messages, reference) {
+            Some((end, _)) => last(&messages[..end], *limit),
+            None => Vec::new(),
+        },
+        Query::After {
+            reference, limit, ..
+        } => match bounds(&messages, reference) {
+            Some((_, start)) => first(&messages[start..], *limit),
+            None => Vec::new(),
+        },
+        Query::Around {
+            reference, limit, ..
+        } => match bounds(&messages, reference) {
+            Some((middle, _)) => {
+                let mut around = last(&messages[..middle], limit / 2);
+                around.extend(first(&messages[middle..], limit - around.len()));
+                around
+            }
+            None => Vec::new(),
+        },
+        Query::Between {
+            start, end, limit, ..
+        } => match (bounds(&messages, start), bounds(&messages, end)) {
+            (Some((_, after_start)), Some((before_end, _))) if after_start <= before_end => {
+                first(&messages[after_start..before_end], *limit)
+            }
+            // Counting backwards from a start later than the end
+            (Some((before_start, _)), Some((_, after_end))) if after_end <= before_start => {
+                last(&messages[after_end..before_start], *limit)
+            }
+            _ => Vec::new(),
+        },
+        Query::Targets { .. } => Vec::new(),
+    }
+}
+
+/// Frames the results of `query` in a batch for the client.
+pub fn batch(query: &Query, results: Vec<Message>) -> Vec<Message> {
+    match query.target() {
+        Some(target) => frame(vec!["chathistory".to_string(), target.to_string()], results),
+        None => frame(vec!["draft/chathistory-targets".to_string()], results),
+    }
+}
+
+/// Frames `results` in a batch of the type and parameters in `kind`.
+pub fn frame(kind: Vec<String>, results: Vec<Message>) -> Vec<Message> {
+    let id: String = thread_rng().sample_iter(&Alphanumeric).take(12).collect();
+    let prefix: Option<Prefix> = BOUNCER_PREFIX.parse().ok();
+
+    let mut start = vec![format!("+{}", id)];
+    start.extend(kind);
+
+    let mut messages = vec![Message::new(prefix.clone(), "BATCH", start)];
+    for mut message in results {
+        message.set_tag("batch", Some(&id));
+        messages.push(message);
+    }
+    messages.push(Message::new(prefix, "BATCH", vec![format!("-{}", id)]));
+
+    messages
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use anyhow::{format_err, Result};
+
+    fn state() -> Result<NetworkState> {
+        let mut state = NetworkState::default();
+        state.update(&Message::from_str(":irc.test 001 jay :Welcome")?);
+        Ok(state)
+    }
+
+    fn stored(minute: u32, msgid: &str, line: &str) -> Result<Message> {
+        Message::from_str(&format!(
+            "@time=2020-04-01T12:{:02}:00.000Z;msgid={} {}",
+            minute, msgid, line
+        ))
+        .map_err(|e| e.into())
+    }
+
+    fn messages() -> Result<Vec<Message>> {
+        Ok(vec![
+            stored(0, "a", ":belak PRIVMSG #test :one")?,
+            stored(1, "b", ":belak PRIVMSG jay :private")?,
+            stored(2, "c", ":jay PRIVMSG #test :two")?,
+            stored(3, "d", ":belak!b@h JOIN #test")?,
+            stored(4, "e", ":jay PRIVMSG Belak :reply")?,
+            stored(5, "f", ":belak NOTICE #TEST :three")?,
+            stored(6, "g", ":belak PRIVMSG #test :four")?,
+        ])
+    }
+
+    fn msgids(results: &[Message]) -> Vec<&str> {
+        results
+            .iter()
+            .filter_map(|m| m.tag("msgid").and_then(|tag| tag.value()))
+            .collect()
+    }
+
+    fn query(line: &str) -> Result<Query> {
+        Query::parse(&Message::from_str(line)?).map_err(|e| format_err!("{:?}", e))
+    }
+
+    #[test]
+    fn test_parse() -> Result<()> {
+        assert_eq!(
+            query("CHATHISTORY LATEST #test * 500")?,
+            Query::Latest {
+                target: "#test".to_string(),
+                after: None,
+                limit: MAX_LIMIT,
+            },
+        );
+        assert_eq!(
+            query("CHATHISTORY BEFORE #test timestamp=2020-04-01T12:00:00.000Z 10")?,
+            Query::Before {
+                target: "#test".to_string(),
+                reference: Reference::Timestamp(parse_server_time("2020-04-01T12:00:00Z").unwrap()),
+                limit: 10,
+            },
+        );
+
+        let fail = |line: &str| -> Result<&'static str> {
+            Ok(Query::parse(&Message::from_str(line)?).unwrap_err().code)
+        };
+        assert_eq!(fail("CHATHISTORY BEFORE #test * 10")?, "INVALID_PARAMS");
+        assert_eq!(
+            fail("CHATHISTORY AFTER #test msgid=a many")?,
+            "INVALID_PARAMS"
+        );
+        assert_eq!(
+            fail("CHATHISTORY AROUND #test msgid=a")?,
+            "NEED_MORE_PARAMS"
+        );
+        assert_eq!(
+            fail("CHATHISTORY SIDEWAYS #test msgid=a 1")?,
+            "INVALID_PARAMS"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_latest_before_after() -> Result<()> {
+        let (state, messages) = (state()?, messages()?);
+        let run =
+            |line: &str| -> Result<Vec<Message>> { Ok(run(&query(line)?, &messages, &state)) };
+
+        assert_eq!(
+            msgids(&run("CHATHISTORY LATEST #test * 2")?),
+            vec!["f", "g"]
+        );
+        assert_eq!(
+            msgids(&run("CHATHISTORY LATEST #test msgid=c 10")?),
+            vec!["f", "g"]
+        );
+        assert_eq!(
+            msgids(&run("CHATHISTORY BEFORE #test msgid=f 10")?),
+            vec!["a", "c"]
+        );
+        assert_eq!(
+            msgids(&run(
+                "CHATHISTORY AFTER #test timestamp=2020-04-01T12:02:00.000Z 1"
+            )?),
+            vec!["f"]
+        );
+        assert!(run("CHATHISTORY AFTER #test msgid=unknown 10")?.is_empty());
+
+        // Queries cover both sides of the conversation
+        assert_eq!(
+            msgids(&run("CHATHISTORY LATEST belak * 10")?),
+            vec!["b", "e"]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_around_between() -> Result<()> {
+        let (state, messages) = (state()?, messages()?);
+        let run =
+            |line: &str| -> Result<Vec<Message>> { Ok(run(&query(line)?, &messages, &state)) };
+
+        assert_eq!(
+            msgids(&run("CHATHISTORY AROUND #test msgid=c 3")?),
+            vec!["a", "c", "f"]
+        );
+        assert_eq!(
+            msgids(&run("CHATHISTORY BETWEEN #test msgid=a msgid=g 10")?),
+            vec!["c", "f"]
+        );
+        assert_eq!(
+            msgids(&run("CHATHISTORY BETWEEN #test msgid=g msgid=a 1")?),
+            vec!["f"]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_targets() -> Result<()> {
+        let (state, messages) = (state()?, messages()?);
+
+        let results = run(
+            &query(
+                "CHATHISTORY TARGETS timestamp=2020-04-01T13:00:00.000Z timestamp=2020-04-01T11:00:00.000Z 10",
+            )?,
+            &messages,
+            &state,
+        );
+        let lines: Vec<String> = results.iter().map(|m| m.to_string()).collect();
+
+        assert_eq!(
+            lines,
+            vec![
+                ":bounce CHATHISTORY TARGETS Belak 2020-04-01T12:04:00.000Z",
+                ":bounce CHATHISTORY TARGETS #test 2020-04-01T12:06:00.000Z",
+            ],
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_batch() -> Result<()> {
+        let batched = batch(
+            &query("CHATHISTORY LATEST #test * 1")?,
+            messages()?[..1].to_vec(),
+        );
+
+        assert_eq!(batched.len(), 3);
+        let id = batched[0].params()[0].trim_start_matches('+').to_string();
+        assert_eq!(&batched[0].params()[1..], &["chathistory", "#test"]);
+        assert_eq!(
+            batched[1].tag("batch").and_then(|tag| tag.value()),
+            Some(id.as_str())
+        );
+        assert_eq!(batched[2].params(), &vec![format!("-{}", id)]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,1062 @@
+//! Handles connections from downstream IRC clients.
+//!
+//! A client registers with `PASS`, `NICK`, and `USER`, where the password
+//! is of the form `<username>[@<client>]/<network>:<password>` (the same
+//! form used by ZNC). Once authenticated the client is attached to the
+//! matching upstream network: anything it missed since it last detached
+//! is replayed, its messages are forwarded to the server, and the server's
+//! messages are streamed back to it.
+//!
+//! The optional `<client>` identifies the device connecting so that each
+//! one gets its own backlog.
+//!
+//! Clients may negotiate capabilities with the bouncer before registering,
+//! independently of what the upstream connection has negotiated; see the
+//! `cap` module.
+
+use std::collections::VecDeque;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{format_err, Result};
+use chrono::{DateTime, TimeDelta, Utc};
+use futures::channel::mpsc::{channel, Receiver, Sender};
+use futures::future::{join, select, Either};
+use futures::lock::Mutex;
+use futures::{pin_mut, SinkExt, StreamExt};
+use log::{debug, info, trace, warn};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines, ReadHalf, WriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::broadcast::{self, RecvError};
+
+use super::cap::ClientCapabilities;
+use super::chathistory::{self, Query, Reference};
+use super::config::Config;
+use super::control::{self, Command};
+use super::irc::Message;
+use super::log_manager::LogManager;
+use super::search_command;
+use super::server::{queue_key, GuardedQueueMap, NetworkQueues};
+
+/// Name the bouncer uses as the prefix of messages it originates.
+pub const BOUNCER_PREFIX: &str = "bounce";
+
+/// Client identifier used when a client doesn't provide one.
+const DEFAULT_CLIENT: &str = "default";
+
+/// A bcrypt hash, at the cost `hash-password` uses, that no password is
+/// checked against except to make unknown users as slow as known ones.
+const DUMMY_PASSWORD_HASH: &str = "$2b$12$YFbb1gyHuADW8yJhQj7xM.nJWgQhnZ5JfX1VmsBr4tFcYae4dhdb6";
+
+type ClientLines = Lines<BufReader<ReadHalf<TcpStream>>>;
+
+/// Credentials sent by a client as
+/// `PASS <username>[@<client>]/<network>:<password>`.
+#[derive(Debug, PartialEq)]
+struct Credentials {
+    username: String,
+    client: String,
+    network: String,
+    password: String,
+}
+
+impl FromStr for Credentials {
+    type Err = anyhow::Error;
+
+    fn from_str(pass: &str) -> Result<Self> {
+        let colon = pass.find(':').ok_or_else(|| {
+            format_err!("Password must be of the form <username>/<network>:<password>")
+        })?;
+        let (login, password) = (&pass[..colon], &pass[colon + 1..]);
+
+        let slash = login.find('/').ok_or_else(|| {
+            format_err!("Password must be of the form <username>/<network>:<password>")
+        })?;
+        let (username, network) = (&login[..slash], &login[slash + 1..]);
+
+        let (username, client) = match username.find('@') {
+            Some(at) => (&username[..at], &username[at + 1..]),
+            None => (username, DEFAULT_CLIENT),
+        };
+
+        if username.is_empty() || network.is_empty() {
+            return Err(format_err!("Username and network must not be empty"));
+        }
+
+        // The client name is used as a file name when tracking backlog
+        if client.is_empty()
+            || !client
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(format_err!(
+                "Client name must be made of letters, digits, '-' and '_'"
+            ));
+        }
+
+        Ok(Credentials {
+            username: username.to_string(),
+            client: client.to_string(),
+            network: network.to_string(),
+            password: password.to_string(),
+        })
+    }
+}
+
+struct Registration {
+    nick: String,
+    credentials: Credentials,
+    caps: ClientCapabilities,
+}
+
+/// What the bouncer tracks for an attached client, shared between the
+/// tasks forwarding messages in each direction.
+struct ClientState {
+    caps: ClientCapabilities,
+    /// Messages sent by the client that the server will echo back, but
+    /// that the client doesn't want to see again, oldest first.
+    unechoed: VecDeque<(Instant, Message)>,
+}
+
+/// The most messages kept waiting for their echoes.
+const MAX_UNECHOED: usize = 100;
+
+/// How long to wait for a message's echo, which may never come if the
+/// server dropped the message.
+const UNECHOED_TIMEOUT: Duration = Duration::from_secs(60);
+
+impl ClientState {
+    /// Notes that the server will echo `message` back to a client that
+    /// doesn't want to see it again.
+    fn expect_echo(&mut self, message: Message) {
+        self.forget_old_echoes();
+        if self.unechoed.len() == MAX_UNECHOED {
+            self.unechoed.pop_front();
+        }
+        self.unechoed.push_back((Instant::now(), message));
+    }
+
+    /// Returns whether `message` is the echo of one the client sent,
+    /// forgetting about it if so.
+    fn take_echo(&mut self, message: &Message) -> bool {
+        self.forget_old_echoes();
+        let echo = self.unechoed.iter().position(|(_, sent)| {
+            sent.command() == message.command() && sent.params() == message.params()
+        });
+
+        match echo {
+            Some(index) => {
+                self.unechoed.remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn forget_old_echoes(&mut self) {
+        while let Some((sent, _)) = self.unechoed.front() {
+            if sent.elapsed() < UNECHOED_TIMEOUT {
+                break;
+            }
+            self.unechoed.pop_front();
+        }
+    }
+}
+
+/// Commands the server echoes back when echo-message is enabled.
+const ECHOED_COMMANDS: &[&str] = &["PRIVMSG", "NOTICE", "TAGMSG"];
+
+fn respond_to_ping(message: &Message, client_messages: &mut Sender<Message>) -> Result<()> {
+    match message.params().last() {
+        Some(last) => {
+            client_messages.try_send(Message::from_str(&format!(
+                ":{} PONG {} :{}",
+                BOUNCER_PREFIX, BOUNCER_PREFIX, last
+            ))?)?;
+            Ok(())
+        }
+        None => Err(format_err!("PING message has no parameters")),
+    }
+}
+
+/// Parses a line from a client. Lines that aren't messages are logged and
+/// skipped, as they are from servers, rather than costing the client its
+/// connection.
+fn parse_client_line(line: &str) -> Option<Message> {
+    if line.is_empty() {
+        return None;
+    }
+
+    match Message::from_str(line) {
+        Ok(message) => {
+            trace!("[client recv] {}", message);
+            Some(message)
+        }
+        Err(e) => {
+            let start: String = line.chars().take(64).collect();
+            warn!("skipping bad line from client: {}: {:?}", e, start);
+            None
+        }
+    }
+}
+
+async fn read_registration(
+    lines: &mut ClientLines,
+    client_messages: &mut Sender<Message>,
+) -> Result<Registration> {
+    let mut pass = None;
+    let mut nick = None;
+    let mut user = None;
+    let mut caps = ClientCapabilities::default();
+
+    while let Some(line) = lines.next_line().await? {
+        let message = match parse_client_line(&line) {
+            Some(message) => message,
+            None => continue,
+        };
+
+        match message.command() {
+            "PASS" => pass = message.params().first().cloned(),
+            "NICK" => nick = message.params().first().cloned(),
+            "USER" => user = message.params().first().cloned(),
+            "PING" => respond_to_ping(&message, client_messages)?,
+            "CAP" => {
+                for reply in caps.handle(&message, nick.as_deref()) {
+                    client_messages.send(reply).await?;
+                }
+            }
+            _ => {}
+        }
+
+        // Negotiating capabilities holds registration open until CAP END
+        if caps.is_negotiating() {
+            continue;
+        }
+
+        if let (Some(nick), Some(_)) = (&nick, &user) {
+            let credentials = match &pass {
+                Some(pass) => pass.parse()?,
+                None => {
+                    client_messages.try_send(Message::from_str(&format!(
+                        ":{} 464 {} :Password required",
+                        BOUNCER_PREFIX, nick
+                    ))?)?;
+                    return Err(format_err!("Client did not send a password"));
+                }
+            };
+
+            return Ok(Registration {
+                nick: nick.clone(),
+                credentials,
+                caps,
+            });
+        }
+    }
+
+    Err(format_err!("Client disconnected before registering"))
+}
+
+async fn authenticate(
+    config: &Config,
+    registration: &Registration,
+    client_messages: &mut Sender<Message>,
+) -> Result<()> {
+    let credentials = &registration.credentials;
+
+    // Unknown users are checked against a dummy hash anyway, so that they
+    // take as long to turn away as a wrong password does
+    let user = config.user(&credentials.username);
+    let password = credentials.password.clone();
+    let password_hash = user.map_or(DUMMY_PASSWORD_HASH.to_string(), |user| {
+        user.password_hash.clone()
+    });
+    let verified =
+        tokio::task::spawn_blocking(move || bcrypt::verify(password, &password_hash)).await??;
+    let valid = user.is_some() && verified;
+
+    if !valid {
+        info!(
+            "failed login attempt for user \"{}\" on network \"{}\"",
+            credentials.username, credentials.network
+        );
+        client_messages.try_send(Message::from_str(&format!(
+            ":{} 464 {} :Password incorrect",
+            BOUNCER_PREFIX, registration.nick
+        ))?)?;
+        return Err(format_err!("Invalid username or password"));
+    }
+
+    Ok(())
+}
+
+/// An authenticated client attached to one of its user's networks.
+struct Session {
+    username: String,
+    network: String,
+    /// Every network of the user's, which the client may search.
+    networks: Vec<String>,
+    log_manager: Arc<Mutex<LogManager>>,
+    queues: NetworkQueues,
+    client_state: Mutex<ClientState>,
+}
+
+impl Session {
+    /// Prepares a message from the client to be sent upstream. When the
+    /// server won't echo it, the bouncer logs it and echoes it to every
+    /// attached client itself so that history includes both sides.
+    async fn send_upstream(&self, mut message: Message) -> Result<()> {
+        let (server_tags, server_echoes) = {
+            let mut network_state = self.queues.state.lock().await;
+            network_state.joining(&message);
+            (
+                network_state.caps().is_enabled("message-tags"),
+                network_state.caps().is_enabled("echo-message"),
+            )
+        };
+
+        // Only client-only tags are meaningful coming from a client, and
+        // only if the server can relay them
+        message.retain_tags(|tag| server_tags && tag.is_client_only());
+        if message.command() == "TAGMSG" && !server_tags {
+            return Ok(());
+        }
+
+        if !ECHOED_COMMANDS.contains(&message.command()) {
+            self.queues.server_messages.clone().send(message).await?;
+            return Ok(());
+        }
+
+        {
+            let mut client_state = self.client_state.lock().await;
+            if !client_state.caps.is_enabled("echo-message") {
+                client_state.expect_echo(message.clone());
+            }
+        }
+
+        if !server_echoes {
+            let mut log_manager = self.log_manager.lock().await;
+            let network_state = self.queues.state.lock().await;
+
+            let mut echo = Message::new(
+                network_state
+                    .own_prefix()
+                    .and_then(|prefix| prefix.parse().ok()),
+                message.command(),
+                message.params().clone(),
+            );
+            for tag in message.tags() {
+                echo.set_tag(tag.key(), tag.value());
+            }
+
+            log_manager
+                .add_message(
+                    &self.username,
+                    &self.network,
+                    &network_state.log_targets(&echo),
+                    &mut echo,
+                )
+                .await?;
+
+            // Nobody may be attached to receive it, which is fine
+            let _ = self.queues.client_messages.send(echo);
+        }
+
+        self.queues.server_messages.clone().send(message).await?;
+
+        Ok(())
+    }
+
+    /// Reads the messages logged for `target` that `query` needs back from
+    /// `end`, or from now, reading further back until `limit` of them are
+    /// history or the logs run out.
+    async fn read_back(
+        &self,
+        log_manager: &LogManager,
+        query: &Query,
+        target: Option<&str>,
+        end: Option<DateTime<Utc>>,
+        limit: usize,
+    ) -> Result<Vec<Message>> {
+        // Most of what's logged for a conversation is part of its history
+        let mut read = limit * 2;
+        loop {
+            let messages = log_manager
+                .latest_messages(&self.username, &self.network, target, end.as_ref(), read)
+                .await?;
+            if messages.len() < read {
+                return Ok(messages);
+            }
+
+            let network_state = self.queues.state.lock().await;
+            if chathistory::run(query, &messages, &network_state).len() >= limit {
+                return Ok(messages);
+            }
+            read *= 2;
+        }
+    }
+
+    /// Returns when `reference` is, or `None` if it's a msgid that isn't
+    /// logged for `target`.
+    async fn reference_time(
+        &self,
+        log_manager: &LogManager,
+        target: Option<&str>,
+        reference: &Reference,
+    ) -> Result<Option<DateTime<Utc>>> {
+        match reference {
+            Reference::Timestamp(time) => Ok(Some(*time)),
+            Reference::MsgId(msgid) => {
+                log_manager
+                    .message_time(&self.username, &self.network, target, msgid)
+                    .await
+            }
+        }
+    }
+
+    /// Reads the messages logged for `target` that an AROUND query for
+    /// `limit` messages around `time` needs: those before it read back
+    /// like LATEST, then those from it on, read forward over a window that
+    /// widens until it holds enough history or reaches now.
+    async fn read_around(
+        &self,
+        log_manager: &LogManager,
+        name: &str,
+        target: Option<&str>,
+        time: DateTime<Utc>,
+        limit: usize,
+    ) -> Result<Vec<Message>> {
+        let before = Query::Latest {
+            target: name.to_string(),
+            after: None,
+            limit: limit / 2,
+        };
+        let end = time - TimeDelta::milliseconds(1);
+        let mut messages = self
+            .read_back(log_manager, &before, target, Some(end), limit / 2)
+            .await?;
+
+        let earlier = {
+            let network_state = self.queues.state.lock().await;
+            chathistory::run(&before, &messages, &network_state).len()
+        };
+        let after = Query::Latest {
+            target: name.to_string(),
+            after: None,
+            limit: limit - earlier,
+        };
+
+        let mut span = TimeDelta::hours(1);
+        loop {
+            let end = Some(time + span).filter(|end| *end < Utc::now());
+            let later = log_manager
+                .messages(
+                    &self.username,
+                    &self.network,
+                    target,
+                    Some(&time),
+                    end.as_ref(),
+                )
+                .await?;
+
+            let enough = {
+                let network_state = self.queues.state.lock().await;
+                chathistory::run(&after, &later, &network_state).len() >= limit - earlier
+            };
+            if enough || end.is_none() {
+                messages.extend(later);
+                return Ok(messages);
+            }
+            span = span * 2;
+        }
+    }
+
+    /// Answers a CHATHISTORY request from the logs.
+    async fn send_history(
+        &self,
+        message: &Message,
+        client_messages: &mut Sender<Message>,
+    ) -> Result<()> {
+        let query = match Query::parse(message) {
+            Ok(query) => query,
+            Err(fail) => {
+                client_messages.send(fail.to_message()).await?;
+                return Ok(());
+            }
+        };
+
+        // Conversations are logged under their folded names
+        let casemapping = self.queues.state.lock().await.casemapping();
+        let target = query.target().map(|target| casemapping.fold(target));
+
+        let log_manager = self.log_manager.lock().await;
+        let messages = match (&query, target) {
+            (Query::Targets { start, end, .. }, _) => {
+                let mut messages = Vec::new();
+                for target in log_manager.targets(&self.username, &self.network).await? {
+                    messages.extend(
+                        log_manager
+                            .messages(
+                                &self.username,
+                                &self.network,
+                                Some(&target),
+                                Some(start),
+                                Some(end),
+                            )
+                            .await?,
+                    );
+                }
+                messages
+            }
+            (
+                Query::Around {
+                    target: name,
+                    reference,
+                    limit,
+                },
+                target,
+            ) => {
+                match self
+                    .reference_time(&log_manager, target.as_deref(), reference)
+                    .await?
+                {
+                    Some(time) => {
+                        self.read_around(&log_manager, name, target.as_deref(), time, *limit)
+                            .await?
+                    }
+                    // Nothing is around a message that was never logged
+                    None => Vec::new(),
+                }
+            }
+            (_, target) => match query.reads_back() {
+                Some((reference, limit)) => {
+                    let end = match reference {
+                        None => None,
+                        Some(reference) => {
+                            self.reference_time(&log_manager, target.as_deref(), reference)
+                                .await?
+                        }
+                    };
+
+                    // Nothing is before a message that was never logged
+                    if reference.is_some() && end.is_none() {
+                        Vec::new()
+                    } else {
+                        self.read_back(&log_manager, &query, target.as_deref(), end, limit)
+                            .await?
+                    }
+                }
+                None => {
+                    // Only read from the earliest message the query could
+                    // return
+                    let mut times = Vec::new();
+                    for reference in query.lower_bounds() {
+                        times.push(
+                            self.reference_time(&log_manager, target.as_deref(), reference)
+                                .await?,
+                        );
+                    }
+
+                    // Nothing is after or between messages that were never
+                    // logged
+                    match times.into_iter().collect::<Option<Vec<_>>>() {
+                        Some(times) => {
+                            log_manager
+                                .messages(
+                                    &self.username,
+                                    &self.network,
+                                    target.as_deref(),
+                                    times.into_iter().min().as_ref(),
+                                    None,
+                                )
+                                .await?
+                        }
+                        None => Vec::new(),
+                    }
+                }
+            },
+        };
+        drop(log_manager);
+
+        let (results, prefix_symbols) = {
+            let network_state = self.queues.state.lock().await;
+            (
+                chathistory::run(&query, &messages, &network_state),
+                network_state.prefix_symbols(),
+            )
+        };
+
+        let client_state = self.client_state.lock().await;
+        for message in chathistory::batch(&query, results) {
+            if let Some(message) = client_state.caps.translate(message, &prefix_symbols) {
+                client_messages.send(message).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Answers a SEARCH request from the logs.
+    async fn send_search(
+        &self,
+        message: &Message,
+        client_messages: &mut Sender<Message>,
+    ) -> Result<()> {
+        let casemapping = self.queues.state.lock().await.casemapping();
+        let search = match search_command::parse(message, casemapping) {
+            Ok(search) => search,
+            Err(fail) => {
+                client_messages.send(fail.to_message()).await?;
+                return Ok(());
+            }
+        };
+
+        let results = self
+            .log_manager
+            .lock()
+            .await
+            .search(&self.username, &self.network, &search)
+            .await?;
+
+        let prefix_symbols = self.queues.state.lock().await.prefix_symbols();
+        let client_state = self.client_state.lock().await;
+        for message in search_command::batch(results) {
+            if let Some(message) = client_state.caps.translate(message, &prefix_symbols) {
+                client_messages.send(message).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Answers a message sent to the control user.
+    async fn answer_control(
+        &self,
+        message: &Message,
+        client_messages: &mut Sender<Message>,
+    ) -> Result<()> {
+        let (casemapping, nick) = {
+            let network_state = self.queues.state.lock().await;
+            (
+                network_state.casemapping(),
+                network_state.nick().unwrap_or_default().to_string(),
+            )
+        };
+        let text = message.params().get(1).map_or("", |text| text.as_str());
+
+        let replies = match control::parse(text, casemapping) {
+            Ok(Command::Help) => control::help(),
+            Ok(Command::Search { network, search }) => {
+                let network = network.unwrap_or_else(|| self.network.clone());
+                if self.networks.contains(&network) {
+                    let results = self
+                        .log_manager
+                        .lock()
+                        .await
+                        .search(&self.username, &network, &search)
+                        .await?;
+                    control::describe_results(&results)
+                } else {
+                    vec![format!("Unknown network \"{}\"", network)]
+                }
+            }
+            Err(e) => vec![e],
+        };
+
+        for reply in replies {
+            client_messages.send(control::reply(&nick, &reply)).await?;
+        }
+
+        Ok(())
+    }
+}
+
+async fn forward_client_messages(
+    lines: &mut ClientLines,
+    client_messages: &mut Sender<Message>,
+    session: &Session,
+) -> Result<()> {
+    while let Some(line) = lines.next_line().await? {
+        let message = match parse_client_line(&line) {
+            Some(message) => message,
+            None => continue,
+        };
+
+        match message.command() {
+            "PING" => respond_to_ping(&message, client_messages)?,
+            "QUIT" => break,
+            "CAP" => {
+                let nick = session
+                    .queues
+                    .state
+                    .lock()
+                    .await
+                    .nick()
+                    .map(|n| n.to_string());
+                let replies = session
+                    .client_state
+                    .lock()
+                    .await
+                    .caps
+                    .handle(&message, nick.as_deref());
+                for reply in replies {
+                    client_messages.send(reply).await?;
+                }
+            }
+            "CHATHISTORY" => session.send_history(&message, client_messages).await?,
+            "SEARCH" => session.send_search(&message, client_messages).await?,
+            "PRIVMSG" if control::is_control_query(&message) => {
+                session.answer_control(&message, client_messages).await?
+            }
+            // Registration and keepalives are handled by the bouncer itself
+            "PASS" | "USER" | "PONG" => {}
+            _ => session.send_upstream(message).await?,
+        }
+    }
+
+    Ok(())
+}
+
+async fn forward_upstream_messages(
+    mut upstream_messages: broadcast::Receiver<Message>,
+    mut client_messages: Sender<Message>,
+    client_state: &Mutex<ClientState>,
+    prefix_symbols: &str,
+) -> Result<()> {
+    loop {
+        let message = match upstream_messages.recv().await {
+            Ok(message) => message,
+            Err(RecvError::Lagged(missed)) => {
+                return Err(format_err!("Client fell behind by {} messages", missed))
+            }
+            Err(RecvError::Closed) => return Ok(()),
+        };
+
+        let translated = {
+            let mut client_state = client_state.lock().await;
+
+            if ECHOED_COMMANDS.contains(&message.command()) && client_state.take_echo(&message) {
+                None
+            } else {
+                client_state.caps.translate(message, prefix_symbols)
+            }
+        };
+
+        if let Some(message) = translated {
+            client_messages.send(message).await?;
+        }
+    }
+}
+
+async fn individual_client_read_worker(
+    config: Config,
+    log_manager: Arc<Mutex<LogManager>>,
+    queues: GuardedQueueMap,
+    client_reader: ReadHalf<TcpStream>,
+    mut client_messages: Sender<Message>,
+) -> Result<()> {
+    let mut lines = BufReader::new(client_reader).lines();
+
+    let registration = match read_registration(&mut lines, &mut client_messages).await {
+        Ok(registration) => registration,
+        Err(e) => {
+            client_messages.try_send(Message::from_str(&format!("ERROR :{}", e))?)?;
+            client_messages.close_channel();
+            return Err(e);
+        }
+    };
+
+    if let Err(e) = authenticate(&config, &registration, &mut client_messages).await {
+        client_messages.try_send(Message::from_str(&format!("ERROR :{}", e))?)?;
+        client_messages.close_channel();
+        return Err(e);
+    }
+
+    let credentials = &registration.credentials;
+    let key = queue_key(&credentials.username, &credentials.network);
+
+    // Holding the log while subscribing ensures nothing is logged between
+    // building the burst and replay and joining the broadcast.
+    let log_manager_guard = log_manager.lock().await;
+    let (network_queues, upstream_messages, burst, prefix_symbols, nick) = {
+        let queues = queues.lock().await;
+        match queues.get(&key) {
+            Some(network_queues) => {
+                let state = network_queues.state.lock().await;
+                (
+                    network_queues.clone(),
+                    network_queues.client_messages.subscribe(),
+                    state.burst(),
+                    state.prefix_symbols(),
+                    state.nick().unwrap_or(&registration.nick).to_string(),
+                )
+            }
+            None => {
+                client_messages.try_send(Message::from_str(&format!(
+                    "ERROR :Unknown network \"{}\"",
+                    credentials.network
+                ))?)?;
+                client_messages.close_channel();
+                return Err(format_err!("No upstream connection for {}", key));
+            }
+        }
+    };
+
+    let replay = log_manager_guard
+        .replay_messages_since_detach(
+            &credentials.username,
+            &credentials.network,
+            &credentials.client,
+        )
+        .await;
+    drop(log_manager_guard);
+
+    debug!(
+        "client \"{}\" ({}) attached to {}",
+        registration.nick, credentials.client, key
+    );
+
+    let caps = registration.caps;
+
+    // Until the server welcomes the bouncer there's nothing to catch up
+    // on; the client will receive the server's welcome as it arrives.
+    if !burst.is_empty() {
+        for message in burst {
+            if let Some(message) = caps.translate(message, &prefix_symbols) {
+                client_messages.send(message).await?;
+            }
+        }
+        client_messages
+            .send(Message::from_str(&format!(
+                ":{} 005 {} CHATHISTORY={} :are supported by this server",
+                BOUNCER_PREFIX,
+                nick,
+                chathistory::MAX_LIMIT
+            ))?)
+            .await?;
+    }
+
+    match replay {
+        Ok(replay) => {
+            debug!(
+                "replaying {} messages to {} on {}",
+                replay.len(),
+                credentials.client,
+                key
+            );
+            for message in replay {
+                if let Some(message) = caps.translate(message, &prefix_symbols) {
+                    client_messages.send(message).await?;
+                }
+            }
+        }
+        Err(e) => warn!("unable to replay backlog for {}: {}", key, e),
+    }
+
+    let session = Session {
+        username: credentials.username.clone(),
+        network: credentials.network.clone(),
+        networks: config
+            .networks
+            .iter()
+            .filter(|network| network.username == credentials.username)
+            .map(|network| network.name.clone())
+            .collect(),
+        log_manager: Arc::clone(&log_manager),
+        queues: network_queues,
+        client_state: Mutex::new(ClientState {
+            caps,
+            unechoed: VecDeque::new(),
+        }),
+    };
+
+    let result = {
+        let upstream = forward_upstream_messages(
+            upstream_messages,
+            client_messages.clone(),
+            &session.client_state,
+            &prefix_symbols,
+        );
+        let downstream = forward_client_messages(&mut lines, &mut client_messages, &session);
+        pin_mut!(upstream, downstream);
+
+        match select(upstream, downstream).await {
+            Either::Left((result, _)) | Either::Right((result, _)) => result,
+        }
+    };
+
+    debug!(
+        "client \"{}\" ({}) detached from {}",
+        registration.nick, credentials.client, key
+    );
+
+    if let Err(e) = log_manager
+        .lock()
+        .await
+        .mark_detached(
+            &credentials.username,
+            &credentials.network,
+            &credentials.client,
+        )
+        .await
+    {
+        warn!("unable to record detach for {}: {}", key, e);
+    }
+
+    if let Err(e) = &result {
+        // Best effort: the client may be too far behind to receive this.
+        let _ = client_messages.try_send(Message::from_str(&format!("ERROR :{}", e))?);
+    }
+
+    // Closing the channel ends the write worker, including for the clone
+    // held by the upstream forwarder.
+    client_messages.close_channel();
+
+    result
+}
+
+async fn individual_client_write_worker(
+    mut client_writer: WriteHalf<TcpStream>,
+    mut messages: Receiver<Message>,
+) -> Result<()> {
+    while let Some(message) = messages.next().await {
+        trace!("[client send] {}", message);
+        client_writer
+            .write_all(format!("{}\r\n", message).as_bytes())
+            .await?;
+    }
+
+    Ok(())
+}
+
+pub async fn client_worker(
+    config: Config,
+    log_manager: Arc<Mutex<LogManager>>,
+    queues: GuardedQueueMap,
+    socket: TcpStream,
+) -> Result<()> {
+    let (client_reader, client_writer) = tokio::io::split(socket);
+
+    // TODO(jsvana): make buffer size configurable?
+    let (client_messages_tx, client_messages_rx) = channel::<Message>(100);
+
+    let (read_result, write_result) = join(
+        individual_client_read_worker(
+            config,
+            log_manager,
+            queues,
+            client_reader,
+            client_messages_tx,
+        ),
+        individual_client_write_worker(client_writer, client_messages_rx),
+    )
+    .await;
+
+    read_result?;
+    write_result?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_credentials() -> Result<()> {
+        assert_eq!(
+            Credentials::from_str("jay/freenode:hunter2")?,
+            Credentials {
+                username: "jay".to_string(),
+                client: DEFAULT_CLIENT.to_string(),
+                network: "freenode".to_string(),
+                password: "hunter2".to_string(),
+            },
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_credentials_with_client() -> Result<()> {
+        assert_eq!(
+            Credentials::from_str("jay@phone/freenode:hunter2")?,
+            Credentials {
+                username: "jay".to_string(),
+                client: "phone".to_string(),
+                network: "freenode".to_string(),
+                password: "hunter2".to_string(),
+            },
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_credentials_invalid_client() {
+        assert!(Credentials::from_str("jay@../freenode:hunter2").is_err());
+    }
+
+    #[test]
+    fn test_parse_credentials_password_with_colon() -> Result<()> {
+        assert_eq!(
+            Credentials::from_str("jay/freenode:hunter2:extra")?,
+            Credentials {
+                username: "jay".to_string(),
+                client: DEFAULT_CLIENT.to_string(),
+                network: "freenode".to_string(),
+                password: "hunter2:extra".to_string(),
+            },
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_credentials_missing_network() {
+        assert!(Credentials::from_str("jay:hunter2").is_err());
+    }
+
+    #[test]
+    fn test_parse_credentials_missing_password() {
+        assert!(Credentials::from_str("jay/freenode").is_err());
+    }
+
+    #[test]
+    fn test_unechoed() -> Result<()> {
+        let mut client_state = ClientState {
+            caps: ClientCapabilities::default(),
+            unechoed: VecDeque::new(),
+        };
+        let privmsg = |text: &str| Message::from_str(&format!("PRIVMSG #rust :{}", text));
+
+        for i in 0..=MAX_UNECHOED {
+            client_state.expect_echo(privmsg(&i.to_string())?);
+        }
+        assert!(!client_state.take_echo(&privmsg("0")?));
+        assert!(client_state.take_echo(&privmsg("1")?));
+        assert!(!client_state.take_echo(&privmsg("1")?));
+
+        // Echoes that never came are given up on
+        client_state
+            .unechoed
+            .push_front((Instant::now() - UNECHOED_TIMEOUT, privmsg("late")?));
+        assert!(!client_state.take_echo(&privmsg("late")?));
+        assert_eq!(client_state.unechoed.len(), MAX_UNECHOED - 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_client_line() -> Result<()> {
+        assert_eq!(
+            parse_client_line("PRIVMSG #rust :hi"),
+            Some(Message::from_str("PRIVMSG #rust :hi")?)
+        );
+        assert_eq!(parse_client_line(""), None);
+        assert_eq!(parse_client_line(":jay"), None);
+        assert_eq!(parse_client_line("PRIV/MSG #rust"), None);
+
+        Ok(())
+    }
+}
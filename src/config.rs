@@ -1,8 +1,11 @@
 use std::path::PathBuf;
 
 use anyhow::{format_err, Result};
+use chrono_tz::Tz;
 use serde_derive::Deserialize;
 
+use super::encoding;
+
 #[derive(Clone, Debug, Deserialize)]
 pub struct Core {
     pub bind_hostname: String,
@@ -15,9 +18,40 @@ impl Core {
     }
 }
 
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogCompression {
+    #[default]
+    None,
+    Gzip,
+    Zstd,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogBackend {
+    #[default]
+    Files,
+    Sqlite,
+}
+
 #[derive(Clone, Debug, Deserialize)]
 pub struct Log {
     pub base_path: PathBuf,
+    /// Where logs are stored under the base path.
+    #[serde(default)]
+    pub backend: LogBackend,
+    /// Timezone whose midnight starts each day's log file.
+    #[serde(default)]
+    pub timezone: Tz,
+    /// How logs from previous days are compressed.
+    #[serde(default)]
+    pub compression: LogCompression,
+    /// Logs from more than this many days ago are deleted.
+    pub retention_days: Option<u32>,
+    /// The oldest logs are deleted once all logs together take up more
+    /// than this many bytes.
+    pub retention_bytes: Option<u64>,
 }
 
 fn default_port() -> u16 {
@@ -44,6 +78,60 @@ impl NetworkServer {
     }
 }
 
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SaslMechanism {
+    /// Authenticate with an account name and password.
+    Plain,
+    /// Authenticate with the TLS client certificate.
+    External,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SaslFailurePolicy {
+    /// Finish registering without being logged in.
+    Continue,
+    /// Drop the connection and try again later.
+    Disconnect,
+}
+
+fn default_sasl_failure_policy() -> SaslFailurePolicy {
+    SaslFailurePolicy::Disconnect
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Sasl {
+    pub mechanism: SaslMechanism,
+    /// Account to log in to. Required for PLAIN.
+    pub account: Option<String>,
+    /// Account password. Required for PLAIN.
+    pub password: Option<String>,
+    #[serde(default = "default_sasl_failure_policy")]
+    pub on_failure: SaslFailurePolicy,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ClientCertificate {
+    /// PKCS #12 archive holding the certificate and its private key.
+    pub path: PathBuf,
+    #[serde(default)]
+    pub password: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct NetworkChannel {
+    pub name: String,
+    pub key: Option<String>,
+    /// Encoding of messages to the channel that aren't UTF-8, instead of
+    /// the network's.
+    pub encoding: Option<String>,
+}
+
+fn default_rejoin_delay_secs() -> u64 {
+    10
+}
+
 #[derive(Clone, Debug, Deserialize)]
 pub struct Network {
     pub name: String,
@@ -51,31 +139,112 @@ pub struct Network {
     pub username: String,
     pub realname: String,
 
-    pub server: NetworkServer,
+    /// A single server, as configured by `[networks.server]`. Moved into
+    /// `servers` once the configuration is loaded.
+    #[serde(default)]
+    server: Option<NetworkServer>,
+    /// Servers to connect to, tried in order when one fails.
+    #[serde(default)]
+    pub servers: Vec<NetworkServer>,
+    /// Presented to servers connected to over TLS.
+    pub client_certificate: Option<ClientCertificate>,
+    pub sasl: Option<Sasl>,
+    /// Encoding of lines from the network that aren't UTF-8, as a WHATWG
+    /// label such as "latin1" or "shift_jis". Defaults to windows-1252.
+    pub encoding: Option<String>,
+
+    #[serde(default, rename = "channel")]
+    pub channels: Vec<NetworkChannel>,
+    /// How long to wait before rejoining a channel after being kicked.
+    #[serde(default = "default_rejoin_delay_secs")]
+    pub rejoin_delay_secs: u64,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct User {
+    pub name: String,
+    /// bcrypt hash of the password clients authenticate with.
+    pub password_hash: String,
 }
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct Config {
     pub core: Core,
     pub log: Log,
+    pub users: Vec<User>,
     pub networks: Vec<Network>,
 }
 
 impl Config {
     pub fn from_file(filename: &str) -> Result<Self> {
-        let config: Self = toml::from_str(&std::fs::read_to_string(filename)?)
+        let mut config: Self = toml::from_str(&std::fs::read_to_string(filename)?)
             .map_err(|e| format_err!("Failed to read configuration: {}", e))?;
 
-        for network in config.networks.iter() {
-            if network.nick_choices.len() == 0 {
+        for network in config.networks.iter_mut() {
+            if let Some(server) = network.server.take() {
+                network.servers.insert(0, server);
+            }
+
+            if network.servers.is_empty() {
+                return Err(format_err!(
+                    "Must specify at least one server for network \"{}\"",
+                    network.name
+                ));
+            }
+
+            if network.nick_choices.is_empty() {
                 return Err(format_err!(
-                    "Must specify at least one nick for network \"{}\" ({})",
-                    network.name,
-                    network.server.address()
+                    "Must specify at least one nick for network \"{}\"",
+                    network.name
+                ));
+            }
+
+            let encodings = network.channels.iter().map(|channel| &channel.encoding);
+            for label in std::iter::once(&network.encoding)
+                .chain(encodings)
+                .flatten()
+            {
+                if encoding::for_label(label).is_none() {
+                    return Err(format_err!(
+                        "Unknown encoding \"{}\" for network \"{}\"",
+                        label,
+                        network.name
+                    ));
+                }
+            }
+
+            if let Some(sasl) = &network.sasl {
+                match sasl.mechanism {
+                    SaslMechanism::Plain if sasl.account.is_none() || sasl.password.is_none() => {
+                        return Err(format_err!(
+                            "SASL PLAIN requires an account and password for network \"{}\"",
+                            network.name
+                        ));
+                    }
+                    SaslMechanism::External if network.client_certificate.is_none() => {
+                        return Err(format_err!(
+                            "SASL EXTERNAL requires a client certificate for network \"{}\"",
+                            network.name
+                        ));
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        for user in config.users.iter() {
+            if config.users.iter().filter(|u| u.name == user.name).count() > 1 {
+                return Err(format_err!(
+                    "User \"{}\" is defined more than once",
+                    user.name
                 ));
             }
         }
 
         Ok(config)
     }
+
+    pub fn user(&self, name: &str) -> Option<&User> {
+        self.users.iter().find(|user| user.name == name)
+    }
 }
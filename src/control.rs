@@ -0,0 +1,224 @@
+//! Answers messages sent to `*bounce`, which lets users ask the bouncer
+//! itself for things from any IRC client, whatever it supports.
+//!
+//! Searches take a filter for each `<key>:<value>` word and search for
+//! the rest of the words in messages' text, for example
+//!
+//!   /msg *bounce search in:#rust from:jay after:2026-10-01 async await
+
+use chrono::{DateTime, NaiveDate, Utc};
+
+use super::cap::parse_server_time;
+use super::chathistory::MAX_LIMIT;
+use super::irc::{Message, Prefix};
+use super::log_manager::Search;
+use super::state::CaseMapping;
+
+/// Nick of the bouncer's control user.
+pub const CONTROL_NICK: &str = "*bounce";
+
+const HELP: &[&str] = &[
+    "Commands:",
+    "  help",
+    "  search [in:<channel or nick>] [from:<nick>] [after:<date>] [before:<date>] \
+     [network:<name>] [limit:<count>] [<words>...]",
+    "Dates are YYYY-MM-DD or timestamps like 2026-10-18T08:30:00Z, in UTC.",
+];
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Help,
+    /// A search of the logs of `network`, or of the network the client is
+    /// attached to.
+    Search {
+        network: Option<String>,
+        search: Search,
+    },
+}
+
+/// Returns whether `message` is addressed to the control user.
+pub fn is_control_query(message: &Message) -> bool {
+    message.command() == "PRIVMSG"
+        && message
+            .params()
+            .first()
+            .is_some_and(|target| target.eq_ignore_ascii_case(CONTROL_NICK))
+}
+
+/// Parses a date as the start of that day, or a timestamp.
+fn parse_time(time: &str) -> Option<DateTime<Utc>> {
+    match NaiveDate::parse_from_str(time, "%Y-%m-%d") {
+        Ok(date) => Some(date.and_hms_opt(0, 0, 0)?.and_utc()),
+        Err(_) => parse_server_time(time),
+    }
+}
+
+/// Parses a command sent to the control user, folding names with
+/// `casemapping`. Errors are meant to be sent back to the user.
+pub fn parse(text: &str, casemapping: CaseMapping) -> Result<Command, String> {
+    let mut words = text.split_whitespace();
+
+    match words.next().map(|command| command.to_ascii_lowercase()) {
+        Some(command) if command == "search" => {}
+        Some(command) if command == "help" => return Ok(Command::Help),
+        Some(command) => return Err(format!("Unknown command \"{}\". Try \"help\".", command)),
+        None => return Ok(Command::Help),
+    }
+
+    let mut network = None;
+    let mut search = Search {
+        text: None,
+        from: None,
+        target: None,
+        start: None,
+        end: None,
+        limit: MAX_LIMIT,
+        casemapping,
+    };
+    let mut text = Vec::new();
+    for word in words {
+        let (key, value) = match word.split_once(':') {
+            Some((key, value)) if !value.is_empty() => (key, value),
+            _ => {
+                text.push(word);
+                continue;
+            }
+        };
+
+        let invalid = |what: &str| format!("Invalid {} \"{}\"", what, value);
+        match key {
+            "in" => search.target = Some(casemapping.fold(value)),
+            "from" => search.from = Some(casemapping.fold(value)),
+            "network" => network = Some(value.to_string()),
+            "after" => search.start = Some(parse_time(value).ok_or_else(|| invalid("date"))?),
+            "before" => search.end = Some(parse_time(value).ok_or_else(|| invalid("date"))?),
+            "limit" => {
+                search.limit = match value.parse::<usize>() {
+                    Ok(limit) if limit > 0 => limit.min(MAX_LIMIT),
+                    _ => return Err(invalid("limit")),
+                }
+            }
+            _ => text.push(word),
+        }
+    }
+    if !text.is_empty() {
+        search.text = Some(text.join(" "));
+    }
+
+    Ok(Command::Search { network, search })
+}
+
+/// Describes the commands the control user understands.
+pub fn help() -> Vec<String> {
+    HELP.iter().map(|line| line.to_string()).collect()
+}
+
+/// Describes the results of a search, one line per message.
+pub fn describe_results(results: &[Message]) -> Vec<String> {
+    if results.is_empty() {
+        return vec!["No messages found".to_string()];
+    }
+
+    let mut lines: Vec<String> = results
+        .iter()
+        .map(|message| {
+            let time = message
+                .tag("time")
+                .and_then(|tag| tag.value())
+                .and_then(parse_server_time)
+                .unwrap_or_default();
+            let nick = message.prefix().map_or("", |prefix| prefix.entity());
+            let (open, close) = if message.command() == "NOTICE" {
+                ('-', '-')
+            } else {
+                ('<', '>')
+            };
+            let params = message.params();
+
+            format!(
+                "{} {} {}{}{} {}",
+                time.format("%Y-%m-%d %H:%M:%S"),
+                params.first().map_or("", |target| target.as_str()),
+                open,
+                nick,
+                close,
+                params.get(1).map_or("", |text| text.as_str()),
+            )
+        })
+        .collect();
+    lines.push(format!("Found {} messages", results.len()));
+
+    lines
+}
+
+/// Sends `text` to `nick` from the control user.
+pub fn reply(nick: &str, text: &str) -> Message {
+    let prefix: Option<Prefix> = format!("{}!bounce@bounce", CONTROL_NICK).parse().ok();
+    Message::new(prefix, "PRIVMSG", vec![nick.to_string(), text.to_string()])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::str::FromStr;
+
+    use anyhow::Result;
+
+    #[test]
+    fn test_parse() -> Result<()> {
+        assert_eq!(parse("HELP", CaseMapping::Ascii), Ok(Command::Help));
+        assert_eq!(
+            parse(
+                "search in:#Rust from:Jay after:2026-10-01 network:libera limit:5 async: await",
+                CaseMapping::Ascii
+            ),
+            Ok(Command::Search {
+                network: Some("libera".to_string()),
+                search: Search {
+                    text: Some("async: await".to_string()),
+                    from: Some("jay".to_string()),
+                    target: Some("#rust".to_string()),
+                    start: parse_server_time("2026-10-01T00:00:00Z"),
+                    end: None,
+                    limit: 5,
+                    casemapping: CaseMapping::Ascii,
+                },
+            }),
+        );
+        assert_eq!(
+            parse("search before:someday", CaseMapping::Ascii),
+            Err("Invalid date \"someday\"".to_string())
+        );
+        assert!(parse("frobnicate", CaseMapping::Ascii).is_err());
+
+        assert!(is_control_query(&Message::from_str(
+            "PRIVMSG *Bounce :help"
+        )?));
+        assert!(!is_control_query(&Message::from_str(
+            "NOTICE *bounce :help"
+        )?));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_describe_results() -> Result<()> {
+        let results = vec![
+            Message::from_str("@time=2026-10-18T08:30:06.620Z :jay!j@h PRIVMSG #rust :hi there")?,
+            Message::from_str("@time=2026-10-18T08:31:00.000Z :belak NOTICE #rust :hello")?,
+        ];
+
+        assert_eq!(
+            describe_results(&results),
+            vec![
+                "2026-10-18 08:30:06 #rust <jay> hi there",
+                "2026-10-18 08:31:00 #rust -belak- hello",
+                "Found 2 messages",
+            ]
+        );
+        assert_eq!(describe_results(&[]), vec!["No messages found"]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,149 @@
+//! Decodes lines from servers, which are meant to be UTF-8 but often
+//! aren't: plenty of channels still talk in latin-1, CP1252 or older
+//! regional encodings.
+//!
+//! A line that's valid UTF-8 is taken as it is. Anything else is decoded
+//! with the encoding configured for the channel it was sent to, or for
+//! its network, which defaults to windows-1252. Bytes that aren't valid
+//! in that encoding either are kept as the characters with the same
+//! values, U+0000 to U+00FF, rather than replaced, so that nothing sent
+//! is lost.
+
+use encoding_rs::{Encoding, WINDOWS_1252};
+
+use super::config::Network;
+
+/// Decodes lines from one network.
+#[derive(Debug)]
+pub struct Decoder {
+    fallback: &'static Encoding,
+    /// Channels with encodings of their own.
+    channels: Vec<(String, &'static Encoding)>,
+}
+
+/// Returns the encoding `label` names, as the WHATWG Encoding Standard
+/// describes them.
+pub fn for_label(label: &str) -> Option<&'static Encoding> {
+    Encoding::for_label(label.as_bytes())
+}
+
+/// Returns the middle parameters of the raw `line`, skipping its tags,
+/// source and command.
+fn middle_params(line: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let mut words = line.split(|&b| b == b' ').filter(|word| !word.is_empty());
+    let mut command = words.next();
+    while command.is_some_and(|word| word.starts_with(b"@") || word.starts_with(b":")) {
+        command = words.next();
+    }
+
+    words.take_while(|word| !word.starts_with(b":"))
+}
+
+impl Decoder {
+    pub fn new(network: &Network) -> Self {
+        let fallback = network
+            .encoding
+            .as_deref()
+            .and_then(for_label)
+            .unwrap_or(WINDOWS_1252);
+        let channels = network
+            .channels
+            .iter()
+            .filter_map(|channel| {
+                let encoding = for_label(channel.encoding.as_deref()?)?;
+                Some((channel.name.clone(), encoding))
+            })
+            .collect();
+
+        Decoder { fallback, channels }
+    }
+
+    /// Returns the encoding of a line that isn't UTF-8.
+    fn encoding(&self, line: &[u8]) -> &'static Encoding {
+        middle_params(line)
+            .find_map(|param| {
+                self.channels
+                    .iter()
+                    .find(|(name, _)| name.as_bytes().eq_ignore_ascii_case(param))
+                    .map(|(_, encoding)| *encoding)
+            })
+            .unwrap_or(self.fallback)
+    }
+
+    /// Decodes a line, without its line ending.
+    pub fn decode(&self, line: &[u8]) -> String {
+        if let Ok(line) = std::str::from_utf8(line) {
+            return line.to_string();
+        }
+
+        match self
+            .encoding(line)
+            .decode_without_bom_handling_and_without_replacement(line)
+        {
+            Some(line) => line.into_owned(),
+            None => line.iter().map(|&b| char::from(b)).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use anyhow::Result;
+
+    use crate::config::NetworkChannel;
+
+    fn network_decoder(fallback: Option<&str>, channels: &[(&str, &str)]) -> Result<Decoder> {
+        let mut network: Network = toml::from_str(
+            "name = \"n\"\nnick_choices = [\"n\"]\nusername = \"u\"\nrealname = \"r\"",
+        )?;
+        network.encoding = fallback.map(|label| label.to_string());
+        network.channels = channels
+            .iter()
+            .map(|(name, encoding)| NetworkChannel {
+                name: name.to_string(),
+                key: None,
+                encoding: Some(encoding.to_string()),
+            })
+            .collect();
+
+        Ok(Decoder::new(&network))
+    }
+
+    #[test]
+    fn test_decode() -> Result<()> {
+        let decoder = network_decoder(None, &[("#tokyo", "shift_jis")])?;
+
+        assert_eq!(
+            decoder.decode("PRIVMSG #a :café".as_bytes()),
+            "PRIVMSG #a :café"
+        );
+        assert_eq!(
+            decoder.decode(b"PRIVMSG #a :caf\xe9 \x80"),
+            "PRIVMSG #a :café €"
+        );
+        assert_eq!(
+            decoder.decode(b"@t=1 :a!b@c PRIVMSG #Tokyo :\x82\xb1\x82\xf1"),
+            "@t=1 :a!b@c PRIVMSG #Tokyo :こん"
+        );
+        // Not valid Shift_JIS either, so kept byte for byte
+        assert_eq!(
+            decoder.decode(b"PRIVMSG #tokyo :\x82\xff"),
+            "PRIVMSG #tokyo :\u{82}\u{ff}"
+        );
+        // The channel has to be a middle parameter
+        assert_eq!(
+            decoder.decode(b"PRIVMSG #a :#tokyo \xe9"),
+            "PRIVMSG #a :#tokyo é"
+        );
+
+        let decoder = network_decoder(Some("koi8-r"), &[])?;
+        assert_eq!(
+            decoder.decode(b"PRIVMSG #a :\xf0\xd2\xc9"),
+            "PRIVMSG #a :При"
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,482 @@
+//! Renders logged messages for people to read, for `bounce logs export`.
+//!
+//! Messages can be rendered like irssi's or weechat's own logs, as JSON
+//! Lines with a message per line, or as a standalone HTML transcript with
+//! each nick in its own color. Times are shown in the configured `[log]`
+//! timezone.
+
+use std::fmt::Write;
+use std::str::FromStr;
+
+use anyhow::{format_err, Result};
+use chrono::{DateTime, NaiveDate, Utc};
+use chrono_tz::Tz;
+
+use super::cap::{format_server_time, parse_server_time};
+use super::irc::Message;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Irssi,
+    Weechat,
+    Jsonl,
+    Html,
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(format: &str) -> Result<Self> {
+        match format {
+            "irssi" => Ok(Format::Irssi),
+            "weechat" => Ok(Format::Weechat),
+            "jsonl" => Ok(Format::Jsonl),
+            "html" => Ok(Format::Html),
+            _ => Err(format_err!(
+                "Unknown format \"{}\", expected irssi, weechat, jsonl or html",
+                format
+            )),
+        }
+    }
+}
+
+/// What a logged message did, as far as a reader is concerned.
+#[derive(Debug, PartialEq)]
+enum Event<'a> {
+    Message(&'a str),
+    Action(&'a str),
+    Notice(&'a str),
+    Join(&'a str),
+    Part(&'a str, Option<&'a str>),
+    Quit(Option<&'a str>),
+    Nick(&'a str),
+    Kick {
+        channel: &'a str,
+        kicked: &'a str,
+        reason: Option<&'a str>,
+    },
+    Topic(&'a str, &'a str),
+    Mode(&'a str, String),
+    /// Anything else, such as numerics, described by its text.
+    Other(String),
+}
+
+impl<'a> Event<'a> {
+    fn from_message(message: &'a Message) -> Self {
+        let params = message.params();
+        let param = |i: usize| params.get(i).map(|param| param.as_str());
+        let text = param(1).unwrap_or_default();
+
+        match (message.command(), param(0)) {
+            ("PRIVMSG", Some(_)) => match text
+                .strip_prefix("\x01ACTION ")
+                .map(|action| action.trim_end_matches('\x01'))
+            {
+                Some(action) => Event::Action(action),
+                None => Event::Message(text),
+            },
+            ("NOTICE", Some(_)) => Event::Notice(text),
+            ("JOIN", Some(channel)) => Event::Join(channel),
+            ("PART", Some(channel)) => Event::Part(channel, param(1)),
+            ("QUIT", reason) => Event::Quit(reason),
+            ("NICK", Some(nick)) => Event::Nick(nick),
+            ("KICK", Some(channel)) => Event::Kick {
+                channel,
+                kicked: text,
+                reason: param(2),
+            },
+            ("TOPIC", Some(channel)) => Event::Topic(channel, text),
+            ("MODE", Some(target)) => Event::Mode(target, params[1..].join(" ")),
+            _ => Event::Other(params.iter().skip(1).cloned().collect::<Vec<_>>().join(" ")),
+        }
+    }
+}
+
+/// A message along with the details every format shows.
+struct Line<'a> {
+    time: DateTime<Tz>,
+    nick: &'a str,
+    /// The `user@host` of the sender, if known.
+    mask: Option<String>,
+    event: Event<'a>,
+    message: &'a Message,
+}
+
+impl<'a> Line<'a> {
+    fn new(message: &'a Message, timezone: Tz) -> Self {
+        let time = message
+            .tag("time")
+            .and_then(|tag| tag.value())
+            .and_then(parse_server_time)
+            .unwrap_or_default();
+        let mask = message.prefix().and_then(|prefix| {
+            let prefix = prefix.to_string();
+            prefix.split_once('!').map(|(_, mask)| mask.to_string())
+        });
+
+        Line {
+            time: time.with_timezone(&timezone),
+            nick: message.prefix().map_or("", |prefix| prefix.entity()),
+            mask,
+            event: Event::from_message(message),
+            message,
+        }
+    }
+
+    fn date(&self) -> NaiveDate {
+        self.time.date_naive()
+    }
+}
+
+fn with_reason(text: String, reason: Option<&str>, open: char, close: char) -> String {
+    match reason {
+        Some(reason) => format!("{} {}{}{}", text, open, reason, close),
+        None => text,
+    }
+}
+
+fn irssi_line(line: &Line) -> String {
+    let nick = line.nick;
+    let mask = line.mask.as_deref().unwrap_or_default();
+    let text = match &line.event {
+        Event::Message(text) => format!("<{}> {}", nick, text),
+        Event::Action(text) => format!(" * {} {}", nick, text),
+        Event::Notice(text) => format!("-{}- {}", nick, text),
+        Event::Join(channel) => format!("-!- {} [{}] has joined {}", nick, mask, channel),
+        Event::Part(channel, reason) => with_reason(
+            format!("-!- {} [{}] has left {}", nick, mask, channel),
+            *reason,
+            '[',
+            ']',
+        ),
+        Event::Quit(reason) => format!(
+            "-!- {} [{}] has quit [{}]",
+            nick,
+            mask,
+            reason.unwrap_or_default()
+        ),
+        Event::Nick(new_nick) => format!("-!- {} is now known as {}", nick, new_nick),
+        Event::Kick {
+            channel,
+            kicked,
+            reason,
+        } => format!(
+            "-!- {} was kicked from {} by {} [{}]",
+            kicked,
+            channel,
+            nick,
+            reason.unwrap_or_default()
+        ),
+        Event::Topic(channel, topic) => {
+            format!(
+                "-!- {} changed the topic of {} to: {}",
+                nick, channel, topic
+            )
+        }
+        Event::Mode(target, modes) => format!("-!- mode/{} [{}] by {}", target, modes, nick),
+        Event::Other(text) => format!("-!- {}", text),
+    };
+
+    format!("{} {}", line.time.format("%H:%M"), text)
+}
+
+/// Returns the prefix and message weechat shows for a line, which are
+/// separated by a tab in its logs.
+fn weechat_columns(line: &Line) -> (String, String) {
+    let nick = line.nick;
+    let mask = line.mask.as_deref().unwrap_or_default();
+    let (prefix, text) = match &line.event {
+        Event::Message(text) => (nick, text.to_string()),
+        Event::Action(text) => (" *", format!("{} {}", nick, text)),
+        Event::Notice(text) => ("--", format!("Notice({}): {}", nick, text)),
+        Event::Join(channel) => ("-->", format!("{} ({}) has joined {}", nick, mask, channel)),
+        Event::Part(channel, reason) => (
+            "<--",
+            with_reason(
+                format!("{} ({}) has left {}", nick, mask, channel),
+                *reason,
+                '(',
+                ')',
+            ),
+        ),
+        Event::Quit(reason) => (
+            "<--",
+            with_reason(format!("{} ({}) has quit", nick, mask), *reason, '(', ')'),
+        ),
+        Event::Nick(new_nick) => ("--", format!("{} is now known as {}", nick, new_nick)),
+        Event::Kick {
+            channel,
+            kicked,
+            reason,
+        } => (
+            "<--",
+            with_reason(
+                format!("{} has kicked {} from {}", nick, kicked, channel),
+                *reason,
+                '(',
+                ')',
+            ),
+        ),
+        Event::Topic(channel, topic) => (
+            "--",
+            format!(
+                "{} has changed topic for {} to \"{}\"",
+                nick, channel, topic
+            ),
+        ),
+        Event::Mode(target, modes) => ("--", format!("Mode {} [{}] by {}", target, modes, nick)),
+        Event::Other(text) => ("--", text.clone()),
+    };
+
+    (prefix.to_string(), text)
+}
+
+fn json_string(value: &str) -> String {
+    let mut json = String::with_capacity(value.len() + 2);
+    json.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(json, "\\u{:04x}", c as u32);
+            }
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+fn json_line(line: &Line) -> String {
+    let message = line.message;
+    let optional = |value: Option<&str>| value.map_or("null".to_string(), json_string);
+    let params: Vec<String> = message
+        .params()
+        .iter()
+        .map(|param| json_string(param))
+        .collect();
+
+    format!(
+        "{{\"time\":{},\"msgid\":{},\"nick\":{},\"command\":{},\"params\":[{}]}}",
+        json_string(&format_server_time(&line.time.with_timezone(&Utc))),
+        optional(message.tag("msgid").and_then(|tag| tag.value())),
+        optional(message.prefix().map(|prefix| prefix.entity())),
+        json_string(message.command()),
+        params.join(",")
+    )
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Picks a hue for a nick, so that it's shown in the same color wherever
+/// it appears.
+fn nick_hue(nick: &str) -> u32 {
+    let hash = nick.bytes().fold(5381u32, |hash, byte| {
+        hash.wrapping_mul(33) ^ u32::from(byte)
+    });
+    hash % 360
+}
+
+const HTML_STYLE: &str = "body { font-family: monospace; background: #fdfdfd; color: #222; }
+h2 { font-size: 1em; border-bottom: 1px solid #ccc; }
+.line { white-space: pre-wrap; }
+.time { color: #888; }
+.event { color: #666; }";
+
+fn html_line(line: &Line) -> String {
+    let nick = |nick: &str| {
+        format!(
+            "<span class=\"nick\" style=\"color: hsl({}, 65%, 40%)\">{}</span>",
+            nick_hue(nick),
+            escape_html(nick)
+        )
+    };
+
+    let body = match &line.event {
+        Event::Message(text) => format!("&lt;{}&gt; {}", nick(line.nick), escape_html(text)),
+        Event::Action(text) => format!("* {} {}", nick(line.nick), escape_html(text)),
+        Event::Notice(text) => format!("-{}- {}", nick(line.nick), escape_html(text)),
+        _ => {
+            let (_, text) = weechat_columns(line);
+            format!("<span class=\"event\">{}</span>", escape_html(&text))
+        }
+    };
+
+    format!(
+        "<div class=\"line\"><span class=\"time\">{}</span> {}</div>",
+        line.time.format("%H:%M:%S"),
+        body
+    )
+}
+
+/// Renders `messages`, which must be ordered by when they were received,
+/// in `format` with times in `timezone`. `title` heads HTML transcripts.
+pub fn render(format: Format, messages: &[Message], timezone: Tz, title: &str) -> String {
+    let lines: Vec<Line> = messages
+        .iter()
+        .map(|message| Line::new(message, timezone))
+        .collect();
+
+    let mut output = String::new();
+    let mut date = None;
+    match format {
+        Format::Irssi => {
+            for line in &lines {
+                if date.is_none() {
+                    let _ = writeln!(
+                        output,
+                        "--- Log opened {}",
+                        line.time.format("%a %b %d %H:%M:%S %Y")
+                    );
+                } else if date != Some(line.date()) {
+                    let _ = writeln!(
+                        output,
+                        "--- Day changed {}",
+                        line.time.format("%a %b %d %Y")
+                    );
+                }
+                date = Some(line.date());
+                let _ = writeln!(output, "{}", irssi_line(line));
+            }
+        }
+        Format::Weechat => {
+            for line in &lines {
+                let (prefix, text) = weechat_columns(line);
+                let _ = writeln!(
+                    output,
+                    "{}\t{}\t{}",
+                    line.time.format("%Y-%m-%d %H:%M:%S"),
+                    prefix,
+                    text
+                );
+            }
+        }
+        Format::Jsonl => {
+            for line in &lines {
+                let _ = writeln!(output, "{}", json_line(line));
+            }
+        }
+        Format::Html => {
+            let _ = writeln!(
+                output,
+                "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n<style>\n{}\n</style>\n</head>\n<body>\n<h1>{}</h1>",
+                escape_html(title),
+                HTML_STYLE,
+                escape_html(title)
+            );
+            for line in &lines {
+                if date != Some(line.date()) {
+                    let _ = writeln!(output, "<h2>{}</h2>", line.date());
+                    date = Some(line.date());
+                }
+                let _ = writeln!(output, "{}", html_line(line));
+            }
+            output.push_str("</body>\n</html>\n");
+        }
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use anyhow::Result;
+
+    fn messages() -> Result<Vec<Message>> {
+        [
+            "@time=2026-10-18T21:59:00.000Z;msgid=a :jay!j@host JOIN #rust",
+            "@time=2026-10-18T22:00:00.000Z;msgid=b :jay!j@host PRIVMSG #rust :<b>hi</b> \"all\"",
+            "@time=2026-10-18T22:01:00.000Z;msgid=c :jay!j@host PRIVMSG #rust :\x01ACTION waves\x01",
+            "@time=2026-10-18T22:02:00.000Z;msgid=d :jay!j@host QUIT :Bye",
+        ]
+        .iter()
+        .map(|line| Ok(Message::from_str(line)?))
+        .collect()
+    }
+
+    #[test]
+    fn test_irssi() -> Result<()> {
+        assert_eq!(
+            render(
+                Format::Irssi,
+                &messages()?,
+                chrono_tz::Europe::Berlin,
+                "#rust"
+            ),
+            "--- Log opened Sun Oct 18 23:59:00 2026\n\
+             23:59 -!- jay [j@host] has joined #rust\n\
+             --- Day changed Mon Oct 19 2026\n\
+             00:00 <jay> <b>hi</b> \"all\"\n\
+             00:01  * jay waves\n\
+             00:02 -!- jay [j@host] has quit [Bye]\n"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_weechat() -> Result<()> {
+        assert_eq!(
+            render(Format::Weechat, &messages()?, Tz::UTC, "#rust"),
+            "2026-10-18 21:59:00\t-->\tjay (j@host) has joined #rust\n\
+             2026-10-18 22:00:00\tjay\t<b>hi</b> \"all\"\n\
+             2026-10-18 22:01:00\t *\tjay waves\n\
+             2026-10-18 22:02:00\t<--\tjay (j@host) has quit (Bye)\n"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_jsonl() -> Result<()> {
+        let output = render(Format::Jsonl, &messages()?, Tz::UTC, "#rust");
+        assert_eq!(
+            output.lines().nth(1),
+            Some(
+                "{\"time\":\"2026-10-18T22:00:00.000Z\",\"msgid\":\"b\",\"nick\":\"jay\",\
+                 \"command\":\"PRIVMSG\",\"params\":[\"#rust\",\"<b>hi</b> \\\"all\\\"\"]}"
+            )
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_html() -> Result<()> {
+        let output = render(Format::Html, &messages()?, Tz::UTC, "#rust");
+        let nick = format!(
+            "<span class=\"nick\" style=\"color: hsl({}, 65%, 40%)\">jay</span>",
+            nick_hue("jay")
+        );
+
+        assert!(output.starts_with("<!DOCTYPE html>"));
+        assert!(output.contains("<h2>2026-10-18</h2>"));
+        assert!(output.contains(&format!(
+            "<span class=\"time\">22:00:00</span> &lt;{}&gt; &lt;b&gt;hi&lt;/b&gt; &quot;all&quot;",
+            nick
+        )));
+        assert!(output.contains("jay (j@host) has joined #rust"));
+        assert!(output.ends_with("</html>\n"));
+
+        Ok(())
+    }
+}
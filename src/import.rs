@@ -0,0 +1,562 @@
+//! Parses logs written by other clients and bouncers, for
+//! `bounce logs import`.
+//!
+//! ZNC's log module writes a file per day, named after the day and kept
+//! in a directory named after the channel or nick (or, in older versions,
+//! named `<network>_<channel>_<YYYYMMDD>.log`), with lines like
+//!
+//!   [08:30:06] <jay> hi
+//!   [08:30:10] *** Joins: belak (b@host)
+//!
+//! weechat writes a file per buffer, named `irc.<server>.<channel>.weechatlog`,
+//! with lines of tab-separated time, prefix and message like
+//!
+//!   2026-10-18 08:30:06 <tab> jay <tab> hi
+//!   2026-10-18 08:30:10 <tab> --> <tab> belak (b@host) has joined #rust
+//!
+//! Each line is turned back into the message that caused it, so that it
+//! can be logged like any other. Lines that don't describe a message,
+//! such as ZNC's and weechat's own notes, are skipped.
+
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{format_err, Result};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc};
+use chrono_tz::Tz;
+
+use super::cap::format_server_time;
+use super::irc::{Message, Prefix};
+
+const ZNC_DATE_FORMAT: &str = "%Y-%m-%d";
+const ZNC_LEGACY_DATE_FORMAT: &str = "%Y%m%d";
+const WEECHAT_TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+const WEECHAT_EXTENSION: &str = ".weechatlog";
+
+/// Channel membership prefixes weechat shows before nicks.
+const NICK_PREFIXES: &[char] = &['~', '&', '@', '%', '+'];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Znc,
+    Weechat,
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(format: &str) -> Result<Self> {
+        match format {
+            "znc" => Ok(Format::Znc),
+            "weechat" => Ok(Format::Weechat),
+            _ => Err(format_err!(
+                "Unknown format \"{}\", expected znc or weechat",
+                format
+            )),
+        }
+    }
+}
+
+impl Format {
+    /// Returns whether the file at `path` looks like one of this format's
+    /// logs.
+    pub fn is_log(self, path: &Path) -> bool {
+        let name = path.file_name().and_then(|name| name.to_str());
+        match self {
+            Format::Znc => name.is_some_and(|name| name.ends_with(".log")),
+            Format::Weechat => name.is_some_and(|name| name.ends_with(WEECHAT_EXTENSION)),
+        }
+    }
+}
+
+/// The conversation a log file holds, and everything else about it that
+/// isn't repeated on every line.
+#[derive(Debug, PartialEq)]
+pub struct LogFile {
+    /// The channel or nick the log is for.
+    pub target: String,
+    /// The day the log is for, if its lines don't say.
+    date: Option<NaiveDate>,
+}
+
+impl LogFile {
+    /// Describes the log at `path` from its name. `target` overrides the
+    /// channel or nick the name gives.
+    pub fn parse(format: Format, path: &Path, target: Option<&str>) -> Result<Self> {
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| format_err!("Invalid log name {:?}", path))?;
+        let unrecognized = || format_err!("Unrecognized log name {:?}", path);
+
+        let (name_target, date) = match format {
+            Format::Znc => {
+                let stem = name.strip_suffix(".log").ok_or_else(unrecognized)?;
+                match NaiveDate::parse_from_str(stem, ZNC_DATE_FORMAT) {
+                    // Named for the day, within the channel's directory
+                    Ok(date) => (
+                        path.parent()
+                            .and_then(|parent| parent.file_name())
+                            .and_then(|parent| parent.to_str())
+                            .map(|parent| parent.to_string()),
+                        Some(date),
+                    ),
+                    Err(_) => {
+                        let (rest, date) = stem.rsplit_once('_').ok_or_else(unrecognized)?;
+                        let date = NaiveDate::parse_from_str(date, ZNC_LEGACY_DATE_FORMAT)
+                            .map_err(|_| unrecognized())?;
+                        let target = rest.split_once('_').map(|(_, target)| target.to_string());
+                        (target, Some(date))
+                    }
+                }
+            }
+            Format::Weechat => {
+                let buffer = name
+                    .strip_suffix(WEECHAT_EXTENSION)
+                    .ok_or_else(unrecognized)?;
+                let target = buffer
+                    .strip_prefix("irc.")
+                    .and_then(|buffer| buffer.split_once('.'))
+                    .map(|(_, target)| target.to_string());
+                (target, None)
+            }
+        };
+
+        let target = target
+            .map(|target| target.to_string())
+            .or(name_target)
+            .ok_or_else(|| format_err!("Unable to tell the channel of {:?}", path))?;
+
+        Ok(LogFile { target, date })
+    }
+
+    fn is_channel(&self) -> bool {
+        self.target.starts_with(|c| "#&+!".contains(c))
+    }
+
+    /// Returns the target of a message sent by `nick`. In a query, the
+    /// user's messages were sent to the log's target, and everyone else's
+    /// to `own_nick`, the user's nick, when it's known.
+    fn message_target(&self, nick: &str, own_nick: Option<&str>) -> String {
+        match own_nick {
+            Some(own_nick) if !self.is_channel() && nick != own_nick => own_nick.to_string(),
+            _ => self.target.clone(),
+        }
+    }
+}
+
+fn message(
+    time: DateTime<Utc>,
+    source: &str,
+    mask: Option<&str>,
+    command: &str,
+    params: Vec<String>,
+) -> Message {
+    let prefix = match mask {
+        Some(mask) => format!("{}!{}", source, mask),
+        None => source.to_string(),
+    };
+    let mut message = Message::new(Prefix::from_str(&prefix).ok(), command, params);
+    message.set_tag("time", Some(&format_server_time(&time)));
+    message
+}
+
+/// Splits `nick (mask)` into its parts.
+fn nick_and_mask(text: &str) -> (&str, Option<&str>) {
+    match text.split_once(" (") {
+        Some((nick, mask)) => (nick, mask.strip_suffix(')')),
+        None => (text, None),
+    }
+}
+
+/// Splits `words (reason)` into its parts. Reasons may hold parentheses
+/// of their own, but the words before them never hold spaces.
+fn split_reason(text: &str) -> (&str, Option<&str>) {
+    match text.split_once(" (") {
+        Some((text, reason)) => (text, Some(reason.strip_suffix(')').unwrap_or(reason))),
+        None => (text, None),
+    }
+}
+
+/// Splits ZNC's `nick (mask) (reason)` into its parts.
+fn split_user_reason(text: &str) -> (&str, Option<&str>, Option<&str>) {
+    let (nick, rest) = match text.split_once(" (") {
+        Some(parts) => parts,
+        None => return (text, None, None),
+    };
+    match rest.split_once(") (") {
+        Some((mask, reason)) => (
+            nick,
+            Some(mask),
+            Some(reason.strip_suffix(')').unwrap_or(reason)),
+        ),
+        None => (nick, rest.strip_suffix(')'), None),
+    }
+}
+
+fn local_time(timezone: Tz, time: NaiveDateTime) -> Option<DateTime<Utc>> {
+    Some(
+        timezone
+            .from_local_datetime(&time)
+            .earliest()?
+            .with_timezone(&Utc),
+    )
+}
+
+/// Turns a line of a ZNC log back into a message, taking times to be in
+/// `timezone`.
+fn parse_znc_line(
+    line: &str,
+    log: &LogFile,
+    timezone: Tz,
+    own_nick: Option<&str>,
+) -> Option<Message> {
+    let line = line.strip_prefix('[')?;
+    let (time, rest) = line.split_once("] ")?;
+    let time = NaiveTime::parse_from_str(time, "%H:%M:%S").ok()?;
+    let time = local_time(timezone, log.date?.and_time(time))?;
+    let target = log.target.clone();
+
+    if let Some(rest) = rest.strip_prefix('<') {
+        let (nick, text) = rest.split_once("> ")?;
+        let params = vec![log.message_target(nick, own_nick), text.to_string()];
+        return Some(message(time, nick, None, "PRIVMSG", params));
+    }
+    if let Some(rest) = rest.strip_prefix("*** ") {
+        if let Some(rest) = rest.strip_prefix("Joins: ") {
+            let (nick, mask) = nick_and_mask(rest);
+            return Some(message(time, nick, mask, "JOIN", vec![target]));
+        }
+        if let Some(rest) = rest.strip_prefix("Parts: ") {
+            let (nick, mask, reason) = split_user_reason(rest);
+            let mut params = vec![target];
+            params.extend(reason.filter(|r| !r.is_empty()).map(|r| r.to_string()));
+            return Some(message(time, nick, mask, "PART", params));
+        }
+        if let Some(rest) = rest.strip_prefix("Quits: ") {
+            let (nick, mask, reason) = split_user_reason(rest);
+            let params = reason.map(|r| r.to_string()).into_iter().collect();
+            return Some(message(time, nick, mask, "QUIT", params));
+        }
+        if let Some((nick, new_nick)) = rest.split_once(" is now known as ") {
+            return Some(message(
+                time,
+                nick,
+                None,
+                "NICK",
+                vec![new_nick.to_string()],
+            ));
+        }
+        if let Some((kicked, rest)) = rest.split_once(" was kicked by ") {
+            let (op, reason) = split_reason(rest);
+            let mut params = vec![target, kicked.to_string()];
+            params.extend(reason.map(|r| r.to_string()));
+            return Some(message(time, op, None, "KICK", params));
+        }
+        if let Some((nick, topic)) = rest.split_once(" changes topic to '") {
+            let topic = topic.strip_suffix('\'').unwrap_or(topic);
+            return Some(message(
+                time,
+                nick,
+                None,
+                "TOPIC",
+                vec![target, topic.to_string()],
+            ));
+        }
+        if let Some((nick, modes)) = rest.split_once(" sets mode: ") {
+            let mut params = vec![target];
+            params.extend(modes.split_whitespace().map(|mode| mode.to_string()));
+            return Some(message(time, nick, None, "MODE", params));
+        }
+        return None;
+    }
+    if let Some(rest) = rest.strip_prefix("* ") {
+        let (nick, text) = rest.split_once(' ')?;
+        let params = vec![
+            log.message_target(nick, own_nick),
+            format!("\x01ACTION {}\x01", text),
+        ];
+        return Some(message(time, nick, None, "PRIVMSG", params));
+    }
+    if let Some(rest) = rest.strip_prefix('-') {
+        let (nick, text) = rest.split_once("- ")?;
+        let params = vec![log.message_target(nick, own_nick), text.to_string()];
+        return Some(message(time, nick, None, "NOTICE", params));
+    }
+
+    None
+}
+
+/// Turns a line of a weechat log back into a message, taking times to be
+/// in `timezone`.
+fn parse_weechat_line(
+    line: &str,
+    log: &LogFile,
+    timezone: Tz,
+    own_nick: Option<&str>,
+) -> Option<Message> {
+    let mut columns = line.splitn(3, '\t');
+    let time = NaiveDateTime::parse_from_str(columns.next()?, WEECHAT_TIME_FORMAT).ok()?;
+    let time = local_time(timezone, time)?;
+    let prefix = columns.next()?;
+    let text = columns.next()?;
+    let target = log.target.clone();
+
+    match prefix {
+        "-->" => {
+            let (rest, channel) = text.split_once(" has joined ")?;
+            let (nick, mask) = nick_and_mask(rest);
+            Some(message(time, nick, mask, "JOIN", vec![channel.to_string()]))
+        }
+        "<--" => {
+            if let Some((op, rest)) = text.split_once(" has kicked ") {
+                let (rest, reason) = split_reason(rest);
+                // Newer versions name the channel too
+                let kicked = rest.split(" from ").next().unwrap_or(rest);
+                let mut params = vec![target, kicked.to_string()];
+                params.extend(reason.map(|r| r.to_string()));
+                return Some(message(time, op, None, "KICK", params));
+            }
+
+            if let Some((user, rest)) = text.split_once(" has left ") {
+                let (nick, mask) = nick_and_mask(user);
+                let (channel, reason) = split_reason(rest);
+                let mut params = vec![channel.to_string()];
+                params.extend(reason.map(|r| r.to_string()));
+                return Some(message(time, nick, mask, "PART", params));
+            }
+            let (user, rest) = text.split_once(" has quit")?;
+            let (nick, mask) = nick_and_mask(user);
+            let reason = rest
+                .trim_start()
+                .strip_prefix('(')
+                .and_then(|reason| reason.strip_suffix(')'));
+            let params = reason.map(|r| r.to_string()).into_iter().collect();
+            Some(message(time, nick, mask, "QUIT", params))
+        }
+        "--" => {
+            if let Some((nick, new_nick)) = text.split_once(" is now known as ") {
+                return Some(message(
+                    time,
+                    nick,
+                    None,
+                    "NICK",
+                    vec![new_nick.to_string()],
+                ));
+            }
+            if let Some(rest) = text.strip_prefix("Notice(") {
+                let (nick, rest) = rest.split_once(')')?;
+                let (_, text) = rest.split_once(": ")?;
+                let params = vec![log.message_target(nick, own_nick), text.to_string()];
+                return Some(message(time, nick, None, "NOTICE", params));
+            }
+            if let Some(rest) = text.strip_prefix("Mode ") {
+                let (channel, rest) = rest.split_once(" [")?;
+                let (modes, nick) = rest.split_once("] by ")?;
+                let mut params = vec![channel.to_string()];
+                params.extend(modes.split_whitespace().map(|mode| mode.to_string()));
+                return Some(message(time, nick, None, "MODE", params));
+            }
+            if let Some((nick, rest)) = text.split_once(" has changed topic for ") {
+                let (channel, rest) = rest.split_once(' ')?;
+                // The old topic may be given too, but only the last is new
+                let topic = rest.rsplit_once("to \"")?.1.strip_suffix('"')?;
+                let params = vec![channel.to_string(), topic.to_string()];
+                return Some(message(time, nick, None, "TOPIC", params));
+            }
+            None
+        }
+        " *" => {
+            let (nick, text) = text.split_once(' ')?;
+            let params = vec![
+                log.message_target(nick, own_nick),
+                format!("\x01ACTION {}\x01", text),
+            ];
+            Some(message(time, nick, None, "PRIVMSG", params))
+        }
+        // Anything else with a prefix weechat uses for itself is a note
+        "" | "=!=" | " " => None,
+        nick => {
+            let nick = nick.trim_start_matches(NICK_PREFIXES);
+            if nick.is_empty() || nick.contains(' ') {
+                return None;
+            }
+            let params = vec![log.message_target(nick, own_nick), text.to_string()];
+            Some(message(time, nick, None, "PRIVMSG", params))
+        }
+    }
+}
+
+/// Turns the lines of a log back into messages, along with how many lines
+/// were skipped.
+pub fn parse_log(
+    format: Format,
+    contents: &str,
+    log: &LogFile,
+    timezone: Tz,
+    own_nick: Option<&str>,
+) -> (Vec<Message>, usize) {
+    let mut messages = Vec::new();
+    let mut skipped = 0;
+    for line in contents.lines().filter(|line| !line.is_empty()) {
+        let message = match format {
+            Format::Znc => parse_znc_line(line, log, timezone, own_nick),
+            Format::Weechat => parse_weechat_line(line, log, timezone, own_nick),
+        };
+        match message {
+            Some(message) => messages.push(message),
+            None => skipped += 1,
+        }
+    }
+
+    (messages, skipped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use anyhow::Result;
+
+    fn lines(messages: &[Message]) -> Vec<String> {
+        messages.iter().map(|message| message.to_string()).collect()
+    }
+
+    #[test]
+    fn test_log_names() -> Result<()> {
+        assert_eq!(
+            LogFile::parse(Format::Znc, Path::new("libera/#rust/2026-10-18.log"), None)?,
+            LogFile {
+                target: "#rust".to_string(),
+                date: NaiveDate::from_ymd_opt(2026, 10, 18),
+            }
+        );
+        assert_eq!(
+            LogFile::parse(Format::Znc, Path::new("libera_belak_20261018.log"), None)?,
+            LogFile {
+                target: "belak".to_string(),
+                date: NaiveDate::from_ymd_opt(2026, 10, 18),
+            }
+        );
+        assert_eq!(
+            LogFile::parse(
+                Format::Weechat,
+                Path::new("logs/irc.libera.#rust.weechatlog"),
+                None
+            )?
+            .target,
+            "#rust"
+        );
+        assert_eq!(
+            LogFile::parse(
+                Format::Weechat,
+                Path::new("core.weechat.weechatlog"),
+                Some("x")
+            )?
+            .target,
+            "x"
+        );
+        assert!(
+            LogFile::parse(Format::Weechat, Path::new("core.weechat.weechatlog"), None).is_err()
+        );
+        assert!(LogFile::parse(Format::Znc, Path::new("notes.txt"), None).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_znc() -> Result<()> {
+        let log = LogFile::parse(Format::Znc, Path::new("#rust/2026-10-18.log"), None)?;
+        let contents = "[08:30:06] <jay> hi there\n\
+                        [08:30:07] * jay waves\n\
+                        [08:30:08] -belak- psst\n\
+                        [08:30:09] *** Joins: belak (b@host)\n\
+                        [08:30:10] *** Parts: belak (b@host) (see you)\n\
+                        [08:30:11] *** Quits: jay (j@host) (Quit: bye (really))\n\
+                        [08:30:12] *** belak is now known as belak_\n\
+                        [08:30:13] *** jay was kicked by belak_ (spam)\n\
+                        [08:30:14] *** belak_ changes topic to 'Rust: it's fast'\n\
+                        [08:30:15] *** belak_ sets mode: +o jay\n\
+                        [08:30:16] *** Buffer Playback...\n\
+                        garbage\n";
+
+        let (messages, skipped) =
+            parse_log(Format::Znc, contents, &log, chrono_tz::Europe::Berlin, None);
+        assert_eq!(
+            lines(&messages),
+            vec![
+                "@time=2026-10-18T06:30:06.000Z :jay PRIVMSG #rust :hi there",
+                "@time=2026-10-18T06:30:07.000Z :jay PRIVMSG #rust :\x01ACTION waves\x01",
+                "@time=2026-10-18T06:30:08.000Z :belak NOTICE #rust psst",
+                "@time=2026-10-18T06:30:09.000Z :belak!b@host JOIN #rust",
+                "@time=2026-10-18T06:30:10.000Z :belak!b@host PART #rust :see you",
+                "@time=2026-10-18T06:30:11.000Z :jay!j@host QUIT :Quit: bye (really)",
+                "@time=2026-10-18T06:30:12.000Z :belak NICK belak_",
+                "@time=2026-10-18T06:30:13.000Z :belak_ KICK #rust jay spam",
+                "@time=2026-10-18T06:30:14.000Z :belak_ TOPIC #rust :Rust: it's fast",
+                "@time=2026-10-18T06:30:15.000Z :belak_ MODE #rust +o jay",
+            ]
+        );
+        assert_eq!(skipped, 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_weechat() -> Result<()> {
+        let log = LogFile::parse(
+            Format::Weechat,
+            Path::new("irc.libera.belak.weechatlog"),
+            None,
+        )?;
+        let contents = "2026-10-18 08:30:06\tbelak\thi\n\
+                        2026-10-18 08:30:07\tjay\thello\n\
+                        2026-10-18 08:30:08\t *\tbelak waves\n\
+                        2026-10-18 08:30:09\t--\tNotice(belak) -> jay: psst\n\
+                        2026-10-18 08:30:10\t--\tbelak is now known as belak_\n\
+                        2026-10-18 08:30:11\t<--\tbelak_ (b@host) has quit (Ping timeout)\n\
+                        2026-10-18 08:30:12\t--\tIrc: connected to server\n";
+
+        let (messages, skipped) = parse_log(Format::Weechat, contents, &log, Tz::UTC, Some("jay"));
+        assert_eq!(
+            lines(&messages),
+            vec![
+                "@time=2026-10-18T08:30:06.000Z :belak PRIVMSG jay hi",
+                "@time=2026-10-18T08:30:07.000Z :jay PRIVMSG belak hello",
+                "@time=2026-10-18T08:30:08.000Z :belak PRIVMSG jay :\x01ACTION waves\x01",
+                "@time=2026-10-18T08:30:09.000Z :belak NOTICE jay psst",
+                "@time=2026-10-18T08:30:10.000Z :belak NICK belak_",
+                "@time=2026-10-18T08:30:11.000Z :belak_!b@host QUIT :Ping timeout",
+            ]
+        );
+        assert_eq!(skipped, 1);
+
+        let log = LogFile::parse(
+            Format::Weechat,
+            Path::new("irc.libera.#rust.weechatlog"),
+            None,
+        )?;
+        let contents = "2026-10-18 08:30:06\t-->\tbelak (b@host) has joined #rust\n\
+                        2026-10-18 08:30:07\t@jay\thi\n\
+                        2026-10-18 08:30:08\t<--\tjay has kicked belak (spam)\n\
+                        2026-10-18 08:30:09\t<--\tjay (j@host) has left #rust (bye)\n\
+                        2026-10-18 08:30:10\t--\tjay has changed topic for #rust from \"a\" to \"b c\"\n\
+                        2026-10-18 08:30:11\t--\tMode #rust [+o belak] by jay\n";
+
+        let (messages, skipped) = parse_log(Format::Weechat, contents, &log, Tz::UTC, None);
+        assert_eq!(
+            lines(&messages),
+            vec![
+                "@time=2026-10-18T08:30:06.000Z :belak!b@host JOIN #rust",
+                "@time=2026-10-18T08:30:07.000Z :jay PRIVMSG #rust hi",
+                "@time=2026-10-18T08:30:08.000Z :jay KICK #rust belak spam",
+                "@time=2026-10-18T08:30:09.000Z :jay!j@host PART #rust bye",
+                "@time=2026-10-18T08:30:10.000Z :jay TOPIC #rust :b c",
+                "@time=2026-10-18T08:30:11.000Z :jay MODE #rust +o belak",
+            ]
+        );
+        assert_eq!(skipped, 0);
+
+        Ok(())
+    }
+}
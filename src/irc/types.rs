@@ -4,13 +4,172 @@ use std::str::FromStr;
 
 use thiserror::Error;
 
-#[derive(Error, Debug)]
+/// Longest a message may be without its tags, in bytes, counting the
+/// `\r\n` that ends it.
+pub const MAX_MESSAGE_LENGTH: usize = 512;
+/// Longest the tags of a message may be, in bytes, counting the leading
+/// `@` and the space after them.
+pub const MAX_TAGS_LENGTH: usize = 8191;
+/// Most parameters a message may have.
+pub const MAX_PARAMS: usize = 15;
+
+#[derive(Error, Debug, PartialEq)]
 pub enum InvalidMessageError {
     #[error("Message has no contents")]
     Empty,
+    #[error("Message has tags or a source but no command")]
+    MissingCommand,
+    #[error("Message has an empty source")]
+    EmptyPrefix,
+    #[error("Message has an invalid command \"{0}\"")]
+    InvalidCommand(String),
+    #[error("Message is {0} bytes, more than the {limit} allowed", limit = MAX_MESSAGE_LENGTH)]
+    TooLong(usize),
+    #[error("Message has {0} parameters, more than the {limit} allowed", limit = MAX_PARAMS)]
+    TooManyParams(usize),
+    #[error("Message has an invalid parameter \"{0}\"")]
+    InvalidParam(String),
+    #[error("Message tags are {0} bytes, more than the {limit} allowed", limit = MAX_TAGS_LENGTH)]
+    TagsTooLong(usize),
+}
+
+/// Checks that a line, without its line ending, is short enough to be a
+/// message.
+pub fn check_line_length(line: &[u8]) -> Result<(), InvalidMessageError> {
+    let (tags, message) = match line.first() {
+        Some(b'@') => match line.iter().position(|&b| b == b' ') {
+            Some(space) => line.split_at(space + 1),
+            None => (line, &line[line.len()..]),
+        },
+        _ => (&line[..0], line),
+    };
+
+    if tags.len() > MAX_TAGS_LENGTH {
+        return Err(InvalidMessageError::TagsTooLong(tags.len()));
+    }
+    // The line ending counts too
+    if message.len() + 2 > MAX_MESSAGE_LENGTH {
+        return Err(InvalidMessageError::TooLong(message.len() + 2));
+    }
+
+    Ok(())
+}
+
+/// Checks that `command` is a word or a three digit numeric reply.
+fn parse_command(command: &str) -> Result<&str, InvalidMessageError> {
+    let is_numeric = command.len() == 3 && command.bytes().all(|b| b.is_ascii_digit());
+    let is_word = command.bytes().all(|b| b.is_ascii_alphabetic());
+
+    match command {
+        "" => Err(InvalidMessageError::MissingCommand),
+        _ if is_numeric || is_word => Ok(command),
+        _ => Err(InvalidMessageError::InvalidCommand(command.to_string())),
+    }
+}
+
+/// A single IRCv3 message tag. Values are stored unescaped.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Tag {
+    key: String,
+    value: Option<String>,
+}
+
+impl Tag {
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    pub fn value(&self) -> Option<&str> {
+        self.value.as_deref()
+    }
+
+    /// Client-only tags are prefixed with `+` and are relayed between
+    /// clients without the server interpreting them.
+    pub fn is_client_only(&self) -> bool {
+        self.key.starts_with('+')
+    }
+
+    fn escape_value(value: &str) -> String {
+        let mut escaped = String::with_capacity(value.len());
+        for c in value.chars() {
+            match c {
+                ';' => escaped.push_str("\\:"),
+                ' ' => escaped.push_str("\\s"),
+                '\\' => escaped.push_str("\\\\"),
+                '\r' => escaped.push_str("\\r"),
+                '\n' => escaped.push_str("\\n"),
+                _ => escaped.push(c),
+            }
+        }
+        escaped
+    }
+
+    fn unescape_value(value: &str) -> String {
+        let mut unescaped = String::with_capacity(value.len());
+        let mut chars = value.chars();
+        while let Some(c) = chars.next() {
+            if c != '\\' {
+                unescaped.push(c);
+                continue;
+            }
+
+            // Unknown escapes drop the backslash, and a trailing one is
+            // dropped entirely
+            match chars.next() {
+                Some(':') => unescaped.push(';'),
+                Some('s') => unescaped.push(' '),
+                Some('r') => unescaped.push('\r'),
+                Some('n') => unescaped.push('\n'),
+                Some(other) => unescaped.push(other),
+                None => {}
+            }
+        }
+        unescaped
+    }
+
+    /// Parses the tags section of a message, without its leading `@`.
+    pub fn parse_all(tags: &str) -> Vec<Tag> {
+        let mut parsed: Vec<Tag> = Vec::new();
+
+        for tag in tags.split(';').filter(|tag| !tag.is_empty()) {
+            let (key, value) = match tag.split_once('=') {
+                Some((key, value)) => (key, Some(Tag::unescape_value(value))),
+                None => (tag, None),
+            };
+            // Every tag needs a key
+            if key.is_empty() {
+                continue;
+            }
+            // An empty value is the same as no value at all
+            let tag = Tag {
+                key: key.to_string(),
+                value: value.filter(|v| !v.is_empty()),
+            };
+
+            // When a key is repeated, the last value wins
+            match parsed.iter_mut().find(|existing| existing.key == tag.key) {
+                Some(existing) => *existing = tag,
+                None => parsed.push(tag),
+            }
+        }
+
+        parsed
+    }
 }
 
-#[derive(Debug)]
+impl fmt::Display for Tag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.key)?;
+
+        if let Some(value) = &self.value {
+            write!(f, "={}", Tag::escape_value(value))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug)]
 pub struct Prefix {
     entity: String,
     user: Option<String>,
@@ -24,6 +183,29 @@ impl PartialEq for Prefix {
 }
 
 impl Prefix {
+    pub fn entity(&self) -> &str {
+        &self.entity
+    }
+
+    fn parse(message: &str) -> Self {
+        let (entity, rest) = Prefix::split_on_string("!", message);
+        if let Some(rest) = rest {
+            let (user, host) = Prefix::split_on_string("@", &rest);
+            Prefix {
+                entity,
+                user: Some(user),
+                host,
+            }
+        } else {
+            let (entity, host) = Prefix::split_on_string("@", &entity);
+            Prefix {
+                entity,
+                user: None,
+                host,
+            }
+        }
+    }
+
     fn split_on_string(s: &str, message: &str) -> (String, Option<String>) {
         let at = message.find(s);
         (
@@ -53,27 +235,13 @@ impl FromStr for Prefix {
     type Err = Infallible;
 
     fn from_str(message: &str) -> Result<Self, Self::Err> {
-        let (entity, rest) = Prefix::split_on_string("!", message);
-        if let Some(rest) = rest {
-            let (user, host) = Prefix::split_on_string("@", &rest);
-            Ok(Prefix {
-                entity,
-                user: Some(user),
-                host,
-            })
-        } else {
-            let (entity, host) = Prefix::split_on_string("@", &entity);
-            Ok(Prefix {
-                entity,
-                user: None,
-                host,
-            })
-        }
+        Ok(Prefix::parse(message))
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Message {
+    tags: Vec<Tag>,
     prefix: Option<Prefix>,
     // TODO(jsvana): Maybe make this an enum?
     command: String,
@@ -81,6 +249,43 @@ pub struct Message {
 }
 
 impl Message {
+    pub fn new(prefix: Option<Prefix>, command: &str, params: Vec<String>) -> Self {
+        Message {
+            tags: Vec::new(),
+            prefix,
+            command: command.to_string(),
+            params,
+        }
+    }
+
+    pub fn tags(&self) -> &[Tag] {
+        &self.tags
+    }
+
+    pub fn tag(&self, key: &str) -> Option<&Tag> {
+        self.tags.iter().find(|tag| tag.key == key)
+    }
+
+    /// Adds a tag, replacing any existing tag with the same key.
+    pub fn set_tag(&mut self, key: &str, value: Option<&str>) {
+        let tag = Tag {
+            key: key.to_string(),
+            value: value.map(|v| v.to_string()),
+        };
+        match self.tags.iter_mut().find(|existing| existing.key == key) {
+            Some(existing) => *existing = tag,
+            None => self.tags.push(tag),
+        }
+    }
+
+    pub fn retain_tags<F: FnMut(&Tag) -> bool>(&mut self, f: F) {
+        self.tags.retain(f);
+    }
+
+    pub fn prefix(&self) -> Option<&Prefix> {
+        self.prefix.as_ref()
+    }
+
     pub fn command(&self) -> &str {
         &self.command
     }
@@ -88,16 +293,61 @@ impl Message {
     pub fn params(&self) -> &Vec<String> {
         &self.params
     }
+
+    pub fn params_mut(&mut self) -> &mut Vec<String> {
+        &mut self.params
+    }
+
+    /// Checks that the message can be sent: that its command is valid, and
+    /// that it has no more than 15 parameters, none holding a line break
+    /// or NUL, and only the last holding spaces or starting with a colon.
+    pub fn validate(&self) -> Result<(), InvalidMessageError> {
+        parse_command(&self.command)?;
+
+        if self.params.len() > MAX_PARAMS {
+            return Err(InvalidMessageError::TooManyParams(self.params.len()));
+        }
+        for (i, param) in self.params.iter().enumerate() {
+            let is_last = i == self.params.len() - 1;
+            if param.contains(['\0', '\r', '\n']) || (!is_last && needs_colon(param)) {
+                return Err(InvalidMessageError::InvalidParam(param.clone()));
+            }
+        }
+
+        Ok(())
+    }
 }
 
 impl PartialEq for Message {
     fn eq(&self, other: &Self) -> bool {
-        self.prefix == other.prefix && self.command == other.command && self.params == other.params
+        self.tags == other.tags
+            && self.prefix == other.prefix
+            && self.command == other.command
+            && self.params == other.params
     }
 }
 
+/// Returns whether `param` can only be sent as the last parameter, after
+/// a colon.
+fn needs_colon(param: &str) -> bool {
+    param.is_empty() || param.starts_with(':') || param.contains(' ')
+}
+
+/// Messages with invalid parameters, which `validate` catches, can't be
+/// written faithfully.
 impl fmt::Display for Message {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.tags.is_empty() {
+            write!(f, "@")?;
+            for (i, tag) in self.tags.iter().enumerate() {
+                if i > 0 {
+                    write!(f, ";")?;
+                }
+                write!(f, "{}", tag)?;
+            }
+            write!(f, " ")?;
+        }
+
         if let Some(prefix) = &self.prefix {
             write!(f, ":{} ", prefix)?;
         }
@@ -106,7 +356,7 @@ impl fmt::Display for Message {
 
         for (i, param) in self.params.iter().enumerate() {
             write!(f, " ")?;
-            if i == self.params.len() - 1 {
+            if i == self.params.len() - 1 && needs_colon(param) {
                 write!(f, ":")?;
             }
             write!(f, "{}", param)?;
@@ -116,80 +366,98 @@ impl fmt::Display for Message {
     }
 }
 
-impl FromStr for Message {
-    type Err = InvalidMessageError;
+/// Splits the first word from `text`, along with the rest of the text
+/// after the spaces that follow it.
+fn split_word(text: &str) -> (&str, &str) {
+    match text.split_once(' ') {
+        Some((word, rest)) => (word, rest.trim_start_matches(' ')),
+        None => (text, ""),
+    }
+}
 
-    fn from_str(message: &str) -> Result<Self, Self::Err> {
-        if message.len() == 0 {
+/// A message that borrows its parts from the line it was parsed from, so
+/// that parsing copies nothing. Tags are left escaped, and the prefix
+/// unsplit, until it's turned into an owned `Message`.
+#[derive(Debug, PartialEq)]
+pub struct MessageRef<'a> {
+    /// The tags, without their leading `@`.
+    tags: Option<&'a str>,
+    prefix: Option<&'a str>,
+    command: &'a str,
+    params: Vec<&'a str>,
+}
+
+impl<'a> MessageRef<'a> {
+    pub fn parse(line: &'a str) -> Result<Self, InvalidMessageError> {
+        if line.trim_matches(' ').is_empty() {
             return Err(InvalidMessageError::Empty);
         }
 
-        let mut space = match message.find(" ") {
-            Some(idx) => idx,
-            None => {
-                return Ok(Message {
-                    prefix: None,
-                    command: message.to_string(),
-                    params: Vec::new(),
-                })
+        let mut rest = line;
+        let tags = match rest.strip_prefix('@') {
+            Some(tagged) => {
+                let (tags, after) = split_word(tagged);
+                rest = after;
+                Some(tags)
             }
+            None => None,
         };
 
-        let mut message_iter = message;
-        let prefix = match &message[..1] {
-            ":" => {
-                let old_space = space;
-                message_iter = &message[space + 1..];
-                space = message_iter.find(" ").unwrap_or(message_iter.len());
-                Some(message[1..old_space].parse().unwrap())
+        let prefix = match rest.strip_prefix(':') {
+            Some(prefixed) => {
+                let (prefix, after) = split_word(prefixed);
+                if prefix.is_empty() {
+                    return Err(InvalidMessageError::EmptyPrefix);
+                }
+                rest = after;
+                Some(prefix)
             }
-            _ => None,
+            None => None,
         };
 
-        let command = message_iter[..space].to_string();
-
-        if space == message_iter.len() {
-            return Ok(Message {
-                prefix,
-                command,
-                params: Vec::new(),
-            });
-        }
-
-        message_iter = &message_iter[space + 1..];
+        let (command, mut rest) = split_word(rest);
+        let command = parse_command(command)?;
 
         let mut params = Vec::new();
-
-        while message_iter.len() > 0 {
-            if &message_iter[..1] == ":" {
-                params.push(message_iter[1..].to_string());
+        while !rest.is_empty() {
+            // The last parameter may omit its colon when it's the 15th
+            if let Some(trailing) = rest.strip_prefix(':') {
+                params.push(trailing);
+                break;
+            } else if params.len() == MAX_PARAMS - 1 {
+                params.push(rest);
                 break;
             }
 
-            message_iter = match &message_iter[..1] {
-                ":" => {
-                    params.push(message_iter[1..].to_string());
-                    ""
-                }
-                _ => match message_iter.find(" ") {
-                    Some(idx) => {
-                        params.push(message_iter[..idx].to_string());
-                        &message_iter[idx + 1..]
-                    }
-                    None => {
-                        params.push(message_iter.to_string());
-                        ""
-                    }
-                },
-            }
+            let (param, after) = split_word(rest);
+            params.push(param);
+            rest = after;
         }
 
-        Ok(Message {
+        Ok(MessageRef {
+            tags,
             prefix,
-            command: command.to_string(),
-            params: params,
+            command,
+            params,
         })
     }
+
+    pub fn to_message(&self) -> Message {
+        Message {
+            tags: self.tags.map(Tag::parse_all).unwrap_or_default(),
+            prefix: self.prefix.map(Prefix::parse),
+            command: self.command.to_string(),
+            params: self.params.iter().map(|param| param.to_string()).collect(),
+        }
+    }
+}
+
+impl FromStr for Message {
+    type Err = InvalidMessageError;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        Ok(MessageRef::parse(line)?.to_message())
+    }
 }
 
 #[cfg(test)]
@@ -197,6 +465,7 @@ mod tests {
     use super::*;
 
     use anyhow::Result;
+    use proptest::prelude::*;
 
     #[test]
     fn test_parse_prefix_only_entity() -> Result<()> {
@@ -259,6 +528,7 @@ mod tests {
         assert_eq!(
             Message::from_str(":jay@localhost FAKE")?,
             Message {
+                tags: Vec::new(),
                 prefix: Some(Prefix {
                     entity: "jay".to_string(),
                     user: None,
@@ -277,6 +547,7 @@ mod tests {
         assert_eq!(
             Message::from_str("FAKE")?,
             Message {
+                tags: Vec::new(),
                 prefix: None,
                 command: "FAKE".to_string(),
                 params: Vec::new(),
@@ -291,6 +562,7 @@ mod tests {
         assert_eq!(
             Message::from_str(":irc-west.hs.gy NOTICE * :*** Looking up your hostname...")?,
             Message {
+                tags: Vec::new(),
                 prefix: Some(Prefix {
                     entity: "irc-west.hs.gy".to_string(),
                     user: None,
@@ -312,6 +584,7 @@ mod tests {
         assert_eq!(
             Message::from_str(":jay!jsvana PRIVMSG belak :test message")?,
             Message {
+                tags: Vec::new(),
                 prefix: Some(Prefix {
                     entity: "jay".to_string(),
                     user: Some("jsvana".to_string()),
@@ -330,6 +603,7 @@ mod tests {
         assert_eq!(
             Message::from_str("PING :1234")?,
             Message {
+                tags: Vec::new(),
                 prefix: None,
                 command: "PING".to_string(),
                 params: vec!["1234".to_string()],
@@ -405,6 +679,7 @@ mod tests {
             format!(
                 "{}",
                 Message {
+                    tags: Vec::new(),
                     prefix: Some(Prefix {
                         entity: "jay".to_string(),
                         user: None,
@@ -424,6 +699,7 @@ mod tests {
             format!(
                 "{}",
                 Message {
+                    tags: Vec::new(),
                     prefix: None,
                     command: "FAKE".to_string(),
                     params: Vec::new(),
@@ -439,6 +715,7 @@ mod tests {
             format!(
                 "{}",
                 Message {
+                    tags: Vec::new(),
                     prefix: Some(Prefix {
                         entity: "irc-west.hs.gy".to_string(),
                         user: None,
@@ -461,6 +738,7 @@ mod tests {
             format!(
                 "{}",
                 Message {
+                    tags: Vec::new(),
                     prefix: Some(Prefix {
                         entity: "jay".to_string(),
                         user: Some("jsvana".to_string()),
@@ -480,12 +758,277 @@ mod tests {
             format!(
                 "{}",
                 Message {
+                    tags: Vec::new(),
                     prefix: None,
                     command: "PING".to_string(),
                     params: vec!["1234".to_string()],
                 }
             ),
-            "PING :1234".to_string(),
+            "PING 1234".to_string(),
+        )
+    }
+
+    fn tag(key: &str, value: Option<&str>) -> Tag {
+        Tag {
+            key: key.to_string(),
+            value: value.map(|v| v.to_string()),
+        }
+    }
+
+    #[test]
+    fn test_parse_message_tags() -> Result<()> {
+        assert_eq!(
+            Message::from_str(
+                "@time=2020-04-01T12:00:00.000Z;+example.com/flag;msgid=abc :jay PRIVMSG #test :hi"
+            )?,
+            Message {
+                tags: vec![
+                    tag("time", Some("2020-04-01T12:00:00.000Z")),
+                    tag("+example.com/flag", None),
+                    tag("msgid", Some("abc")),
+                ],
+                prefix: Some(Prefix {
+                    entity: "jay".to_string(),
+                    user: None,
+                    host: None
+                }),
+                command: "PRIVMSG".to_string(),
+                params: vec!["#test".to_string(), "hi".to_string()],
+            },
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_message_tag_escapes() -> Result<()> {
+        assert_eq!(
+            Message::from_str(r"@a=one\:two\sthree\\four\r\n;b=\x\;c=;c PING")?.tags,
+            vec![
+                tag("a", Some("one;two three\\four\r\n")),
+                // Unknown escapes lose their backslash, as does a trailing one
+                tag("b", Some("x")),
+                // Repeated keys keep the last value, and empty values are no
+                // value at all
+                tag("c", None),
+            ],
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_message_tags_without_command() {
+        assert!(Message::from_str("@a=b").is_err());
+        assert!(Message::from_str("@a=b ").is_err());
+    }
+
+    #[test]
+    fn test_parse_invalid_messages() {
+        let error = |line: &str| Message::from_str(line).unwrap_err();
+
+        assert_eq!(error(""), InvalidMessageError::Empty);
+        assert_eq!(error(":jay"), InvalidMessageError::MissingCommand);
+        assert_eq!(error(":jay "), InvalidMessageError::MissingCommand);
+        assert_eq!(error(": PING"), InvalidMessageError::EmptyPrefix);
+        assert_eq!(
+            error("PRIV\x01MSG #test"),
+            InvalidMessageError::InvalidCommand("PRIV\x01MSG".to_string())
+        );
+        assert_eq!(
+            error(":jay 1234 #test"),
+            InvalidMessageError::InvalidCommand("1234".to_string())
+        );
+    }
+
+    #[test]
+    fn test_check_line_length() {
+        let tags = format!("@a={}", "x".repeat(MAX_TAGS_LENGTH - 4));
+        let message = format!("PRIVMSG #test :{}", "x".repeat(MAX_MESSAGE_LENGTH - 17));
+
+        assert!(check_line_length(format!("{} {}", tags, message).as_bytes()).is_ok());
+        assert_eq!(
+            check_line_length(format!("{}x {}", tags, message).as_bytes()),
+            Err(InvalidMessageError::TagsTooLong(MAX_TAGS_LENGTH + 1))
+        );
+        assert_eq!(
+            check_line_length(format!("{} {}x", tags, message).as_bytes()),
+            Err(InvalidMessageError::TooLong(MAX_MESSAGE_LENGTH + 1))
+        );
+    }
+
+    #[test]
+    fn test_message_tags_round_trip() -> Result<()> {
+        let line =
+            r"@a=one\:two\sthree\\four\r\n;+b;c=d :jay!jsvana@localhost PRIVMSG #test :hi there";
+        assert_eq!(Message::from_str(line)?.to_string(), line);
+
+        let mut message = Message::from_str("PRIVMSG #test :hi")?;
+        message.tags = vec![tag("+typing", Some("active")), tag("label", Some("a;b"))];
+        assert_eq!(
+            message.to_string(),
+            r"@+typing=active;label=a\:b PRIVMSG #test hi"
+        );
+        assert_eq!(Message::from_str(&message.to_string())?, message);
+
+        message.retain_tags(|tag| tag.key != "label");
+        assert_eq!(message.to_string(), "@+typing=active PRIVMSG #test hi");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_message_repeated_spaces() -> Result<()> {
+        assert_eq!(
+            Message::from_str("@a=b  :jay!j@h   PRIVMSG  #test   :hi  there ")?,
+            Message {
+                tags: vec![tag("a", Some("b"))],
+                prefix: Some(Prefix::parse("jay!j@h")),
+                command: "PRIVMSG".to_string(),
+                params: vec!["#test".to_string(), "hi  there ".to_string()],
+            },
+        );
+        assert_eq!(
+            Message::from_str("MODE #test +o  jay  ")?.params(),
+            &vec!["#test".to_string(), "+o".to_string(), "jay".to_string()],
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_message_fifteen_params() -> Result<()> {
+        let message = Message::from_str("005 a b c d e f g h i j k l m n o p :q")?;
+        assert_eq!(message.params().len(), MAX_PARAMS);
+        assert_eq!(message.params()[MAX_PARAMS - 1], "o p :q");
+        assert_eq!(Message::from_str(&message.to_string())?, message);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_message_to_string_colons() {
+        let to_string = |params: &[&str]| {
+            Message::new(
+                None,
+                "PRIVMSG",
+                params.iter().map(|p| p.to_string()).collect(),
+            )
+            .to_string()
+        };
+
+        assert_eq!(to_string(&["#test", "hi"]), "PRIVMSG #test hi");
+        assert_eq!(to_string(&["#test", "hi there"]), "PRIVMSG #test :hi there");
+        assert_eq!(to_string(&["#test", ":)"]), "PRIVMSG #test ::)");
+        assert_eq!(to_string(&["#test", ""]), "PRIVMSG #test :");
+    }
+
+    #[test]
+    fn test_validate() {
+        let validate = |command: &str, params: &[&str]| {
+            Message::new(
+                None,
+                command,
+                params.iter().map(|p| p.to_string()).collect(),
+            )
+            .validate()
+        };
+
+        assert_eq!(validate("PRIVMSG", &["#test", "hi there"]), Ok(()));
+        assert_eq!(
+            validate("PRIVMSG", &["#a b", "hi"]),
+            Err(InvalidMessageError::InvalidParam("#a b".to_string()))
+        );
+        assert_eq!(
+            validate("PRIVMSG", &[":a", "hi"]),
+            Err(InvalidMessageError::InvalidParam(":a".to_string()))
+        );
+        assert_eq!(
+            validate("PRIVMSG", &["#test", "hi\r\nQUIT"]),
+            Err(InvalidMessageError::InvalidParam("hi\r\nQUIT".to_string()))
+        );
+        assert_eq!(
+            validate("PRIVMSG", &["a"; 16]),
+            Err(InvalidMessageError::TooManyParams(16))
+        );
+        assert_eq!(
+            validate("PRIV MSG", &[]),
+            Err(InvalidMessageError::InvalidCommand("PRIV MSG".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_parse_borrowed() -> Result<()> {
+        let line = "@time=12:00;a=b\\sc :jay!j@host PRIVMSG  #rust ::hi there";
+        let message = MessageRef::parse(line)?;
+
+        assert_eq!(
+            message,
+            MessageRef {
+                tags: Some("time=12:00;a=b\\sc"),
+                prefix: Some("jay!j@host"),
+                command: "PRIVMSG",
+                params: vec!["#rust", ":hi there"],
+            }
+        );
+        assert_eq!(message.to_message(), Message::from_str(line)?);
+
+        Ok(())
+    }
+
+    fn message_strategy() -> impl Strategy<Value = Message> {
+        let tags = prop::collection::btree_map(
+            "\\+?[a-z][a-z0-9./-]{0,10}",
+            prop::option::of("[^\\x00]{1,10}"),
+            0..4,
+        );
+        let prefix = prop::option::of((
+            "[a-zA-Z0-9._\\[\\]-]{1,10}",
+            prop::option::of("~?[a-z]{1,8}"),
+            prop::option::of("[a-z0-9.:]{1,10}"),
+        ));
+        let command = "[A-Za-z]{1,10}|[0-9]{3}";
+        let middles = prop::collection::vec("[^ :\\x00\r\n][^ \\x00\r\n]{0,8}", 0..MAX_PARAMS);
+        let trailing = prop::option::of("[^\\x00\r\n]{0,20}");
+
+        (tags, prefix, command, middles, trailing).prop_map(
+            |(tags, prefix, command, mut params, trailing)| {
+                if params.len() < MAX_PARAMS {
+                    params.extend(trailing);
+                }
+                Message {
+                    tags: tags
+                        .into_iter()
+                        .map(|(key, value)| Tag { key, value })
+                        .collect(),
+                    prefix: prefix.map(|(entity, user, host)| Prefix { entity, user, host }),
+                    command,
+                    params,
+                }
+            },
         )
     }
+
+    proptest! {
+        #[test]
+        fn test_message_round_trip(message in message_strategy()) {
+            prop_assert_eq!(message.validate(), Ok(()));
+            prop_assert_eq!(Message::from_str(&message.to_string()), Ok(message));
+        }
+
+        #[test]
+        fn test_parse_anything(line in "\\PC*") {
+            // Whatever parses writes back out as the same message
+            if let Ok(message) = Message::from_str(&line) {
+                prop_assert_eq!(Message::from_str(&message.to_string()), Ok(message));
+            }
+        }
+
+        #[test]
+        fn test_parse_colons_and_spaces(line in "[@:; a-z=]{0,30}") {
+            if let Ok(message) = Message::from_str(&line) {
+                prop_assert_eq!(Message::from_str(&message.to_string()), Ok(message));
+            }
+        }
+    }
 }
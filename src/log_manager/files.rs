@@ -0,0 +1,992 @@
+//! Stores logs as plain text files.
+//!
+//! Structure:
+//!   <username>/
+//!     <server:hostport>/
+//!       <YYYY-MM-DD>.log
+//!       <channel or nick>/
+//!         <YYYY-MM-DD>.log[.gz|.zst]
+//!         <YYYY-MM-DD>.imported.log[.gz|.zst]
+//!         [...]
+//!       .markers/
+//!         <client>
+//!
+//! A new file is started for each day in the configured timezone, and
+//! files from previous days may be compressed and eventually deleted; see
+//! the `rotation` module.
+//!
+//! Each line of a log is a `Record`; see the `record` module for its
+//! format.
+//!
+//! Each day's log has an index of where each hour starts in it, so that
+//! history from a given time on can be found without reading the whole
+//! log; see the `index` module.
+//!
+//! Each marker file records, for every uncompressed log file under the
+//! server, the byte offset the client had seen up to when it last
+//! detached, along with the day it detached. Logs from before that day
+//! that aren't listed had already been compressed, and so were complete
+//! and seen in full.
+//!
+//! Imported history goes in a day's `.imported.log` rather than its log,
+//! which the bouncer may be appending to and which markers hold offsets
+//! into. Imported logs are read by queries and searches but never
+//! replayed, so markers don't list them.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{format_err, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDate, TimeDelta, Utc};
+use log::{info, warn};
+use tokio::fs::{
+    create_dir_all, metadata, read_dir, read_to_string, remove_file, rename, write, File,
+    OpenOptions,
+};
+use tokio::io::AsyncWriteExt;
+
+use super::index::{self, Index};
+use super::record::Record;
+use super::rotation::{self, Compressed, ExpiryCandidate, LogFile, PendingCompression};
+use super::{IrcLog, Search};
+use crate::config::{Log, LogCompression};
+
+const MARKERS_DIR_STR: &str = ".markers";
+
+/// Marker line recording the day a client detached.
+const MARKER_DAY_STR: &str = "day";
+
+pub struct FileLog {
+    config: Log,
+    /// Today's logs that have been written to, keyed by path.
+    open_logs: BTreeMap<PathBuf, OpenLog>,
+    /// The day the open logs are for.
+    today: Option<NaiveDate>,
+}
+
+/// A log being appended to, along with its index.
+struct OpenLog {
+    file: File,
+    index_file: File,
+    index: Index,
+    length: u64,
+}
+
+impl OpenLog {
+    /// Opens the log at `path` for appending, rebuilding its index if it's
+    /// missing or out of date.
+    async fn open(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(path)
+            .await?;
+        let length = file.metadata().await?.len();
+
+        let index_path = index::path_for(path);
+        let index = match read_to_string(&index_path)
+            .await
+            .ok()
+            .and_then(|contents| contents.parse::<Index>().ok())
+        {
+            Some(index) if index.last_offset() <= length => index,
+            _ => {
+                let index = Index::build(&read_to_string(path).await?);
+                write(&index_path, index.to_string()).await?;
+                index
+            }
+        };
+
+        let index_file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&index_path)
+            .await?;
+
+        Ok(OpenLog {
+            file,
+            index_file,
+            index,
+            length,
+        })
+    }
+}
+
+/// What a client had seen when it last detached.
+struct Marker {
+    /// Missing from markers written before logs were split by day.
+    day: Option<NaiveDate>,
+    /// Offsets into each log, keyed by its uncompressed path.
+    offsets: BTreeMap<PathBuf, u64>,
+}
+
+impl FileLog {
+    pub async fn new(config: &Log) -> Result<Self> {
+        create_dir_all(&config.base_path).await?;
+
+        Ok(FileLog {
+            config: config.clone(),
+            open_logs: BTreeMap::new(),
+            today: None,
+        })
+    }
+
+    fn today(&self) -> NaiveDate {
+        Utc::now().with_timezone(&self.config.timezone).date_naive()
+    }
+
+    /// Returns today's date, closing the previous day's logs if it has
+    /// just ended.
+    fn roll_over(&mut self) -> NaiveDate {
+        let today = self.today();
+        if self.today != Some(today) {
+            self.open_logs.clear();
+            self.today = Some(today);
+        }
+        today
+    }
+
+    fn path_from_params(&self, user: &str, server: &str, channel: Option<&str>) -> PathBuf {
+        let mut path: PathBuf = [
+            &self.config.base_path,
+            &PathBuf::from(user),
+            &PathBuf::from(server),
+        ]
+        .iter()
+        .collect();
+
+        if let Some(channel) = channel {
+            path.push(directory_name(channel));
+        }
+
+        path
+    }
+
+    async fn append_to(&mut self, dir_path: PathBuf, record: &Record) -> Result<()> {
+        let today = self.roll_over();
+        let file_path = dir_path.join(rotation::name_for_date(today));
+
+        if !self.open_logs.contains_key(&file_path) {
+            create_dir_all(&dir_path).await?;
+            self.open_logs
+                .insert(file_path.clone(), OpenLog::open(&file_path).await?);
+        }
+        let log = self.open_logs.get_mut(&file_path).unwrap();
+
+        // The index is written first so that it can only ever point past
+        // the end of the log, never miss part of it.
+        if let Some(entry) = log.index.add(&record.time, log.length) {
+            log.index_file.write_all(entry.as_bytes()).await?;
+            log.index_file.flush().await?;
+        }
+
+        let line = format!("{}\r\n", record);
+        log.file.write_all(line.as_bytes()).await?;
+        log.length += line.len() as u64;
+
+        // Replay reads through a separate handle, so make sure the
+        // message is visible there before anyone is told about it.
+        log.file.flush().await?;
+
+        Ok(())
+    }
+
+    fn marker_path(&self, user: &str, server: &str, client: &str) -> PathBuf {
+        let mut path = self.path_from_params(user, server, None);
+        path.push(MARKERS_DIR_STR);
+        path.push(client);
+        path
+    }
+
+    /// Lists every log file under `dir`, relative to `dir`, with each
+    /// directory's logs in the order they were written.
+    async fn log_files(dir: &Path) -> Result<Vec<(PathBuf, LogFile)>> {
+        let mut log_files = Vec::new();
+        let mut pending = vec![PathBuf::new()];
+
+        while let Some(relative_dir) = pending.pop() {
+            let mut entries = match read_dir(dir.join(&relative_dir)).await {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+
+            while let Some(entry) = entries.next_entry().await? {
+                let name = entry.file_name();
+                if name == MARKERS_DIR_STR {
+                    continue;
+                }
+
+                if entry.file_type().await?.is_dir() {
+                    pending.push(relative_dir.join(name));
+                } else if let Some(log_file) = name.to_str().and_then(LogFile::parse) {
+                    log_files.push((relative_dir.join(name), log_file));
+                }
+            }
+        }
+
+        // Legacy logs have no date, and so come first
+        log_files.sort_by(|(a, a_file), (b, b_file)| {
+            (a.parent(), a_file.date).cmp(&(b.parent(), b_file.date))
+        });
+
+        Ok(log_files)
+    }
+
+    async fn read_log(path: PathBuf, offset: u64) -> Result<String> {
+        tokio::task::spawn_blocking(move || rotation::read(&path, offset)).await?
+    }
+
+    /// Looks for the record with `msgid` in `log_files` under `dir_path`,
+    /// newest first since messages are mostly looked up soon after they
+    /// were sent.
+    async fn find_in(
+        dir_path: &Path,
+        mut log_files: Vec<(PathBuf, LogFile)>,
+        msgid: &str,
+    ) -> Result<Option<Record>> {
+        // Legacy logs have no date, and so come last
+        log_files.sort_by_key(|(_, log_file)| std::cmp::Reverse(log_file.date));
+
+        for (log_file, _) in log_files {
+            let contents = Self::read_log(dir_path.join(&log_file), 0).await?;
+
+            // Checking the msgid column first avoids parsing every line
+            let line = contents
+                .lines()
+                .find(|line| line.split(' ').nth(1) == Some(msgid));
+            if let Some(line) = line {
+                return Ok(Some(Record::from_str(line)?));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Returns the offset in the log at `path` to read from to find every
+    /// message from `since` on, or `None` if there are none. Logs without
+    /// a readable index have to be read in full, until the index is
+    /// rebuilt when the log is next opened.
+    async fn offset_since(path: &Path, since: &DateTime<Utc>) -> Result<Option<u64>> {
+        match read_to_string(index::path_for(path)).await {
+            Ok(contents) => match contents.parse::<Index>() {
+                Ok(index) => Ok(index.offset_since(since)),
+                Err(e) => {
+                    warn!("reading all of {:?}, its index is unreadable: {}", path, e);
+                    Ok(Some(0))
+                }
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Some(0)),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Reads the records in the log at `path` from `offset` on.
+    async fn read_records(path: PathBuf, offset: u64) -> Result<Vec<Record>> {
+        let contents = Self::read_log(path.clone(), offset).await?;
+
+        let mut records = Vec::new();
+        for line in contents.lines() {
+            match Record::from_str(line) {
+                Ok(record) => records.push(record),
+                Err(e) => warn!("skipping unparseable line in {:?}: {}", path, e),
+            }
+        }
+
+        Ok(records)
+    }
+
+    async fn read_marker(&self, path: &Path) -> Result<Option<Marker>> {
+        let contents = match read_to_string(path).await {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut marker = Marker {
+            day: None,
+            offsets: BTreeMap::new(),
+        };
+        for line in contents.lines() {
+            let mut parts = line.splitn(2, '\t');
+            let offset = parts.next().unwrap_or_default();
+            let value = parts
+                .next()
+                .ok_or_else(|| format_err!("Malformed marker line \"{}\" in {:?}", line, path))?;
+
+            if offset == MARKER_DAY_STR {
+                marker.day = Some(value.parse()?);
+            } else {
+                marker.offsets.insert(PathBuf::from(value), offset.parse()?);
+            }
+        }
+
+        Ok(Some(marker))
+    }
+}
+
+#[async_trait]
+impl IrcLog for FileLog {
+    async fn append(
+        &mut self,
+        user: &str,
+        server: &str,
+        targets: &[String],
+        record: &Record,
+    ) -> Result<()> {
+        if targets.is_empty() {
+            self.append_to(self.path_from_params(user, server, None), record)
+                .await?;
+        }
+        for target in targets {
+            self.append_to(self.path_from_params(user, server, Some(target)), record)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn import(
+        &mut self,
+        user: &str,
+        server: &str,
+        target: &str,
+        records: Vec<Record>,
+    ) -> Result<usize> {
+        let dir_path = self.path_from_params(user, server, Some(target));
+        create_dir_all(&dir_path).await?;
+        let existing = Self::log_files(&dir_path).await?;
+
+        let mut by_date: BTreeMap<NaiveDate, Vec<Record>> = BTreeMap::new();
+        for record in records {
+            let date = record
+                .time
+                .with_timezone(&self.config.timezone)
+                .date_naive();
+            by_date.entry(date).or_default().push(record);
+        }
+
+        let mut imported = 0;
+        for (date, records) in by_date {
+            // Only the day's imported history is rewritten. The bouncer's
+            // own log may be open for appending, and clients' markers hold
+            // offsets into it
+            let mut seen: BTreeSet<(DateTime<Utc>, String)> = BTreeSet::new();
+            let mut merged = Vec::new();
+            let mut existing_path = None;
+            for (path, log_file) in &existing {
+                if log_file.date != Some(date) || path.parent() != Some(Path::new("")) {
+                    continue;
+                }
+
+                let path = dir_path.join(path);
+                let records = Self::read_records(path.clone(), 0).await?;
+                seen.extend(
+                    records
+                        .iter()
+                        .map(|record| (record.time, record.message.to_string())),
+                );
+                if log_file.imported {
+                    merged.extend(records);
+                    existing_path = Some(path);
+                }
+            }
+
+            let mut added = 0;
+            for record in records {
+                if seen.insert((record.time, record.message.to_string())) {
+                    merged.push(record);
+                    added += 1;
+                }
+            }
+            if added == 0 {
+                continue;
+            }
+            imported += added;
+            merged.sort_by_key(|record| record.time);
+
+            let contents: String = merged
+                .iter()
+                .map(|record| format!("{}\r\n", record))
+                .collect();
+            let path = dir_path.join(rotation::imported_name_for_date(date));
+            write_atomically(
+                &index::path_for(&path),
+                &Index::build(&contents).to_string(),
+            )
+            .await?;
+            write_atomically(&path, &contents).await?;
+
+            // The day is compressed again by the next maintenance
+            if let Some(existing_path) = existing_path.filter(|existing| *existing != path) {
+                remove_file(existing_path).await?;
+            }
+        }
+
+        Ok(imported)
+    }
+
+    async fn query(
+        &self,
+        user: &str,
+        server: &str,
+        target: Option<&str>,
+        start: Option<&DateTime<Utc>>,
+        end: Option<&DateTime<Utc>>,
+    ) -> Result<Vec<Record>> {
+        let dir_path = self.path_from_params(user, server, target);
+
+        let mut records = Vec::new();
+        for (log_file, _) in Self::log_files(&dir_path).await? {
+            let path = dir_path.join(&log_file);
+            let offset = match start {
+                Some(start) => match Self::offset_since(&path, start).await? {
+                    Some(offset) => offset,
+                    None => continue,
+                },
+                None => 0,
+            };
+
+            records.extend(Self::read_records(path, offset).await?);
+        }
+
+        records.retain(|record| {
+            start.is_none_or(|start| record.time >= *start)
+                && end.is_none_or(|end| record.time <= *end)
+        });
+
+        // Stable, so messages within a file keep their order
+        records.sort_by_key(|record| record.time);
+
+        Ok(records)
+    }
+
+    async fn query_latest(
+        &self,
+        user: &str,
+        server: &str,
+        target: Option<&str>,
+        end: Option<&DateTime<Utc>>,
+        limit: usize,
+    ) -> Result<Vec<Record>> {
+        let dir_path = self.path_from_params(user, server, target);
+
+        // Messages are logged on the day they're received, which can be a
+        // little after the time the server gave them
+        let last_day = end
+            .map(|end| end.with_timezone(&self.config.timezone).date_naive() + TimeDelta::days(1));
+
+        let mut log_files = Self::log_files(&dir_path).await?;
+        log_files.sort_by_key(|(_, log_file)| std::cmp::Reverse(log_file.date));
+
+        // Read newest first, finishing each day since the logs of different
+        // targets from the same day interleave
+        let mut logs = Vec::new();
+        let mut found = 0;
+        let mut day = None;
+        for (log_file, description) in log_files {
+            if description
+                .date
+                .is_some_and(|date| last_day.is_some_and(|last| date > last))
+            {
+                continue;
+            }
+            if description.date != day && found >= limit {
+                break;
+            }
+            day = description.date;
+
+            let mut records = Self::read_records(dir_path.join(&log_file), 0).await?;
+            records.retain(|record| end.is_none_or(|end| record.time <= *end));
+            found += records.len();
+            logs.push(records);
+        }
+
+        // Stable, so messages within a file keep their order
+        let mut records: Vec<Record> = logs.into_iter().rev().flatten().collect();
+        records.sort_by_key(|record| record.time);
+        let first = records.len().saturating_sub(limit);
+
+        Ok(records.split_off(first))
+    }
+
+    async fn find(
+        &self,
+        user: &str,
+        server: &str,
+        target: Option<&str>,
+        msgid: &str,
+    ) -> Result<Option<Record>> {
+        // Messages are mostly looked up in the conversation they were sent
+        // to, so its logs are read before everyone else's
+        let target_path = target.map(|target| self.path_from_params(user, server, Some(target)));
+        if let Some(target_path) = &target_path {
+            let log_files = Self::log_files(target_path).await?;
+            if let Some(record) = Self::find_in(target_path, log_files, msgid).await? {
+                return Ok(Some(record));
+            }
+        }
+
+        let dir_path = self.path_from_params(user, server, None);
+        let log_files = Self::log_files(&dir_path)
+            .await?
+            .into_iter()
+            .filter(|(log_file, _)| {
+                target_path
+                    .as_ref()
+                    .is_none_or(|target_path| !dir_path.join(log_file).starts_with(target_path))
+            })
+            .collect();
+
+        Self::find_in(&dir_path, log_files, msgid).await
+    }
+
+    async fn search(&self, user: &str, server: &str, search: &Search) -> Result<Vec<Record>> {
+        let mut records = self
+            .query(
+                user,
+                server,
+                search.target.as_deref(),
+                search.start.as_ref(),
+                search.end.as_ref(),
+            )
+            .await?;
+
+        records.retain(|record| search.matches(record));
+        let first = records.len().saturating_sub(search.limit);
+
+        Ok(records.split_off(first))
+    }
+
+    async fn targets(&self, user: &str, server: &str) -> Result<Vec<String>> {
+        let mut entries = match read_dir(self.path_from_params(user, server, None)).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut targets = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name();
+            if name == MARKERS_DIR_STR || !entry.file_type().await?.is_dir() {
+                continue;
+            }
+
+            if let Some(name) = name.to_str() {
+                targets.push(target_name(name));
+            }
+        }
+        targets.sort();
+
+        Ok(targets)
+    }
+
+    async fn since_detach(&self, user: &str, server: &str, client: &str) -> Result<Vec<Record>> {
+        let marker = match self
+            .read_marker(&self.marker_path(user, server, client))
+            .await?
+        {
+            Some(marker) => marker,
+            None => return Ok(Vec::new()),
+        };
+
+        let dir_path = self.path_from_params(user, server, None);
+
+        let mut records = Vec::new();
+        for (log_file, description) in Self::log_files(&dir_path).await? {
+            if description.imported {
+                continue;
+            }
+
+            let offset = match marker.offsets.get(&rotation::uncompressed_path(&log_file)) {
+                Some(offset) => *offset,
+                None if description.date < marker.day => continue,
+                None => 0,
+            };
+
+            records.extend(Self::read_records(dir_path.join(&log_file), offset).await?);
+        }
+
+        Ok(records)
+    }
+
+    async fn mark_detached(&self, user: &str, server: &str, client: &str) -> Result<()> {
+        let dir_path = self.path_from_params(user, server, None);
+
+        let mut contents = format!("{}\t{}\n", MARKER_DAY_STR, self.today());
+        for (log_file, description) in Self::log_files(&dir_path).await? {
+            if description.compression != LogCompression::None || description.imported {
+                continue;
+            }
+
+            let length = metadata(dir_path.join(&log_file)).await?.len();
+            contents.push_str(&format!("{}\t{}\n", length, log_file.display()));
+        }
+
+        let marker_path = self.marker_path(user, server, client);
+        create_dir_all(marker_path.parent().unwrap()).await?;
+        write(&marker_path, contents).await?;
+
+        Ok(())
+    }
+
+    async fn pending_compressions(&mut self) -> Result<Vec<PendingCompression>> {
+        let today = self.roll_over();
+        let compression = self.config.compression;
+        if compression == LogCompression::None {
+            return Ok(Vec::new());
+        }
+
+        let base_path = self.config.base_path.clone();
+        Ok(Self::log_files(&base_path)
+            .await?
+            .into_iter()
+            .filter(|(_, description)| {
+                description.date.is_some_and(|date| date < today)
+                    && description.compression == LogCompression::None
+            })
+            .map(|(log_file, _)| PendingCompression {
+                path: base_path.join(log_file),
+                compression,
+            })
+            .collect())
+    }
+
+    async fn maintain(&mut self, compressed: Vec<Compressed>) -> Result<()> {
+        let today = self.roll_over();
+        let base_path = self.config.base_path.clone();
+
+        for log in compressed {
+            if let Some(path) = tokio::task::spawn_blocking(move || log.replace()).await?? {
+                info!("compressed {:?}", path);
+            }
+        }
+
+        let mut candidates = Vec::new();
+        for (log_file, description) in Self::log_files(&base_path).await? {
+            let date = match description.date {
+                Some(date) => date,
+                None => continue,
+            };
+
+            let path = base_path.join(&log_file);
+            candidates.push(ExpiryCandidate {
+                size: metadata(&path).await?.len(),
+                path,
+                date,
+            });
+        }
+
+        for path in rotation::expired(
+            candidates,
+            today,
+            self.config.retention_days,
+            self.config.retention_bytes,
+        ) {
+            info!("deleting expired log {:?}", path);
+            remove_file(&path).await?;
+            match remove_file(index::path_for(&path)).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn rebuild_indexes(&mut self) -> Result<usize> {
+        self.open_logs.clear();
+        let base_path = self.config.base_path.clone();
+
+        let mut reindexed = 0;
+        for (log_file, description) in Self::log_files(&base_path).await? {
+            if description.date.is_none() {
+                continue;
+            }
+
+            let path = base_path.join(&log_file);
+            let contents = Self::read_log(path.clone(), 0).await?;
+            write(index::path_for(&path), Index::build(&contents).to_string()).await?;
+            reindexed += contents.lines().filter(|line| !line.is_empty()).count();
+        }
+
+        Ok(reindexed)
+    }
+}
+
+/// Writes `contents` to `path` by way of a temporary file, so that nothing
+/// reading it ever sees it half written.
+async fn write_atomically(path: &Path, contents: &str) -> Result<()> {
+    let mut temporary_path = path.as_os_str().to_os_string();
+    temporary_path.push(".tmp");
+    write(&temporary_path, contents).await?;
+    rename(&temporary_path, path).await?;
+
+    Ok(())
+}
+
+/// Escapes the characters of a channel or nick that can't appear in a
+/// directory name, along with a leading dot so that no target can be
+/// mistaken for the markers directory.
+fn directory_name(target: &str) -> String {
+    let mut name = String::new();
+    for (i, c) in target.chars().enumerate() {
+        match c {
+            '/' | '\\' | '%' | '\0' => name.push_str(&format!("%{:02X}", c as u32)),
+            '.' if i == 0 => name.push_str("%2E"),
+            _ => name.push(c),
+        }
+    }
+    name
+}
+
+/// Reverses `directory_name`.
+fn target_name(directory: &str) -> String {
+    let mut name = String::new();
+    let mut rest = directory;
+    while let Some(percent) = rest.find('%') {
+        name.push_str(&rest[..percent]);
+        let escaped = rest
+            .get(percent + 1..percent + 3)
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                name.push(char::from(byte));
+                rest = &rest[percent + 3..];
+            }
+            None => {
+                name.push('%');
+                rest = &rest[percent + 1..];
+            }
+        }
+    }
+    name.push_str(rest);
+    name
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use anyhow::format_err;
+
+    use crate::cap::{format_server_time, parse_server_time};
+    use crate::config::LogBackend;
+    use crate::irc::Message;
+
+    fn record(time: &str, msgid: &str, line: &str) -> Result<Record> {
+        Ok(Record {
+            time: parse_server_time(time).ok_or_else(|| format_err!("bad time"))?,
+            msgid: Some(msgid.to_string()),
+            message: Message::from_str(line)?,
+        })
+    }
+
+    async fn file_log(name: &str) -> Result<FileLog> {
+        let base_path =
+            std::env::temp_dir().join(format!("bounce-files-{}-{}", name, std::process::id()));
+        FileLog::new(&Log {
+            base_path,
+            backend: LogBackend::Files,
+            timezone: Default::default(),
+            compression: Default::default(),
+            retention_days: None,
+            retention_bytes: None,
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_find() -> Result<()> {
+        let mut log = file_log("find").await?;
+        log.import(
+            "jay",
+            "net",
+            "#rust",
+            vec![
+                record("2026-10-17T08:00:00Z", "a", ":belak PRIVMSG #rust :one")?,
+                record("2026-10-18T08:00:00Z", "b", ":belak PRIVMSG #rust :two")?,
+            ],
+        )
+        .await?;
+        log.import(
+            "jay",
+            "net",
+            "belak",
+            vec![record(
+                "2026-10-18T09:00:00Z",
+                "c",
+                ":belak PRIVMSG jay :three",
+            )?],
+        )
+        .await?;
+
+        let find = |target, msgid| log.find("jay", "net", target, msgid);
+        let time = |record: Option<Record>| record.map(|record| record.time);
+        assert_eq!(
+            time(find(Some("#rust"), "a").await?),
+            parse_server_time("2026-10-17T08:00:00Z")
+        );
+        assert_eq!(
+            time(find(None, "b").await?),
+            parse_server_time("2026-10-18T08:00:00Z")
+        );
+        // Found elsewhere once the target's logs don't have it
+        assert_eq!(
+            time(find(Some("#rust"), "c").await?),
+            parse_server_time("2026-10-18T09:00:00Z")
+        );
+        assert_eq!(find(Some("#go"), "d").await?, None);
+
+        tokio::fs::remove_dir_all(&log.config.base_path).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_import_not_replayed() -> Result<()> {
+        let mut log = file_log("import").await?;
+        let targets = vec!["#rust".to_string()];
+        let msgids = |records: Vec<Record>| -> Vec<String> {
+            records
+                .into_iter()
+                .filter_map(|record| record.msgid)
+                .collect()
+        };
+
+        // Logged a millisecond apart, the way the server stamps them
+        let now = Utc::now();
+        let at = |milliseconds| format_server_time(&(now + TimeDelta::milliseconds(milliseconds)));
+
+        let seen = record(&at(0), "a", ":belak PRIVMSG #rust :seen")?;
+        log.append("jay", "net", &targets, &seen).await?;
+        log.mark_detached("jay", "net", "phone").await?;
+
+        // History from earlier today and before, and a message that was
+        // already logged
+        let imported = vec![
+            record("2026-10-17T08:00:00Z", "c", ":belak PRIVMSG #rust :old")?,
+            record(&at(-1), "b", ":belak PRIVMSG #rust :early")?,
+            seen,
+        ];
+        assert_eq!(
+            log.import("jay", "net", "#rust", imported.clone()).await?,
+            2
+        );
+        assert_eq!(log.import("jay", "net", "#rust", imported).await?, 0);
+
+        let unseen = record(&at(1), "d", ":belak PRIVMSG #rust :unseen")?;
+        log.append("jay", "net", &targets, &unseen).await?;
+
+        assert_eq!(
+            msgids(log.since_detach("jay", "net", "phone").await?),
+            vec!["d"]
+        );
+        assert_eq!(
+            msgids(log.query("jay", "net", Some("#rust"), None, None).await?),
+            vec!["c", "b", "a", "d"]
+        );
+
+        tokio::fs::remove_dir_all(&log.config.base_path).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_query_latest() -> Result<()> {
+        let mut log = file_log("latest").await?;
+        log.import(
+            "jay",
+            "net",
+            "#rust",
+            vec![
+                record("2026-10-16T08:00:00Z", "a", ":belak PRIVMSG #rust :one")?,
+                record("2026-10-17T08:00:00Z", "b", ":belak PRIVMSG #rust :two")?,
+                record("2026-10-17T09:00:00Z", "c", ":belak PRIVMSG #rust :three")?,
+                record("2026-10-18T08:00:00Z", "d", ":belak PRIVMSG #rust :four")?,
+            ],
+        )
+        .await?;
+
+        let msgids = |records: Vec<Record>| -> Vec<String> {
+            records
+                .into_iter()
+                .filter_map(|record| record.msgid)
+                .collect()
+        };
+        let end = parse_server_time("2026-10-17T08:00:00Z");
+        assert_eq!(
+            msgids(
+                log.query_latest("jay", "net", Some("#rust"), None, 2)
+                    .await?
+            ),
+            vec!["c", "d"]
+        );
+        assert_eq!(
+            msgids(
+                log.query_latest("jay", "net", Some("#rust"), end.as_ref(), 3)
+                    .await?
+            ),
+            vec!["a", "b"]
+        );
+        assert_eq!(
+            msgids(log.query_latest("jay", "net", None, None, 10).await?),
+            vec!["a", "b", "c", "d"]
+        );
+
+        tokio::fs::remove_dir_all(&log.config.base_path).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_query_unreadable_index() -> Result<()> {
+        let mut log = file_log("unreadable").await?;
+        log.import(
+            "jay",
+            "net",
+            "#rust",
+            vec![
+                record("2026-10-17T08:00:00Z", "a", ":belak PRIVMSG #rust :one")?,
+                record("2026-10-17T09:00:00Z", "b", ":belak PRIVMSG #rust :two")?,
+            ],
+        )
+        .await?;
+
+        let date = NaiveDate::from_ymd_opt(2026, 10, 17).ok_or_else(|| format_err!("bad date"))?;
+        let path = log
+            .path_from_params("jay", "net", Some("#rust"))
+            .join(rotation::imported_name_for_date(date));
+        write(index::path_for(&path), "not an index\n").await?;
+
+        let start = parse_server_time("2026-10-17T08:30:00Z");
+        let records = log
+            .query("jay", "net", Some("#rust"), start.as_ref(), None)
+            .await?;
+        assert_eq!(
+            records
+                .into_iter()
+                .filter_map(|record| record.msgid)
+                .collect::<Vec<_>>(),
+            vec!["b"]
+        );
+
+        tokio::fs::remove_dir_all(&log.config.base_path).await?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_directory_name() {
+        assert_eq!(directory_name("#rust"), "#rust");
+        assert_eq!(directory_name("#a/b\\c%d"), "#a%2Fb%5Cc%25d");
+        assert_eq!(directory_name(".markers"), "%2Emarkers");
+        assert_eq!(directory_name(".."), "%2E.");
+
+        for target in &["#rust", "#a/b\\c%d", ".markers", "..", "#50%"] {
+            assert_eq!(target_name(&directory_name(target)), *target);
+        }
+    }
+}
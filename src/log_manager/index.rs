@@ -0,0 +1,176 @@
+//! Sidecar indexes that find where each hour starts in a log.
+//!
+//! Alongside each day's log `<name>` is an index `<name>.idx` with a line
+//!
+//!   <hour> <offset>
+//!
+//! for every hour messages were logged in, giving the byte offset of the
+//! first message from that hour or later. Messages are mostly logged in
+//! order, but may carry an earlier time from the server, so every message
+//! from a given time on is found by reading from the first hour at or
+//! after it rather than the hour containing it.
+//!
+//! Indexes are appended to along with their logs, and can be rebuilt
+//! from the log itself with `bounce logs reindex`.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{format_err, Result};
+use chrono::{DateTime, DurationRound, TimeDelta, Utc};
+
+use super::record::Record;
+use super::rotation;
+use crate::cap::{format_server_time, parse_server_time};
+
+const INDEX_EXTENSION: &str = ".idx";
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Index {
+    /// Hours with the offset they start at, both increasing.
+    entries: Vec<(DateTime<Utc>, u64)>,
+}
+
+/// Returns the path of the index for the log at `log`, which is the same
+/// whether or not the log has been compressed.
+pub fn path_for(log: &Path) -> PathBuf {
+    let mut path = rotation::uncompressed_path(log).into_os_string();
+    path.push(INDEX_EXTENSION);
+    PathBuf::from(path)
+}
+
+fn hour(time: &DateTime<Utc>) -> DateTime<Utc> {
+    time.duration_trunc(TimeDelta::hours(1)).unwrap_or(*time)
+}
+
+fn format_entry(hour: &DateTime<Utc>, offset: u64) -> String {
+    format!("{} {}\n", format_server_time(hour), offset)
+}
+
+impl Index {
+    /// Indexes the contents of a log.
+    pub fn build(log: &str) -> Self {
+        let mut index = Index::default();
+
+        let mut offset = 0;
+        for line in log.split_inclusive('\n') {
+            if let Ok(record) = Record::from_str(line.trim_end()) {
+                index.add(&record.time, offset);
+            }
+            offset += line.len() as u64;
+        }
+
+        index
+    }
+
+    /// Notes that a message from `time` is being logged at `offset`,
+    /// returning the line to append to the index file if it starts a new
+    /// hour.
+    pub fn add(&mut self, time: &DateTime<Utc>, offset: u64) -> Option<String> {
+        let hour = hour(time);
+        if self.entries.last().is_some_and(|(last, _)| hour <= *last) {
+            return None;
+        }
+
+        self.entries.push((hour, offset));
+        Some(format_entry(&hour, offset))
+    }
+
+    /// The offset of the last hour indexed, which can't be past the end of
+    /// an index that's up to date with its log.
+    pub fn last_offset(&self) -> u64 {
+        self.entries.last().map_or(0, |(_, offset)| *offset)
+    }
+
+    /// Returns the offset to read from to find every message from `time`
+    /// on, or `None` if there are none.
+    pub fn offset_since(&self, time: &DateTime<Utc>) -> Option<u64> {
+        let hour = hour(time);
+        self.entries
+            .iter()
+            .find(|(start, _)| *start >= hour)
+            .map(|(_, offset)| *offset)
+    }
+}
+
+impl fmt::Display for Index {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (hour, offset) in &self.entries {
+            write!(f, "{}", format_entry(hour, *offset))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for Index {
+    type Err = anyhow::Error;
+
+    fn from_str(contents: &str) -> Result<Self> {
+        let mut index = Index::default();
+
+        for line in contents.lines() {
+            let malformed = || format_err!("Malformed index line \"{}\"", line);
+            let (hour, offset) = line.split_once(' ').ok_or_else(malformed)?;
+            let hour = parse_server_time(hour).ok_or_else(malformed)?;
+            index.add(&hour, offset.parse()?);
+        }
+
+        Ok(index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use anyhow::Result;
+
+    fn time(time: &str) -> DateTime<Utc> {
+        parse_server_time(time).unwrap()
+    }
+
+    #[test]
+    fn test_build() -> Result<()> {
+        let log = [
+            "2026-10-18T08:10:00.000Z a :jay!j@host PRIVMSG #rust :one\r\n",
+            "2026-10-18T08:50:00.000Z b :jay!j@host PRIVMSG #rust :two\r\n",
+            "2026-10-18T10:05:00.000Z c :jay!j@host PRIVMSG #rust :three\r\n",
+            // Sent with an earlier time by the server
+            "2026-10-18T09:59:00.000Z d :jay!j@host PRIVMSG #rust :four\r\n",
+        ]
+        .concat();
+
+        let index = Index::build(&log);
+        assert_eq!(
+            index.to_string(),
+            "2026-10-18T08:00:00.000Z 0\n2026-10-18T10:00:00.000Z 118\n"
+        );
+        assert_eq!(Index::from_str(&index.to_string())?, index);
+
+        assert_eq!(index.offset_since(&time("2026-10-18T07:00:00Z")), Some(0));
+        assert_eq!(index.offset_since(&time("2026-10-18T08:30:00Z")), Some(0));
+        assert_eq!(index.offset_since(&time("2026-10-18T09:59:00Z")), Some(118));
+        assert_eq!(index.offset_since(&time("2026-10-18T11:00:00Z")), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_add() {
+        let mut index = Index::default();
+
+        assert_eq!(
+            index.add(&time("2026-10-18T08:10:00Z"), 0),
+            Some("2026-10-18T08:00:00.000Z 0\n".to_string())
+        );
+        assert_eq!(index.add(&time("2026-10-18T08:20:00Z"), 60), None);
+        assert_eq!(index.add(&time("2026-10-18T07:20:00Z"), 120), None);
+        assert_eq!(
+            index.add(&time("2026-10-18T09:00:00Z"), 180),
+            Some("2026-10-18T09:00:00.000Z 180\n".to_string())
+        );
+        assert_eq!(index.last_offset(), 180);
+    }
+}
@@ -1,104 +1,328 @@
 //! Manages IRC logs.
 //!
-//! Structure:
-//!   <username>/
-//!     <server:hostport>/
-//!       <channel>/
-//!         log
-
-// TODO(jsvana): Maybe store hourly offsets in an index
-// file to make replay easier?
+//! Messages in a channel or query are logged under the channel or the
+//! other user's nick, folded with the network's casemapping so that
+//! differently cased names share a log. Everything else, such as the
+//! MOTD and most numerics, is logged directly under the server.
+//!
+//! Logs are kept by an `IrcLog` backend chosen in the `[log]` config:
+//! daily text files (see the `files` module) or a SQLite database (see
+//! the `sqlite` module). Either way, each message is stored as a `Record`
+//! of when it was received, its msgid and the message itself; see the
+//! `record` module.
+//!
+//! Logged messages can be searched for by their text, sender, target and
+//! time; see the `search` module.
 
-use std::collections::BTreeMap;
-use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::Result;
-use tokio::fs::{create_dir_all, File, OpenOptions};
-use tokio::io::AsyncWriteExt;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures::lock::Mutex;
+use log::warn;
+use tokio::time::delay_for;
 
-use super::config::Config;
-use super::irc::Message;
+use super::config::{Config, LogBackend};
+use super::irc::{Message, Tag};
 
-const LOGFILE_STR: &str = "LOG";
+mod files;
+mod index;
+mod record;
+mod rotation;
+mod search;
+mod sqlite;
 
-pub struct LogManager {
-    base_path: PathBuf,
-    file_handles: BTreeMap<PathBuf, File>,
-}
+use files::FileLog;
+use record::Record;
+use rotation::{Compressed, PendingCompression};
+pub use search::Search;
+use sqlite::SqliteLog;
 
-/*
-trait IrcLog {
-    async fn add_message(
-        user: &User,
-        server: &Server,
-        channel: &Channel,
-        message: &Message,
+/// How often logs from previous days are compressed and expired.
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Commands whose messages are replayed to reattaching clients.
+const REPLAYED_COMMANDS: &[&str] = &["PRIVMSG", "NOTICE"];
+
+/// Storage for the logs of every user's networks.
+///
+/// `target` is a folded channel or nick, and `None` where it's optional
+/// stands for everything logged for the server.
+#[async_trait]
+pub trait IrcLog: Send + Sync {
+    /// Appends `record` to the log of each of `targets`, or to the
+    /// server's log if there are none.
+    async fn append(
+        &mut self,
+        user: &str,
+        server: &str,
+        targets: &[String],
+        record: &Record,
     ) -> Result<()>;
 
-    async fn replay_messages_from_time(
-        user: &User,
-        server: &Server,
-        channel: &Channel,
-        start_time: &Instant,
-    ) -> Result<Vec<Message>>;
+    /// Adds history from before the bouncer logged `server` to the log of
+    /// `target`, skipping records that were already logged. Imported
+    /// records are never replayed. Returns how many were added.
+    async fn import(
+        &mut self,
+        user: &str,
+        server: &str,
+        target: &str,
+        records: Vec<Record>,
+    ) -> Result<usize>;
+
+    /// Returns the records logged for `target` from `start` to `end`
+    /// inclusive, ordered by when they were received.
+    async fn query(
+        &self,
+        user: &str,
+        server: &str,
+        target: Option<&str>,
+        start: Option<&DateTime<Utc>>,
+        end: Option<&DateTime<Utc>>,
+    ) -> Result<Vec<Record>>;
+
+    /// Returns the `limit` most recent records logged for `target` up to
+    /// `end` inclusive, ordered by when they were received. Only as much
+    /// of the logs is read as needed to find them.
+    async fn query_latest(
+        &self,
+        user: &str,
+        server: &str,
+        target: Option<&str>,
+        end: Option<&DateTime<Utc>>,
+        limit: usize,
+    ) -> Result<Vec<Record>>;
+
+    /// Returns the record logged for `server` with `msgid`, if any. The
+    /// message is most likely logged for `target`, if one is given.
+    async fn find(
+        &self,
+        user: &str,
+        server: &str,
+        target: Option<&str>,
+        msgid: &str,
+    ) -> Result<Option<Record>>;
+
+    /// Returns the most recent records logged for `server` matching
+    /// `search`, ordered by when they were received.
+    async fn search(&self, user: &str, server: &str, search: &Search) -> Result<Vec<Record>>;
+
+    /// Lists the channels and nicks with logs for `server`.
+    async fn targets(&self, user: &str, server: &str) -> Result<Vec<String>>;
+
+    /// Returns the records logged since `client` last detached from
+    /// `server`, grouped by target. A client that has never detached
+    /// before has nothing to replay.
+    async fn since_detach(&self, user: &str, server: &str, client: &str) -> Result<Vec<Record>>;
+
+    /// Records that `client` has seen everything currently logged for
+    /// `server`.
+    async fn mark_detached(&self, user: &str, server: &str, client: &str) -> Result<()>;
+
+    /// Lists the logs that have become old enough to compress, which is
+    /// done without holding up logging.
+    async fn pending_compressions(&mut self) -> Result<Vec<PendingCompression>>;
+
+    /// Puts `compressed` logs in place of the originals and deletes any
+    /// beyond the retention limits.
+    async fn maintain(&mut self, compressed: Vec<Compressed>) -> Result<()>;
+
+    /// Rebuilds whatever indexes the backend keeps from the logs
+    /// themselves, returning how many messages were reindexed.
+    async fn rebuild_indexes(&mut self) -> Result<usize>;
+}
+
+pub struct LogManager {
+    backend: Box<dyn IrcLog>,
 }
-*/
 
 impl LogManager {
     pub async fn new(config: &Config) -> Result<Self> {
-        create_dir_all(&config.log.base_path).await?;
+        let backend: Box<dyn IrcLog> = match config.log.backend {
+            LogBackend::Files => Box::new(FileLog::new(&config.log).await?),
+            LogBackend::Sqlite => Box::new(SqliteLog::new(&config.log).await?),
+        };
 
-        Ok(Self {
-            base_path: config.log.base_path.clone(),
-            file_handles: BTreeMap::new(),
-        })
+        Ok(Self { backend })
     }
 
-    fn path_from_params(&self, user: &str, server: &str, channel: Option<&str>) -> PathBuf {
-        let mut path: PathBuf = vec![
-            &self.base_path,
-            &PathBuf::from(user),
-            &PathBuf::from(server),
-        ].iter().collect();
+    /// Appends `message` to the log of each of `targets`, or to the
+    /// server's log if there are none, along with when it was received
+    /// and a msgid that later requests for history can refer to it by.
+    /// Both are added to `message` as tags, so that clients receiving it
+    /// now see the same as clients fetching it from the log later.
+    pub async fn add_message(
+        &mut self,
+        user: &str,
+        server: &str,
+        targets: &[String],
+        message: &mut Message,
+    ) -> Result<()> {
+        let record = Record::new(message.clone());
+        let connection_tags: Vec<Tag> = message
+            .tags()
+            .iter()
+            .filter(|tag| record::CONNECTION_TAGS.contains(&tag.key()))
+            .cloned()
+            .collect();
 
-        if let Some(channel) = channel {
-            path.push(channel);
+        // Clients are sent the message as logged, along with the tags that
+        // only mean something on this connection
+        *message = record.to_message();
+        for tag in connection_tags {
+            message.set_tag(tag.key(), tag.value());
         }
 
-        path
+        self.backend.append(user, server, targets, &record).await
     }
 
-    pub async fn add_message(
+    /// Adds `messages`, which must have `time` tags, to the log of
+    /// `target` as history from before the bouncer logged `server`.
+    /// Returns how many weren't already logged.
+    pub async fn import_messages(
         &mut self,
         user: &str,
         server: &str,
-        channel: Option<&str>,
-        message: &Message,
-    ) -> Result<()> {
-        let dir_path = self.path_from_params(user, server, channel);
+        target: &str,
+        messages: Vec<Message>,
+    ) -> Result<usize> {
+        let records = messages.into_iter().map(Record::new).collect();
+        self.backend.import(user, server, target, records).await
+    }
 
-        let file_path: PathBuf = vec![&dir_path, &PathBuf::from(LOGFILE_STR)]
+    /// Returns the messages logged since `client` last detached from
+    /// `server`, grouped by channel or query.
+    pub async fn replay_messages_since_detach(
+        &self,
+        user: &str,
+        server: &str,
+        client: &str,
+    ) -> Result<Vec<Message>> {
+        Ok(self
+            .backend
+            .since_detach(user, server, client)
+            .await?
             .iter()
-            .collect();
+            .filter(|record| REPLAYED_COMMANDS.contains(&record.message.command()))
+            .map(Record::to_message)
+            .collect())
+    }
+
+    /// Returns the messages logged for `server`, ordered by when they were
+    /// received. Only those with `target` are returned if it's given, and
+    /// only those from `start` to `end`.
+    pub async fn messages(
+        &self,
+        user: &str,
+        server: &str,
+        target: Option<&str>,
+        start: Option<&DateTime<Utc>>,
+        end: Option<&DateTime<Utc>>,
+    ) -> Result<Vec<Message>> {
+        Ok(self
+            .backend
+            .query(user, server, target, start, end)
+            .await?
+            .iter()
+            .map(Record::to_message)
+            .collect())
+    }
+
+    /// Returns the `limit` most recent messages logged for `server` up to
+    /// `end`, ordered by when they were received.
+    pub async fn latest_messages(
+        &self,
+        user: &str,
+        server: &str,
+        target: Option<&str>,
+        end: Option<&DateTime<Utc>>,
+        limit: usize,
+    ) -> Result<Vec<Message>> {
+        Ok(self
+            .backend
+            .query_latest(user, server, target, end, limit)
+            .await?
+            .iter()
+            .map(Record::to_message)
+            .collect())
+    }
+
+    /// Returns when the message logged for `server` with `msgid` was
+    /// received, if there is one, looking in the logs of `target` first.
+    pub async fn message_time(
+        &self,
+        user: &str,
+        server: &str,
+        target: Option<&str>,
+        msgid: &str,
+    ) -> Result<Option<DateTime<Utc>>> {
+        Ok(self
+            .backend
+            .find(user, server, target, msgid)
+            .await?
+            .map(|record| record.time))
+    }
+
+    /// Returns the most recent messages logged for `server` matching
+    /// `search`, ordered by when they were received.
+    pub async fn search(&self, user: &str, server: &str, search: &Search) -> Result<Vec<Message>> {
+        Ok(self
+            .backend
+            .search(user, server, search)
+            .await?
+            .iter()
+            .map(Record::to_message)
+            .collect())
+    }
+
+    /// Lists the channels and nicks with logs for `server`.
+    pub async fn targets(&self, user: &str, server: &str) -> Result<Vec<String>> {
+        self.backend.targets(user, server).await
+    }
+
+    /// Records that `client` has seen everything currently logged for `server`.
+    pub async fn mark_detached(&self, user: &str, server: &str, client: &str) -> Result<()> {
+        self.backend.mark_detached(user, server, client).await
+    }
 
-        if !self.file_handles.contains_key(&file_path) {
-            create_dir_all(&dir_path).await?;
+    pub async fn rebuild_indexes(&mut self) -> Result<usize> {
+        self.backend.rebuild_indexes().await
+    }
+}
 
-            let file = OpenOptions::new()
-                .append(true)
-                .create(true)
-                .open(&file_path)
-                .await?;
+/// Compresses and expires old logs. Compressing can take a while, so it's
+/// done without holding the lock, which is only taken to find the logs to
+/// compress and then to swap them in.
+async fn maintain(log_manager: &Mutex<LogManager>) -> Result<()> {
+    let pending = log_manager
+        .lock()
+        .await
+        .backend
+        .pending_compressions()
+        .await?;
 
-            self.file_handles.insert(file_path.clone(), file);
+    let mut compressed = Vec::new();
+    for pending in pending {
+        let path = pending.path.clone();
+        match tokio::task::spawn_blocking(move || pending.compress()).await? {
+            Ok(log) => compressed.push(log),
+            Err(e) => warn!("unable to compress {:?}: {}", path, e),
         }
+    }
+
+    log_manager.lock().await.backend.maintain(compressed).await
+}
 
-        self.file_handles
-            .get_mut(&file_path)
-            .unwrap()
-            .write(format!("{}\r\n", message).as_bytes())
-            .await?;
+/// Compresses and expires old logs as days end.
+pub async fn maintenance_worker(log_manager: Arc<Mutex<LogManager>>) {
+    loop {
+        if let Err(e) = maintain(&log_manager).await {
+            warn!("unable to compress or expire logs: {}", e);
+        }
 
-        Ok(())
+        delay_for(MAINTENANCE_INTERVAL).await;
     }
 }
@@ -0,0 +1,249 @@
+//! The format of a single line in a log file.
+//!
+//! Each line is `<time> <msgid> <message>`, for example
+//!
+//!   2026-10-18T08:30:06.620Z L0HSohJ4CKZYMME0 :jay!j@host PRIVMSG #rust :hi
+//!
+//! so that logs can be searched by date with plain `grep` and sorted with
+//! `sort`. `<time>` is when the message was received (or the server's
+//! `time` tag, if it sent one) in UTC with millisecond precision, and
+//! `<msgid>` is the server's `msgid` tag or one generated by the bouncer,
+//! which also replaces any from the server that couldn't be told apart
+//! from the other columns.
+//! Both are moved out of the message's tags while it's stored and put
+//! back when it's read. The `batch` and `label` tags only mean something
+//! on the connection the message arrived on, so aren't stored at all.
+//!
+//! Logs written before this format existed contain only the message; they
+//! are still read, taking the time and msgid from the message's tags.
+
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{format_err, Result};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use rand::{thread_rng, Rng};
+
+use crate::cap::{format_server_time, parse_server_time};
+use crate::irc::{Message, Tag};
+
+/// Stands in for the msgid of messages logged without one.
+const MISSING_MSGID: &str = "-";
+
+/// Tags that only mean something on the connection a message arrived on,
+/// and so aren't logged.
+pub const CONNECTION_TAGS: &[&str] = &["batch", "label"];
+
+/// Returns whether `tag` is kept with the logged message rather than in a
+/// column of its own or not at all.
+fn is_logged_tag(tag: &Tag) -> bool {
+    tag.key() != "time" && tag.key() != "msgid" && !CONNECTION_TAGS.contains(&tag.key())
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Record {
+    pub time: DateTime<Utc>,
+    pub msgid: Option<String>,
+    /// The message, without its `time`, `msgid`, `batch` and `label` tags.
+    pub message: Message,
+}
+
+impl Record {
+    /// Creates the record for a message just received, taking its time and
+    /// msgid from its tags if it has them.
+    pub fn new(mut message: Message) -> Self {
+        let time = message
+            .tag("time")
+            .and_then(|tag| tag.value())
+            .and_then(parse_server_time)
+            .unwrap_or_else(Utc::now);
+        let msgid = message
+            .tag("msgid")
+            .and_then(|tag| tag.value())
+            .filter(|msgid| is_loggable_msgid(msgid))
+            .map(|msgid| msgid.to_string())
+            .unwrap_or_else(new_msgid);
+
+        message.retain_tags(is_logged_tag);
+
+        Record {
+            time,
+            msgid: Some(msgid),
+            message,
+        }
+    }
+
+    /// Returns the message as it should be sent to clients, tagged with
+    /// when it was received and its msgid.
+    pub fn to_message(&self) -> Message {
+        let mut message = self.message.clone();
+        message.set_tag("time", Some(&format_server_time(&self.time)));
+        if let Some(msgid) = &self.msgid {
+            message.set_tag("msgid", Some(msgid));
+        }
+        message
+    }
+
+    fn from_legacy_line(line: &str) -> Result<Self> {
+        let mut message = Message::from_str(line)?;
+
+        // Messages logged without a time sort before everything else
+        let time = message
+            .tag("time")
+            .and_then(|tag| tag.value())
+            .and_then(parse_server_time)
+            .unwrap_or_default();
+        let msgid = message
+            .tag("msgid")
+            .and_then(|tag| tag.value())
+            .filter(|msgid| is_loggable_msgid(msgid))
+            .map(|msgid| msgid.to_string());
+
+        message.retain_tags(is_logged_tag);
+
+        Ok(Record {
+            time,
+            msgid,
+            message,
+        })
+    }
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {}",
+            format_server_time(&self.time),
+            self.msgid.as_deref().unwrap_or(MISSING_MSGID),
+            self.message
+        )
+    }
+}
+
+impl FromStr for Record {
+    type Err = anyhow::Error;
+
+    fn from_str(line: &str) -> Result<Self> {
+        let mut parts = line.splitn(3, ' ');
+        let time = match parts.next().and_then(parse_server_time) {
+            Some(time) => time,
+            None => return Self::from_legacy_line(line),
+        };
+        let msgid = parts
+            .next()
+            .ok_or_else(|| format_err!("Log line is missing a msgid"))?;
+        let message = parts
+            .next()
+            .ok_or_else(|| format_err!("Log line is missing a message"))?;
+
+        Ok(Record {
+            time,
+            msgid: match msgid {
+                MISSING_MSGID => None,
+                msgid => Some(msgid.to_string()),
+            },
+            message: Message::from_str(message)?,
+        })
+    }
+}
+
+/// Returns whether `msgid` can be written to its column as it is. Tag
+/// values can hold anything once unescaped, including spaces and line
+/// breaks.
+fn is_loggable_msgid(msgid: &str) -> bool {
+    !msgid.is_empty()
+        && msgid != MISSING_MSGID
+        && !msgid.chars().any(|c| c.is_whitespace() || c.is_control())
+}
+
+/// Generates an identifier for a message that didn't arrive with one.
+fn new_msgid() -> String {
+    let bytes: [u8; 12] = thread_rng().gen();
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use anyhow::Result;
+
+    #[test]
+    fn test_new_uses_server_tags() -> Result<()> {
+        let record = Record::new(Message::from_str(
+            "@time=2026-10-18T10:30:06.62+02:00;msgid=abc;batch=1;label=2;+typing=done :jay!j@host PRIVMSG #rust :hi",
+        )?);
+
+        assert_eq!(
+            record.to_string(),
+            "2026-10-18T08:30:06.620Z abc @+typing=done :jay!j@host PRIVMSG #rust hi"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_new_replaces_unloggable_msgids() -> Result<()> {
+        for msgid in &["a\\sb", "a\\nb", "-"] {
+            let record = Record::new(Message::from_str(&format!(
+                "@time=2026-10-18T08:30:06.620Z;msgid={} :jay!j@host PRIVMSG #rust :hi",
+                msgid
+            ))?);
+
+            assert_eq!(record.msgid.as_ref().map(|msgid| msgid.len()), Some(16));
+            assert_eq!(Record::from_str(&record.to_string())?, record);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_new_stamps_untagged_messages() -> Result<()> {
+        let before = Utc::now();
+        let record = Record::new(Message::from_str(":jay!j@host PRIVMSG #rust :hi")?);
+
+        assert!(record.time >= before && record.time <= Utc::now());
+        assert_eq!(record.msgid.as_ref().map(|msgid| msgid.len()), Some(16));
+        assert!(record.message.tags().is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_round_trip() -> Result<()> {
+        let line = "2026-10-18T08:30:06.620Z abc :jay!j@host PRIVMSG #rust :hi there";
+        let record = Record::from_str(line)?;
+
+        assert_eq!(record.to_string(), line);
+        assert_eq!(
+            record.to_message().to_string(),
+            "@time=2026-10-18T08:30:06.620Z;msgid=abc :jay!j@host PRIVMSG #rust :hi there"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_legacy_lines() -> Result<()> {
+        let record = Record::from_str(":jay!j@host PRIVMSG #rust :hi")?;
+        assert_eq!(record.time, DateTime::<Utc>::default());
+        assert_eq!(record.msgid, None);
+        assert_eq!(
+            record.to_string(),
+            "1970-01-01T00:00:00.000Z - :jay!j@host PRIVMSG #rust hi"
+        );
+
+        let record = Record::from_str(
+            "@time=2026-10-18T08:30:06.620Z;msgid=abc :jay!j@host PRIVMSG #rust :hi",
+        )?;
+        assert_eq!(
+            record.to_string(),
+            "2026-10-18T08:30:06.620Z abc :jay!j@host PRIVMSG #rust hi"
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,410 @@
+//! Names logs by day and compresses and expires old ones.
+//!
+//! A day's log is named `<YYYY-MM-DD>.log` for the day it was written in
+//! the configured timezone, and gains a `.gz` or `.zst` extension once
+//! compressed. History imported from other clients' logs is kept apart in
+//! `<YYYY-MM-DD>.imported.log`, which is compressed and expired the same
+//! way. Logs from before files were split by day are named `LOG` and are
+//! never compressed or expired.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use anyhow::{format_err, Result};
+use chrono::NaiveDate;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+
+use crate::config::LogCompression;
+
+pub const LEGACY_LOGFILE_STR: &str = "LOG";
+
+const DATE_FORMAT: &str = "%Y-%m-%d";
+const LOG_EXTENSION: &str = ".log";
+const IMPORTED_SUFFIX: &str = ".imported";
+const GZIP_EXTENSION: &str = ".gz";
+const ZSTD_EXTENSION: &str = ".zst";
+
+/// A log file, as described by its name.
+#[derive(Debug, PartialEq)]
+pub struct LogFile {
+    /// The day the file holds messages for, or `None` for a legacy log.
+    pub date: Option<NaiveDate>,
+    pub compression: LogCompression,
+    /// Whether the file holds imported history rather than what the
+    /// bouncer logged itself.
+    pub imported: bool,
+}
+
+impl LogFile {
+    /// Describes the file named `name`, or returns `None` if it isn't a log.
+    pub fn parse(name: &str) -> Option<Self> {
+        if name == LEGACY_LOGFILE_STR {
+            return Some(LogFile {
+                date: None,
+                compression: LogCompression::None,
+                imported: false,
+            });
+        }
+
+        let (name, compression) = if let Some(name) = name.strip_suffix(GZIP_EXTENSION) {
+            (name, LogCompression::Gzip)
+        } else if let Some(name) = name.strip_suffix(ZSTD_EXTENSION) {
+            (name, LogCompression::Zstd)
+        } else {
+            (name, LogCompression::None)
+        };
+
+        let name = name.strip_suffix(LOG_EXTENSION)?;
+        let (name, imported) = match name.strip_suffix(IMPORTED_SUFFIX) {
+            Some(name) => (name, true),
+            None => (name, false),
+        };
+        let date = NaiveDate::parse_from_str(name, DATE_FORMAT).ok()?;
+
+        Some(LogFile {
+            date: Some(date),
+            compression,
+            imported,
+        })
+    }
+
+    /// The name of the uncompressed log, which is how clients' markers
+    /// refer to it whether or not it has since been compressed.
+    pub fn uncompressed_name(&self) -> String {
+        match self.date {
+            Some(date) if self.imported => imported_name_for_date(date),
+            Some(date) => name_for_date(date),
+            None => LEGACY_LOGFILE_STR.to_string(),
+        }
+    }
+}
+
+pub fn name_for_date(date: NaiveDate) -> String {
+    format!("{}{}", date.format(DATE_FORMAT), LOG_EXTENSION)
+}
+
+pub fn imported_name_for_date(date: NaiveDate) -> String {
+    format!(
+        "{}{}{}",
+        date.format(DATE_FORMAT),
+        IMPORTED_SUFFIX,
+        LOG_EXTENSION
+    )
+}
+
+/// Returns `path` with its name replaced by the uncompressed name of the
+/// log, if it is one.
+pub fn uncompressed_path(path: &Path) -> PathBuf {
+    let log_file = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .and_then(LogFile::parse);
+
+    match log_file {
+        Some(log_file) => path.with_file_name(log_file.uncompressed_name()),
+        None => path.to_path_buf(),
+    }
+}
+
+/// Reads a log from `offset` on, decompressing it if necessary. Blocks,
+/// so should be run with `spawn_blocking`.
+pub fn read(path: &Path, offset: u64) -> Result<String> {
+    let compression = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .and_then(LogFile::parse)
+        .map_or(LogCompression::None, |log_file| log_file.compression);
+
+    let mut file = fs::File::open(path)?;
+    let mut contents = String::new();
+    match compression {
+        LogCompression::None => {
+            io::Seek::seek(&mut file, io::SeekFrom::Start(offset))?;
+            io::Read::read_to_string(&mut io::BufReader::new(file), &mut contents)?;
+            return Ok(contents);
+        }
+        LogCompression::Gzip => io::Read::read_to_string(&mut GzDecoder::new(file), &mut contents)?,
+        LogCompression::Zstd => {
+            io::Read::read_to_string(&mut zstd::Decoder::new(file)?, &mut contents)?
+        }
+    };
+
+    // Compressed logs can't be seeked in, so skip ahead once decompressed
+    Ok(contents
+        .get(offset as usize..)
+        .unwrap_or_default()
+        .to_string())
+}
+
+/// A log from a previous day that's waiting to be compressed.
+#[derive(Debug)]
+pub struct PendingCompression {
+    pub path: PathBuf,
+    pub compression: LogCompression,
+}
+
+/// A log compressed into a file next to it, which has yet to replace it.
+#[derive(Debug)]
+pub struct Compressed {
+    path: PathBuf,
+    compressed_path: PathBuf,
+    /// Where the compressed log was written, or `None` if an interrupted
+    /// earlier attempt had already finished it.
+    temporary_path: Option<PathBuf>,
+    /// How long the log was when it was compressed.
+    length: u64,
+}
+
+impl PendingCompression {
+    /// Compresses the log into a temporary file, leaving the log itself
+    /// to be read from in the meantime. Blocks, so should be run with
+    /// `spawn_blocking`.
+    pub fn compress(self) -> Result<Compressed> {
+        let extension = match self.compression {
+            LogCompression::None => return Err(format_err!("no compression for {:?}", self.path)),
+            LogCompression::Gzip => GZIP_EXTENSION,
+            LogCompression::Zstd => ZSTD_EXTENSION,
+        };
+
+        let mut compressed_name = self.path.file_name().unwrap_or_default().to_os_string();
+        compressed_name.push(extension);
+        let compressed_path = self.path.with_file_name(&compressed_name);
+
+        let input = fs::File::open(&self.path)?;
+        let length = input.metadata()?.len();
+
+        // The compressed log is only ever renamed into place once complete,
+        // so if it exists a previous attempt was interrupted before removing
+        // the original.
+        if compressed_path.exists() {
+            return Ok(Compressed {
+                path: self.path,
+                compressed_path,
+                temporary_path: None,
+                length,
+            });
+        }
+
+        let mut temporary_name = compressed_name;
+        temporary_name.push(".tmp");
+        let temporary_path = self.path.with_file_name(temporary_name);
+
+        let mut input = io::Read::take(input, length);
+        let output = fs::File::create(&temporary_path)?;
+        match self.compression {
+            LogCompression::None => {}
+            LogCompression::Gzip => {
+                let mut encoder = GzEncoder::new(output, flate2::Compression::default());
+                io::copy(&mut input, &mut encoder)?;
+                encoder.finish()?.sync_all()?;
+            }
+            LogCompression::Zstd => {
+                let mut encoder = zstd::Encoder::new(output, 0)?;
+                io::copy(&mut input, &mut encoder)?;
+                encoder.finish()?.sync_all()?;
+            }
+        }
+
+        Ok(Compressed {
+            path: self.path,
+            compressed_path,
+            temporary_path: Some(temporary_path),
+            length,
+        })
+    }
+}
+
+impl Compressed {
+    /// Replaces the log with its compressed copy and returns the copy's
+    /// path. If the log was written to while it was being compressed, the
+    /// copy is thrown away instead and `None` returned. Blocks, so should
+    /// be run with `spawn_blocking`.
+    pub fn replace(self) -> Result<Option<PathBuf>> {
+        // Logs only ever grow, whether appended to or rewritten by imports
+        if fs::metadata(&self.path)?.len() != self.length {
+            if let Some(temporary_path) = &self.temporary_path {
+                fs::remove_file(temporary_path)?;
+            }
+            return Ok(None);
+        }
+
+        if let Some(temporary_path) = &self.temporary_path {
+            fs::rename(temporary_path, &self.compressed_path)?;
+        }
+        fs::remove_file(&self.path)?;
+
+        Ok(Some(self.compressed_path))
+    }
+}
+
+/// A dated log file somewhere under the base path, considered for expiry.
+#[derive(Debug)]
+pub struct ExpiryCandidate {
+    pub path: PathBuf,
+    pub date: NaiveDate,
+    pub size: u64,
+}
+
+/// Chooses which logs to delete so that none are from more than
+/// `retention_days` days before `today` and together they take up no
+/// more than `retention_bytes`, deleting the oldest first. Logs from
+/// `today` are still being written to and are always kept.
+pub fn expired(
+    mut candidates: Vec<ExpiryCandidate>,
+    today: NaiveDate,
+    retention_days: Option<u32>,
+    retention_bytes: Option<u64>,
+) -> Vec<PathBuf> {
+    candidates.sort_by_key(|candidate| candidate.date);
+
+    let mut total: u64 = candidates.iter().map(|candidate| candidate.size).sum();
+    let mut expired = Vec::new();
+    for candidate in candidates {
+        if candidate.date >= today {
+            break;
+        }
+
+        let too_old = retention_days
+            .is_some_and(|days| (today - candidate.date).num_days() > i64::from(days));
+        let too_big = retention_bytes.is_some_and(|bytes| total > bytes);
+        if !too_old && !too_big {
+            break;
+        }
+
+        total -= candidate.size;
+        expired.push(candidate.path);
+    }
+
+    expired
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use anyhow::Result;
+
+    fn date(date: &str) -> NaiveDate {
+        NaiveDate::parse_from_str(date, DATE_FORMAT).unwrap()
+    }
+
+    #[test]
+    fn test_parse_names() {
+        assert_eq!(
+            LogFile::parse("2026-10-18.log.zst"),
+            Some(LogFile {
+                date: Some(date("2026-10-18")),
+                compression: LogCompression::Zstd,
+                imported: false,
+            }),
+        );
+        assert_eq!(
+            LogFile::parse("LOG"),
+            Some(LogFile {
+                date: None,
+                compression: LogCompression::None,
+                imported: false,
+            }),
+        );
+        assert_eq!(
+            LogFile::parse("2026-10-18.imported.log.gz"),
+            Some(LogFile {
+                date: Some(date("2026-10-18")),
+                compression: LogCompression::Gzip,
+                imported: true,
+            }),
+        );
+        assert_eq!(LogFile::parse("2026-10-18.log.gz.tmp"), None);
+        assert_eq!(LogFile::parse("notes.log"), None);
+        assert_eq!(
+            uncompressed_path(Path::new("#rust/2026-10-18.log.gz")),
+            Path::new("#rust/2026-10-18.log"),
+        );
+        assert_eq!(
+            uncompressed_path(Path::new("#rust/2026-10-18.imported.log.zst")),
+            Path::new("#rust/2026-10-18.imported.log"),
+        );
+    }
+
+    #[test]
+    fn test_compress() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("bounce-rotation-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        let entries = || -> Result<Vec<PathBuf>> {
+            let mut entries: Vec<PathBuf> = fs::read_dir(&dir)?
+                .map(|entry| Ok(entry?.path()))
+                .collect::<Result<_>>()?;
+            entries.sort();
+            Ok(entries)
+        };
+
+        for compression in &[LogCompression::Gzip, LogCompression::Zstd] {
+            let path = dir.join("2026-10-18.log");
+            fs::write(&path, "a line\r\nanother line\r\n")?;
+
+            let compressed = PendingCompression {
+                path: path.clone(),
+                compression: *compression,
+            }
+            .compress()?;
+            // The log is still there to be read until it's replaced
+            assert_eq!(entries()?.len(), 2);
+            assert!(entries()?.contains(&path));
+
+            let compressed = compressed.replace()?.unwrap();
+            assert_eq!(entries()?, vec![compressed.clone()]);
+            assert_eq!(read(&compressed, 0)?, "a line\r\nanother line\r\n");
+            assert_eq!(read(&compressed, 8)?, "another line\r\n");
+            fs::remove_file(compressed)?;
+        }
+
+        // A log that grows while being compressed is left for next time
+        let path = dir.join("2026-10-18.log");
+        fs::write(&path, "a line\r\n")?;
+        let compressed = PendingCompression {
+            path: path.clone(),
+            compression: LogCompression::Gzip,
+        }
+        .compress()?;
+        fs::write(&path, "a line\r\nanother line\r\n")?;
+        assert_eq!(compressed.replace()?, None);
+        assert_eq!(entries()?, vec![path.clone()]);
+        fs::remove_file(path)?;
+
+        fs::remove_dir(&dir)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_expired() {
+        let candidates = || {
+            ["2026-10-15", "2026-10-16", "2026-10-17", "2026-10-18"]
+                .iter()
+                .map(|day| ExpiryCandidate {
+                    path: PathBuf::from(name_for_date(date(day))),
+                    date: date(day),
+                    size: 100,
+                })
+                .collect()
+        };
+        let today = date("2026-10-18");
+
+        assert_eq!(
+            expired(candidates(), today, Some(2), None),
+            vec![PathBuf::from("2026-10-15.log")],
+        );
+        assert_eq!(
+            expired(candidates(), today, None, Some(250)),
+            vec![
+                PathBuf::from("2026-10-15.log"),
+                PathBuf::from("2026-10-16.log"),
+            ],
+        );
+        // Today's log is kept however large it is
+        assert_eq!(expired(candidates(), today, Some(0), Some(0)).len(), 3);
+        assert!(expired(candidates(), today, None, None).is_empty());
+    }
+}
@@ -0,0 +1,143 @@
+//! Searches for logged messages by their text, sender, target and time.
+//!
+//! Text is matched word by word, ignoring case and punctuation, so that
+//! searching for "rust async" finds "Async in Rust?" but not "rusty". The
+//! SQLite backend keeps a full-text index of the words; the files backend
+//! reads through the logs in the time range searched.
+
+use chrono::{DateTime, Utc};
+
+use super::record::Record;
+use crate::irc::Message;
+use crate::state::CaseMapping;
+
+/// Commands whose messages are searched.
+const SEARCHED_COMMANDS: &[&str] = &["PRIVMSG", "NOTICE"];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Search {
+    /// Words that must all appear in a message.
+    pub text: Option<String>,
+    /// Folded nick of the sender.
+    pub from: Option<String>,
+    /// Folded channel or nick the message was logged under.
+    pub target: Option<String>,
+    pub start: Option<DateTime<Utc>>,
+    pub end: Option<DateTime<Utc>>,
+    /// The most matches returned, keeping the most recent.
+    pub limit: usize,
+    /// How `from` was folded, to compare it with senders.
+    pub casemapping: CaseMapping,
+}
+
+/// Returns the text of a message that can be searched for, if it's one
+/// that's searched.
+pub fn searchable_text(message: &Message) -> Option<&str> {
+    if !SEARCHED_COMMANDS.contains(&message.command()) {
+        return None;
+    }
+
+    message.params().get(1).map(|text| text.as_str())
+}
+
+/// Splits text into the lowercase words it's searched by.
+fn words(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_lowercase())
+        .collect()
+}
+
+impl Search {
+    /// Returns whether `record` is one of the messages searched for,
+    /// leaving its target and time to the backend.
+    pub fn matches(&self, record: &Record) -> bool {
+        let text = match searchable_text(&record.message) {
+            Some(text) => text,
+            None => return false,
+        };
+
+        if let Some(from) = &self.from {
+            let source = record.message.prefix().map(|prefix| prefix.entity());
+            if source.map(|source| self.casemapping.fold(source)).as_ref() != Some(from) {
+                return false;
+            }
+        }
+
+        match &self.text {
+            Some(search) => {
+                let message_words = words(text);
+                words(search)
+                    .iter()
+                    .all(|word| message_words.contains(word))
+            }
+            None => true,
+        }
+    }
+
+    /// Returns the text searched for as an SQLite FTS5 query, with each
+    /// word quoted so that words like "OR" aren't taken for query syntax.
+    pub fn fts_query(&self) -> Option<String> {
+        let words = words(self.text.as_deref().unwrap_or_default());
+        if words.is_empty() {
+            return None;
+        }
+
+        Some(
+            words
+                .iter()
+                .map(|word| format!("\"{}\"", word))
+                .collect::<Vec<_>>()
+                .join(" "),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::str::FromStr;
+
+    use anyhow::Result;
+
+    fn search(text: Option<&str>, from: Option<&str>) -> Search {
+        Search {
+            text: text.map(|text| text.to_string()),
+            from: from.map(|from| from.to_string()),
+            target: None,
+            start: None,
+            end: None,
+            limit: 10,
+            casemapping: CaseMapping::Rfc1459,
+        }
+    }
+
+    #[test]
+    fn test_matches() -> Result<()> {
+        let record = Record::from_str(
+            "2026-10-18T08:30:06.620Z abc :Jay[m]!j@host PRIVMSG #rust :Async in Rust?",
+        )?;
+
+        assert!(search(None, None).matches(&record));
+        assert!(search(Some("rust ASYNC"), None).matches(&record));
+        assert!(!search(Some("rusty"), None).matches(&record));
+        assert!(search(Some("in"), Some("jay{m}")).matches(&record));
+        assert!(!search(None, Some("jay")).matches(&record));
+
+        let join = Record::from_str("2026-10-18T08:30:06.620Z abd :Jay[m]!j@host JOIN #rust")?;
+        assert!(!search(None, None).matches(&join));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fts_query() {
+        assert_eq!(
+            search(Some("\"Rust\" OR async*"), None).fts_query(),
+            Some("\"rust\" \"or\" \"async\"".to_string())
+        );
+        assert_eq!(search(Some("?!"), None).fts_query(), None);
+        assert_eq!(search(None, None).fts_query(), None);
+    }
+}
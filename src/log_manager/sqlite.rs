@@ -0,0 +1,730 @@
+//! Stores logs in a SQLite database, `logs.sqlite3` under the base path.
+//!
+//! Every record is a row of `messages`, once for each target it was
+//! logged under, with an empty target for messages logged directly under
+//! the server. Rows are numbered in the order they were logged, and each
+//! client's marker is the last row it had seen when it detached. Imported
+//! history is numbered below zero, counting down, so that it's never after
+//! any marker and so never replayed.
+//!
+//! Times are stored in the same format as the `time` tag, which sorts in
+//! the order the times are in.
+//!
+//! The words of searchable messages are kept in the FTS5 table
+//! `messages_fts`, which stores only the index and shares its row ids with
+//! `messages`.
+
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{format_err, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, TimeDelta, Utc};
+use log::{info, warn};
+use rusqlite::{params, Connection, OptionalExtension, Row};
+
+use super::record::Record;
+use super::rotation::{Compressed, PendingCompression};
+use super::search::searchable_text;
+use super::{IrcLog, Search};
+use crate::cap::{format_server_time, parse_server_time};
+use crate::config::Log;
+use crate::irc::Message;
+
+const DATABASE_NAME: &str = "logs.sqlite3";
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS messages (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        user TEXT NOT NULL,
+        server TEXT NOT NULL,
+        target TEXT NOT NULL,
+        time TEXT NOT NULL,
+        msgid TEXT,
+        message TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS messages_by_time
+        ON messages (user, server, target, time);
+    CREATE INDEX IF NOT EXISTS messages_by_msgid
+        ON messages (user, server, msgid);
+    CREATE VIRTUAL TABLE IF NOT EXISTS messages_fts
+        USING fts5(text, content='', contentless_delete=1);
+    CREATE TABLE IF NOT EXISTS markers (
+        user TEXT NOT NULL,
+        server TEXT NOT NULL,
+        client TEXT NOT NULL,
+        last_id INTEGER NOT NULL,
+        PRIMARY KEY (user, server, client)
+    );
+";
+
+/// Moves the messages out of a table made before ids were AUTOINCREMENT,
+/// which let the ids of the newest messages be reused once they expired,
+/// so they can be copied into one made by `SCHEMA` with their ids intact.
+const MOVE_REUSED_IDS: &str = "
+    ALTER TABLE messages RENAME TO messages_reusing_ids;
+    DROP INDEX messages_by_time;
+    DROP INDEX messages_by_msgid;
+";
+
+const COPY_REUSED_IDS: &str = "
+    INSERT INTO messages (id, user, server, target, time, msgid, message)
+        SELECT id, user, server, target, time, msgid, message FROM messages_reusing_ids;
+    DROP TABLE messages_reusing_ids;
+";
+
+/// Columns a `Record` is read from, in the order `record_from_row` expects.
+const RECORD_COLUMNS: &str = "time, msgid, message";
+
+pub struct SqliteLog {
+    config: Log,
+    connection: Arc<Mutex<Connection>>,
+}
+
+fn record_from_row(row: &Row) -> rusqlite::Result<Result<Record>> {
+    let time: String = row.get(0)?;
+    let msgid: Option<String> = row.get(1)?;
+    let message: String = row.get(2)?;
+
+    Ok((|| {
+        Ok(Record {
+            time: parse_server_time(&time)
+                .ok_or_else(|| format_err!("Invalid time \"{}\"", time))?,
+            msgid,
+            message: Message::from_str(&message)?,
+        })
+    })())
+}
+
+/// Collects the records read by `statement`, skipping any that can't be
+/// parsed.
+fn collect_records(
+    statement: &mut rusqlite::Statement,
+    params: impl rusqlite::Params,
+) -> Result<Vec<Record>> {
+    let mut records = Vec::new();
+    for record in statement.query_map(params, record_from_row)? {
+        match record? {
+            Ok(record) => records.push(record),
+            Err(e) => warn!("skipping unparseable logged message: {}", e),
+        }
+    }
+
+    Ok(records)
+}
+
+impl SqliteLog {
+    pub async fn new(config: &Log) -> Result<Self> {
+        tokio::fs::create_dir_all(&config.base_path).await?;
+        let path = config.base_path.join(DATABASE_NAME);
+
+        let connection = tokio::task::spawn_blocking(move || -> Result<Connection> {
+            let mut connection = Connection::open(path)?;
+            let transaction = connection.transaction()?;
+            let messages_schema: Option<String> = transaction
+                .query_row(
+                    "SELECT sql FROM sqlite_master WHERE type = 'table' AND name = 'messages'",
+                    [],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            match messages_schema {
+                Some(sql) if !sql.contains("AUTOINCREMENT") => {
+                    info!("migrating log database to never reuse message ids");
+                    transaction.execute_batch(MOVE_REUSED_IDS)?;
+                    transaction.execute_batch(SCHEMA)?;
+                    transaction.execute_batch(COPY_REUSED_IDS)?;
+                }
+                _ => transaction.execute_batch(SCHEMA)?,
+            }
+            transaction.commit()?;
+            Ok(connection)
+        })
+        .await??;
+
+        Ok(SqliteLog {
+            config: config.clone(),
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    /// Runs `f` with the connection on a thread where it's free to block.
+    async fn run<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T> + Send + 'static,
+    {
+        let connection = Arc::clone(&self.connection);
+        tokio::task::spawn_blocking(move || {
+            let mut connection = connection
+                .lock()
+                .map_err(|_| format_err!("Log database connection was poisoned"))?;
+            f(&mut connection)
+        })
+        .await?
+    }
+}
+
+#[async_trait]
+impl IrcLog for SqliteLog {
+    async fn append(
+        &mut self,
+        user: &str,
+        server: &str,
+        targets: &[String],
+        record: &Record,
+    ) -> Result<()> {
+        let (user, server) = (user.to_string(), server.to_string());
+        let targets = match targets {
+            [] => vec![String::new()],
+            targets => targets.to_vec(),
+        };
+        let time = format_server_time(&record.time);
+        let msgid = record.msgid.clone();
+        let message = record.message.to_string();
+        let text = searchable_text(&record.message).map(|text| text.to_string());
+
+        self.run(move |connection| {
+            let transaction = connection.transaction()?;
+            {
+                let mut statement = transaction.prepare_cached(
+                    "INSERT INTO messages (user, server, target, time, msgid, message)
+                     VALUES (?, ?, ?, ?, ?, ?)",
+                )?;
+                let mut index = transaction
+                    .prepare_cached("INSERT INTO messages_fts (rowid, text) VALUES (?, ?)")?;
+                for target in &targets {
+                    statement.execute(params![user, server, target, time, msgid, message])?;
+                    if let Some(text) = &text {
+                        index.execute(params![transaction.last_insert_rowid(), text])?;
+                    }
+                }
+            }
+            transaction.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn import(
+        &mut self,
+        user: &str,
+        server: &str,
+        target: &str,
+        records: Vec<Record>,
+    ) -> Result<usize> {
+        let (user, server, target) = (user.to_string(), server.to_string(), target.to_string());
+
+        self.run(move |connection| {
+            let transaction = connection.transaction()?;
+            let mut imported = 0;
+            {
+                let mut next_id: i64 = transaction.query_row(
+                    "SELECT MIN(0, COALESCE(MIN(id), 0)) - 1 FROM messages",
+                    [],
+                    |row| row.get(0),
+                )?;
+                let mut exists = transaction.prepare(
+                    "SELECT EXISTS (SELECT 1 FROM messages
+                     WHERE user = ? AND server = ? AND target = ? AND time = ? AND message = ?)",
+                )?;
+                let mut insert = transaction.prepare(
+                    "INSERT INTO messages (id, user, server, target, time, msgid, message)
+                     VALUES (?, ?, ?, ?, ?, ?, ?)",
+                )?;
+                let mut index =
+                    transaction.prepare("INSERT INTO messages_fts (rowid, text) VALUES (?, ?)")?;
+
+                for record in &records {
+                    let time = format_server_time(&record.time);
+                    let message = record.message.to_string();
+                    let logged: bool = exists
+                        .query_row(params![user, server, target, time, message], |row| {
+                            row.get(0)
+                        })?;
+                    if logged {
+                        continue;
+                    }
+
+                    insert.execute(params![
+                        next_id,
+                        user,
+                        server,
+                        target,
+                        time,
+                        record.msgid,
+                        message
+                    ])?;
+                    if let Some(text) = searchable_text(&record.message) {
+                        index.execute(params![next_id, text])?;
+                    }
+                    next_id -= 1;
+                    imported += 1;
+                }
+            }
+            transaction.commit()?;
+
+            Ok(imported)
+        })
+        .await
+    }
+
+    async fn query(
+        &self,
+        user: &str,
+        server: &str,
+        target: Option<&str>,
+        start: Option<&DateTime<Utc>>,
+        end: Option<&DateTime<Utc>>,
+    ) -> Result<Vec<Record>> {
+        let (user, server) = (user.to_string(), server.to_string());
+        let target = target.map(|target| target.to_string());
+        let start = start.map(format_server_time);
+        let end = end.map(format_server_time);
+
+        self.run(move |connection| {
+            let mut statement = connection.prepare_cached(&format!(
+                "SELECT {} FROM messages
+                 WHERE user = ?1 AND server = ?2
+                   AND (?3 IS NULL OR target = ?3)
+                   AND (?4 IS NULL OR time >= ?4)
+                   AND (?5 IS NULL OR time <= ?5)
+                 ORDER BY time, id",
+                RECORD_COLUMNS
+            ))?;
+            collect_records(&mut statement, params![user, server, target, start, end])
+        })
+        .await
+    }
+
+    async fn query_latest(
+        &self,
+        user: &str,
+        server: &str,
+        target: Option<&str>,
+        end: Option<&DateTime<Utc>>,
+        limit: usize,
+    ) -> Result<Vec<Record>> {
+        let (user, server) = (user.to_string(), server.to_string());
+        let target = target.map(|target| target.to_string());
+        let end = end.map(format_server_time);
+        let limit = limit as i64;
+
+        self.run(move |connection| {
+            let mut statement = connection.prepare_cached(&format!(
+                "SELECT {} FROM messages
+                 WHERE user = ?1 AND server = ?2
+                   AND (?3 IS NULL OR target = ?3)
+                   AND (?4 IS NULL OR time <= ?4)
+                 ORDER BY time DESC, id DESC
+                 LIMIT ?5",
+                RECORD_COLUMNS
+            ))?;
+            let mut records =
+                collect_records(&mut statement, params![user, server, target, end, limit])?;
+            records.reverse();
+
+            Ok(records)
+        })
+        .await
+    }
+
+    async fn find(
+        &self,
+        user: &str,
+        server: &str,
+        _target: Option<&str>,
+        msgid: &str,
+    ) -> Result<Option<Record>> {
+        // msgids are indexed, so there's no need to narrow by target
+        let (user, server, msgid) = (user.to_string(), server.to_string(), msgid.to_string());
+
+        self.run(move |connection| {
+            connection
+                .query_row(
+                    &format!(
+                        "SELECT {} FROM messages
+                         WHERE user = ? AND server = ? AND msgid = ?
+                         LIMIT 1",
+                        RECORD_COLUMNS
+                    ),
+                    params![user, server, msgid],
+                    record_from_row,
+                )
+                .optional()?
+                .transpose()
+        })
+        .await
+    }
+
+    async fn search(&self, user: &str, server: &str, search: &Search) -> Result<Vec<Record>> {
+        let (user, server) = (user.to_string(), server.to_string());
+        let search = search.clone();
+        let fts_query = search.fts_query();
+        let start = search.start.as_ref().map(format_server_time);
+        let end = search.end.as_ref().map(format_server_time);
+
+        self.run(move |connection| {
+            // Senders are only known once messages are parsed, so matches
+            // are counted as they're read, newest first
+            let mut statement = connection.prepare_cached(&format!(
+                "SELECT {} FROM messages
+                 WHERE user = ?1 AND server = ?2
+                   AND (?3 IS NULL OR target = ?3)
+                   AND (?4 IS NULL OR time >= ?4)
+                   AND (?5 IS NULL OR time <= ?5)
+                   AND (?6 IS NULL OR id IN
+                        (SELECT rowid FROM messages_fts WHERE messages_fts MATCH ?6))
+                 ORDER BY time DESC, id DESC",
+                RECORD_COLUMNS
+            ))?;
+            let rows = statement.query_map(
+                params![user, server, search.target, start, end, fts_query],
+                record_from_row,
+            )?;
+
+            let mut records = Vec::new();
+            for record in rows {
+                match record? {
+                    Ok(record) if search.matches(&record) => records.push(record),
+                    Ok(_) => {}
+                    Err(e) => warn!("skipping unparseable logged message: {}", e),
+                }
+                if records.len() == search.limit {
+                    break;
+                }
+            }
+            records.reverse();
+
+            Ok(records)
+        })
+        .await
+    }
+
+    async fn targets(&self, user: &str, server: &str) -> Result<Vec<String>> {
+        let (user, server) = (user.to_string(), server.to_string());
+
+        self.run(move |connection| {
+            let mut statement = connection.prepare_cached(
+                "SELECT DISTINCT target FROM messages
+                 WHERE user = ? AND server = ? AND target != ''
+                 ORDER BY target",
+            )?;
+            let targets = statement
+                .query_map(params![user, server], |row| row.get(0))?
+                .collect::<rusqlite::Result<_>>()?;
+            Ok(targets)
+        })
+        .await
+    }
+
+    async fn since_detach(&self, user: &str, server: &str, client: &str) -> Result<Vec<Record>> {
+        let (user, server, client) = (user.to_string(), server.to_string(), client.to_string());
+
+        self.run(move |connection| {
+            let last_id: Option<i64> = connection
+                .query_row(
+                    "SELECT last_id FROM markers WHERE user = ? AND server = ? AND client = ?",
+                    params![user, server, client],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            let last_id = match last_id {
+                Some(last_id) => last_id,
+                None => return Ok(Vec::new()),
+            };
+
+            let mut statement = connection.prepare_cached(&format!(
+                "SELECT {} FROM messages
+                 WHERE user = ? AND server = ? AND id > ?
+                 ORDER BY target, id",
+                RECORD_COLUMNS
+            ))?;
+            collect_records(&mut statement, params![user, server, last_id])
+        })
+        .await
+    }
+
+    async fn mark_detached(&self, user: &str, server: &str, client: &str) -> Result<()> {
+        let (user, server, client) = (user.to_string(), server.to_string(), client.to_string());
+
+        self.run(move |connection| {
+            connection.execute(
+                "INSERT OR REPLACE INTO markers (user, server, client, last_id)
+                 SELECT ?, ?, ?, COALESCE(MAX(id), 0) FROM messages",
+                params![user, server, client],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn pending_compressions(&mut self) -> Result<Vec<PendingCompression>> {
+        Ok(Vec::new())
+    }
+
+    async fn maintain(&mut self, _compressed: Vec<Compressed>) -> Result<()> {
+        // Compression and retention_bytes only apply to files, which SQLite
+        // manages itself
+        let days = match self.config.retention_days {
+            Some(days) => days,
+            None => return Ok(()),
+        };
+        let cutoff = format_server_time(&(Utc::now() - TimeDelta::days(i64::from(days))));
+
+        let deleted = self
+            .run(move |connection| {
+                let transaction = connection.transaction()?;
+                transaction.execute(
+                    "DELETE FROM messages_fts
+                     WHERE rowid IN (SELECT id FROM messages WHERE time < ?)",
+                    params![cutoff],
+                )?;
+                let deleted =
+                    transaction.execute("DELETE FROM messages WHERE time < ?", params![cutoff])?;
+                transaction.commit()?;
+                Ok(deleted)
+            })
+            .await?;
+        if deleted > 0 {
+            info!("deleted {} expired logged messages", deleted);
+        }
+
+        Ok(())
+    }
+
+    async fn rebuild_indexes(&mut self) -> Result<usize> {
+        self.run(|connection| {
+            let transaction = connection.transaction()?;
+            transaction.execute_batch("REINDEX messages; DELETE FROM messages_fts;")?;
+            let mut reindexed = 0;
+            {
+                let mut statement = transaction.prepare("SELECT id, message FROM messages")?;
+                let mut index =
+                    transaction.prepare("INSERT INTO messages_fts (rowid, text) VALUES (?, ?)")?;
+                let mut rows = statement.query([])?;
+                while let Some(row) = rows.next()? {
+                    let id: i64 = row.get(0)?;
+                    let message: String = row.get(1)?;
+                    let message = match Message::from_str(&message) {
+                        Ok(message) => message,
+                        Err(e) => {
+                            warn!("skipping unparseable logged message: {}", e);
+                            continue;
+                        }
+                    };
+                    if let Some(text) = searchable_text(&message) {
+                        index.execute(params![id, text])?;
+                    }
+                    reindexed += 1;
+                }
+            }
+            transaction.commit()?;
+
+            Ok(reindexed)
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use anyhow::Result;
+
+    use crate::config::LogBackend;
+    use crate::state::CaseMapping;
+
+    fn record(time: &str, msgid: &str, line: &str) -> Result<Record> {
+        Ok(Record {
+            time: parse_server_time(time).ok_or_else(|| format_err!("bad time"))?,
+            msgid: Some(msgid.to_string()),
+            message: Message::from_str(line)?,
+        })
+    }
+
+    fn msgids(records: &[Record]) -> Vec<&str> {
+        records
+            .iter()
+            .filter_map(|record| record.msgid.as_deref())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_log() -> Result<()> {
+        let base_path = std::env::temp_dir().join(format!("bounce-sqlite-{}", std::process::id()));
+        let mut log = SqliteLog::new(&Log {
+            base_path: base_path.clone(),
+            backend: LogBackend::Sqlite,
+            timezone: Default::default(),
+            compression: Default::default(),
+            retention_days: None,
+            retention_bytes: None,
+        })
+        .await?;
+        let targets = |targets: &[&str]| -> Vec<String> {
+            targets.iter().map(|target| target.to_string()).collect()
+        };
+
+        log.append(
+            "jay",
+            "net",
+            &targets(&["#rust"]),
+            &record("2026-10-18T08:00:00Z", "a", ":belak PRIVMSG #rust :one")?,
+        )
+        .await?;
+        log.mark_detached("jay", "net", "phone").await?;
+        log.append(
+            "jay",
+            "net",
+            &targets(&["belak"]),
+            &record("2026-10-18T09:00:00Z", "b", ":belak PRIVMSG jay :two")?,
+        )
+        .await?;
+        log.append(
+            "jay",
+            "net",
+            &targets(&["#rust", "#go"]),
+            &record("2026-10-18T10:00:00Z", "c", ":belak QUIT :bye")?,
+        )
+        .await?;
+        log.append(
+            "jay",
+            "net",
+            &[],
+            &record("2026-10-18T11:00:00Z", "d", ":irc.test 372 jay :motd")?,
+        )
+        .await?;
+
+        let start = parse_server_time("2026-10-18T09:00:00Z");
+        assert_eq!(
+            msgids(&log.query("jay", "net", Some("#rust"), None, None).await?),
+            vec!["a", "c"]
+        );
+        assert_eq!(
+            msgids(
+                &log.query("jay", "net", None, start.as_ref(), start.as_ref())
+                    .await?
+            ),
+            vec!["b"]
+        );
+        assert_eq!(
+            msgids(
+                &log.query_latest("jay", "net", Some("#rust"), None, 1)
+                    .await?
+            ),
+            vec!["c"]
+        );
+        assert_eq!(
+            msgids(
+                &log.query_latest("jay", "net", None, start.as_ref(), 5)
+                    .await?
+            ),
+            vec!["a", "b"]
+        );
+        assert_eq!(
+            log.find("jay", "net", None, "b")
+                .await?
+                .map(|record| record.time),
+            start
+        );
+        assert_eq!(log.find("jay", "other", None, "b").await?, None);
+        assert_eq!(
+            log.targets("jay", "net").await?,
+            vec!["#go", "#rust", "belak"]
+        );
+        assert_eq!(
+            msgids(&log.since_detach("jay", "net", "phone").await?),
+            vec!["d", "c", "c", "b"]
+        );
+        assert!(log.since_detach("jay", "net", "laptop").await?.is_empty());
+
+        let search = Search {
+            text: None,
+            from: Some("belak".to_string()),
+            target: None,
+            start: None,
+            end: None,
+            limit: 10,
+            casemapping: CaseMapping::Rfc1459,
+        };
+        assert_eq!(
+            msgids(&log.search("jay", "net", &search).await?),
+            vec!["a", "b"]
+        );
+        let search = Search { limit: 1, ..search };
+        assert_eq!(msgids(&log.search("jay", "net", &search).await?), vec!["b"]);
+        let search = Search {
+            text: Some("ONE".to_string()),
+            target: Some("#rust".to_string()),
+            limit: 10,
+            ..search
+        };
+        assert_eq!(msgids(&log.search("jay", "net", &search).await?), vec!["a"]);
+
+        // The QUIT was logged once for each channel
+        assert_eq!(log.rebuild_indexes().await?, 5);
+        assert_eq!(msgids(&log.search("jay", "net", &search).await?), vec!["a"]);
+
+        std::fs::remove_dir_all(base_path)?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ids_not_reused() -> Result<()> {
+        let base_path =
+            std::env::temp_dir().join(format!("bounce-sqlite-ids-{}", std::process::id()));
+        std::fs::create_dir_all(&base_path)?;
+
+        // A database from before ids were AUTOINCREMENT, with a client that
+        // detached after the newest message
+        let connection = Connection::open(base_path.join(DATABASE_NAME))?;
+        connection.execute_batch(&SCHEMA.replace(" AUTOINCREMENT", ""))?;
+        connection.execute_batch(
+            "INSERT INTO messages (id, user, server, target, time, msgid, message)
+                VALUES (1, 'jay', 'net', '#rust', '2026-10-18T08:00:00.000Z', 'a',
+                    ':belak PRIVMSG #rust :one');
+             INSERT INTO markers (user, server, client, last_id)
+                VALUES ('jay', 'net', 'phone', 1);",
+        )?;
+        drop(connection);
+
+        let mut log = SqliteLog::new(&Log {
+            base_path: base_path.clone(),
+            backend: LogBackend::Sqlite,
+            timezone: Default::default(),
+            compression: Default::default(),
+            retention_days: None,
+            retention_bytes: None,
+        })
+        .await?;
+        assert_eq!(
+            msgids(&log.query("jay", "net", Some("#rust"), None, None).await?),
+            vec!["a"]
+        );
+
+        // The newest message expiring doesn't let the next one take its id
+        log.run(|connection| {
+            connection.execute("DELETE FROM messages WHERE id = 1", [])?;
+            Ok(())
+        })
+        .await?;
+        log.append(
+            "jay",
+            "net",
+            &["#rust".to_string()],
+            &record("2026-10-18T09:00:00Z", "b", ":belak PRIVMSG #rust :two")?,
+        )
+        .await?;
+        assert_eq!(
+            msgids(&log.since_detach("jay", "net", "phone").await?),
+            vec!["b"]
+        );
+
+        std::fs::remove_dir_all(base_path)?;
+
+        Ok(())
+    }
+}
@@ -1,49 +1,269 @@
+mod cap;
+mod chathistory;
+mod client;
 mod config;
+mod control;
+mod encoding;
+mod export;
+mod import;
 mod irc;
 mod log_manager;
+mod nick;
+mod reader;
+mod sasl;
+mod search_command;
 mod server;
+mod state;
 
-use std::collections::BTreeMap;
-//use std::str::FromStr;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 use anyhow::Result;
-//use futures::channel::mpsc::Sender;
+use chrono::{DateTime, NaiveDate, TimeDelta, TimeZone, Utc};
+use chrono_tz::Tz;
 use futures::lock::Mutex;
 use log::{debug, error};
-use tokio::net::{TcpListener, TcpStream};
+use structopt::StructOpt;
+use tokio::io::{stdin, AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
 
 use config::Config;
-//use irc::Message;
 use log_manager::LogManager;
+use state::CaseMapping;
 
-// TODO(jsvana): use this for incoming user connections
-/*
-async fn write_worker<T>(
-    user_reader: ReadHalf<T>,
-    mut server_messages: Sender<Message>,
-) -> Result<()>
-where
-    T: AsyncRead,
-{
-    let server_reader = BufReader::new(user_reader);
-    let mut lines = server_reader.lines();
-    while let Some(line) = lines.next_line().await? {
-        server_messages.try_send(Message::from_str(&line)?)?;
+#[derive(Debug, StructOpt)]
+#[structopt(name = "bounce", about = "An IRC bouncer focused on message replay")]
+struct Args {
+    /// Path to the configuration file
+    #[structopt(short, long, default_value = "config.toml")]
+    config: String,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Reads a password from stdin and prints its hash for use in a `[[users]]` entry
+    HashPassword,
+    /// Manages the logs in the configured `[log]` directory
+    Logs(LogsCommand),
+}
+
+#[derive(Debug, StructOpt)]
+enum LogsCommand {
+    /// Rebuilds the indexes used to search the logs from the logs themselves
+    Reindex,
+    /// Prints logs in a format for people to read
+    Export(ExportOptions),
+    /// Adds logs written by ZNC or weechat to the logs
+    Import(ImportOptions),
+}
+
+#[derive(Debug, StructOpt)]
+struct ExportOptions {
+    /// The user whose logs to export, as set in the network's `username`
+    #[structopt(long)]
+    user: String,
+    /// The network whose logs to export
+    #[structopt(long)]
+    network: String,
+    /// Only export the conversation in this channel or with this nick
+    #[structopt(long)]
+    channel: Option<String>,
+    /// The first day to export, as YYYY-MM-DD in the log timezone
+    #[structopt(long)]
+    from: Option<NaiveDate>,
+    /// The last day to export, as YYYY-MM-DD in the log timezone
+    #[structopt(long)]
+    to: Option<NaiveDate>,
+    /// One of irssi, weechat, jsonl or html
+    #[structopt(long, default_value = "irssi")]
+    format: export::Format,
+    /// Writes to this file instead of standard output
+    #[structopt(short, long)]
+    output: Option<PathBuf>,
+}
+
+#[derive(Debug, StructOpt)]
+struct ImportOptions {
+    /// The user to import the logs for, as set in the network's `username`
+    #[structopt(long)]
+    user: String,
+    /// The network the logs are from
+    #[structopt(long)]
+    network: String,
+    /// One of znc or weechat
+    #[structopt(long)]
+    format: import::Format,
+    /// The channel or nick the logs are for, instead of the one their names give
+    #[structopt(long)]
+    channel: Option<String>,
+    /// The user's nick, which tells who messages in queries were sent to
+    #[structopt(long)]
+    nick: Option<String>,
+    /// The timezone the logs were written in, instead of the log timezone
+    #[structopt(long)]
+    timezone: Option<Tz>,
+    /// Log files, or directories to search for them
+    #[structopt(required = true)]
+    paths: Vec<PathBuf>,
+}
+
+async fn hash_password() -> Result<()> {
+    let mut lines = BufReader::new(stdin()).lines();
+    let password = lines.next_line().await?.unwrap_or_default();
+    println!("{}", bcrypt::hash(password, bcrypt::DEFAULT_COST)?);
+
+    Ok(())
+}
+
+async fn logs(config: &Config, command: LogsCommand) -> Result<()> {
+    let mut log_manager = LogManager::new(config).await?;
+
+    match command {
+        LogsCommand::Reindex => {
+            let reindexed = log_manager.rebuild_indexes().await?;
+            println!("Reindexed {} messages", reindexed);
+        }
+        LogsCommand::Export(options) => export_logs(config, &log_manager, options).await?,
+        LogsCommand::Import(options) => import_logs(config, &mut log_manager, options).await?,
+    }
+
+    Ok(())
+}
+
+/// Returns when `date` starts in `timezone`.
+fn start_of_day(date: NaiveDate, timezone: Tz) -> Option<DateTime<Utc>> {
+    let start = timezone
+        .from_local_datetime(&date.and_hms_opt(0, 0, 0)?)
+        .earliest()?;
+    Some(start.with_timezone(&Utc))
+}
+
+async fn export_logs(
+    config: &Config,
+    log_manager: &LogManager,
+    options: ExportOptions,
+) -> Result<()> {
+    let timezone = config.log.timezone;
+    let start = options.from.and_then(|date| start_of_day(date, timezone));
+    let end = options
+        .to
+        .and_then(|date| start_of_day(date.succ_opt()?, timezone))
+        .map(|end| end - TimeDelta::milliseconds(1));
+
+    // Logs are stored under folded names, and the network's casemapping
+    // isn't known without connecting, so assume the usual one
+    let target = options
+        .channel
+        .as_ref()
+        .map(|channel| CaseMapping::Rfc1459.fold(channel));
+
+    let mut messages = log_manager
+        .messages(
+            &options.user,
+            &options.network,
+            target.as_deref(),
+            start.as_ref(),
+            end.as_ref(),
+        )
+        .await?;
+
+    // Messages like QUITs are logged once for every channel they affect
+    if target.is_none() {
+        let mut seen = BTreeSet::new();
+        messages.retain(
+            |message| match message.tag("msgid").and_then(|tag| tag.value()) {
+                Some(msgid) => seen.insert(msgid.to_string()),
+                None => true,
+            },
+        );
+    }
+
+    let title = match &options.channel {
+        Some(channel) => format!("{} on {}", channel, options.network),
+        None => options.network.clone(),
+    };
+    let output = export::render(options.format, &messages, timezone, &title);
+
+    match options.output {
+        Some(path) => tokio::fs::write(path, output).await?,
+        None => tokio::io::stdout().write_all(output.as_bytes()).await?,
     }
 
     Ok(())
 }
-*/
 
-async fn start_workers(_queues: server::GuardedQueueMap, _user_socket: TcpStream) -> Result<()> {
-    // TODO(jsvana): read here
-    debug!("Starting worker now");
+/// Adds the logs of `format` in `path` to `files`, searching directories.
+fn find_logs(format: import::Format, path: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    if !path.is_dir() {
+        files.push(path.to_path_buf());
+        return Ok(());
+    }
+
+    for entry in std::fs::read_dir(path)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            find_logs(format, &path, files)?;
+        } else if format.is_log(&path) {
+            files.push(path);
+        }
+    }
+
     Ok(())
 }
 
-// TODO(jsvana): take in the guarded map from above here
-async fn server_listener_worker(queues: server::GuardedQueueMap, config: &Config) -> Result<()> {
+async fn import_logs(
+    config: &Config,
+    log_manager: &mut LogManager,
+    options: ImportOptions,
+) -> Result<()> {
+    let timezone = options.timezone.unwrap_or(config.log.timezone);
+
+    let mut files = Vec::new();
+    for path in &options.paths {
+        find_logs(options.format, path, &mut files)?;
+    }
+    files.sort();
+
+    let (mut imported, mut skipped) = (0, 0);
+    for path in files {
+        let log = import::LogFile::parse(options.format, &path, options.channel.as_deref())?;
+        let contents = tokio::fs::read(&path).await?;
+        let (messages, skipped_lines) = import::parse_log(
+            options.format,
+            &String::from_utf8_lossy(&contents),
+            &log,
+            timezone,
+            options.nick.as_deref(),
+        );
+
+        // As with exports, assume the usual casemapping
+        let target = CaseMapping::Rfc1459.fold(&log.target);
+        let added = log_manager
+            .import_messages(&options.user, &options.network, &target, messages)
+            .await?;
+        println!(
+            "{}: imported {} messages, skipped {} lines",
+            path.display(),
+            added,
+            skipped_lines
+        );
+        imported += added;
+        skipped += skipped_lines;
+    }
+    println!("Imported {} messages, skipped {} lines", imported, skipped);
+
+    Ok(())
+}
+
+async fn server_listener_worker(
+    log_manager: Arc<Mutex<LogManager>>,
+    queues: server::GuardedQueueMap,
+    config: &Config,
+) -> Result<()> {
     let bind_address = config.core.bind_address();
     let mut listener = TcpListener::bind(&bind_address).await?;
 
@@ -51,9 +271,13 @@ async fn server_listener_worker(queues: server::GuardedQueueMap, config: &Config
     loop {
         let (socket, remote_address) = listener.accept().await?;
         debug!("new connection from {}", remote_address);
+        let local_config = config.clone();
+        let local_log_manager = Arc::clone(&log_manager);
         let local_queues = Arc::clone(&queues);
         tokio::spawn(async move {
-            if let Err(e) = start_workers(local_queues, socket).await {
+            if let Err(e) =
+                client::client_worker(local_config, local_log_manager, local_queues, socket).await
+            {
                 error!("Got an error {}", e);
             }
         });
@@ -64,17 +288,32 @@ async fn server_listener_worker(queues: server::GuardedQueueMap, config: &Config
 async fn main() -> Result<()> {
     env_logger::from_env(env_logger::Env::default().default_filter_or("info")).init();
 
-    let config = Config::from_file("config.toml")?;
+    let args = Args::from_args();
+
+    if let Some(Command::HashPassword) = args.command {
+        return hash_password().await;
+    }
+
+    let config = Config::from_file(&args.config)?;
+
+    if let Some(Command::Logs(command)) = args.command {
+        return logs(&config, command).await;
+    }
 
     let log_manager = Arc::new(Mutex::new(LogManager::new(&config).await?));
 
     // This map contains all of the communication queues for servers
     let queues = Arc::new(Mutex::new(BTreeMap::new()));
 
+    tokio::spawn(log_manager::maintenance_worker(Arc::clone(&log_manager)));
+
+    let thread_log_manager = Arc::clone(&log_manager);
     let thread_queues = Arc::clone(&queues);
     let thread_config = config.clone();
     tokio::spawn(async move {
-        if let Err(e) = server_listener_worker(thread_queues, &thread_config).await {
+        if let Err(e) =
+            server_listener_worker(thread_log_manager, thread_queues, &thread_config).await
+        {
             error!("Got an error {}", e);
         }
     });
@@ -0,0 +1,153 @@
+//! Chooses nicks during registration and notices when the preferred nick
+//! becomes available again.
+
+use super::irc::Message;
+use super::state::CaseMapping;
+
+/// How many numbered variants of the first nick to try before giving up.
+const MAX_NUMBERED_NICKS: usize = 9;
+
+/// Returns the nick to try on the given registration attempt: each of the
+/// configured choices in order, followed by numbered variants of the first
+/// shortened to fit in `max_length`. Returns `None` once all of those have
+/// been tried.
+pub fn candidate(choices: &[String], attempt: usize, max_length: usize) -> Option<String> {
+    if let Some(choice) = choices.get(attempt) {
+        return Some(choice.clone());
+    }
+
+    let number = attempt - choices.len() + 1;
+    if number > MAX_NUMBERED_NICKS {
+        return None;
+    }
+    let suffix = format!("_{}", number);
+    let base: String = choices[0]
+        .chars()
+        .take(max_length.saturating_sub(suffix.len()))
+        .collect();
+
+    Some(base + &suffix)
+}
+
+/// Returns whether `message` reports that `nick` is no longer in use,
+/// either through an ISON reply that doesn't include it or a MONITOR
+/// notification that it went offline.
+pub fn reports_available(message: &Message, nick: &str, casemapping: CaseMapping) -> bool {
+    let params = message.params();
+    let nick = casemapping.fold(nick);
+
+    match (message.command(), params.len()) {
+        // RPL_ISON lists only the nicks that are online
+        ("303", 2..=usize::MAX) => !params[1]
+            .split_whitespace()
+            .any(|online| casemapping.fold(online) == nick),
+        // RPL_MONOFFLINE lists targets that went offline
+        ("731", 2..=usize::MAX) => params[1]
+            .split(',')
+            .any(|offline| casemapping.fold(offline) == nick),
+        _ => false,
+    }
+}
+
+/// Returns whether a MONITOR numeric is only about `nick`, and so answers
+/// the bouncer's own `MONITOR +` rather than a client's.
+pub fn is_monitor_reply_for(message: &Message, nick: &str, casemapping: CaseMapping) -> bool {
+    let nick = casemapping.fold(nick);
+
+    match (message.command(), message.params().get(1)) {
+        // RPL_MONONLINE lists full masks, RPL_MONOFFLINE just nicks
+        ("730" | "731", Some(targets)) => targets.split(',').all(|target| {
+            let target = target.split('!').next().unwrap_or(target);
+            casemapping.fold(target) == nick
+        }),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::str::FromStr;
+
+    use anyhow::Result;
+
+    use crate::state::NetworkState;
+
+    #[test]
+    fn test_candidate() {
+        let choices = vec!["jay".to_string(), "jay_".to_string()];
+
+        assert_eq!(candidate(&choices, 0, 9), Some("jay".to_string()));
+        assert_eq!(candidate(&choices, 1, 9), Some("jay_".to_string()));
+        assert_eq!(candidate(&choices, 2, 9), Some("jay_1".to_string()));
+        assert_eq!(candidate(&choices, 3, 9), Some("jay_2".to_string()));
+    }
+
+    #[test]
+    fn test_candidate_erroneous() -> Result<()> {
+        // A server that rejects every nick with ERR_ERRONEUSNICKNAME runs
+        // out of candidates, each short enough for it
+        let mut state = NetworkState::default();
+        state.update(&Message::from_str(
+            ":irc.test 005 * NICKLEN=6 :are supported by this server",
+        )?);
+        let choices = vec!["jsvana".to_string()];
+
+        let nicks: Vec<String> = (1..)
+            .map_while(|attempt| candidate(&choices, attempt, state.nick_length()))
+            .collect();
+        assert_eq!(nicks.len(), MAX_NUMBERED_NICKS);
+        assert_eq!(nicks[0], "jsva_1");
+        assert!(nicks.iter().all(|nick| nick.len() <= 6));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reports_available_ison() -> Result<()> {
+        let casemapping = CaseMapping::Rfc1459;
+
+        assert!(reports_available(
+            &Message::from_str(":irc.test 303 jay_ :")?,
+            "jay",
+            casemapping,
+        ));
+        assert!(!reports_available(
+            &Message::from_str(":irc.test 303 jay_ :JAY")?,
+            "jay",
+            casemapping,
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reports_available_monitor() -> Result<()> {
+        let casemapping = CaseMapping::Rfc1459;
+
+        assert!(reports_available(
+            &Message::from_str(":irc.test 731 jay_ :other,Jay")?,
+            "jay",
+            casemapping,
+        ));
+        assert!(!reports_available(
+            &Message::from_str(":irc.test 730 jay_ :jay!j@h")?,
+            "jay",
+            casemapping,
+        ));
+
+        assert!(is_monitor_reply_for(
+            &Message::from_str(":irc.test 730 jay_ :Jay!j@h")?,
+            "jay",
+            casemapping,
+        ));
+        assert!(!is_monitor_reply_for(
+            &Message::from_str(":irc.test 731 jay_ :other,jay")?,
+            "jay",
+            casemapping,
+        ));
+
+        Ok(())
+    }
+}
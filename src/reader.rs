@@ -0,0 +1,153 @@
+//! Reads messages from servers, which sometimes send lines that aren't
+//! messages at all: empty lines, lines longer than any server should
+//! send, or garbage. Those are logged, counted, and skipped, so that one
+//! bad line doesn't cost the whole connection.
+
+use anyhow::Result;
+use log::warn;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, BufReader};
+
+use super::encoding::Decoder;
+use super::irc::{self, InvalidMessageError, Message, MAX_MESSAGE_LENGTH, MAX_TAGS_LENGTH};
+
+/// Longest a line may be, in bytes, counting its tags and line ending.
+const MAX_LINE_LENGTH: usize = MAX_TAGS_LENGTH + MAX_MESSAGE_LENGTH;
+
+/// Strips the `\r\n`, or bare `\n`, that ends a line.
+fn trim_line_ending(line: &[u8]) -> &[u8] {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+pub struct MessageReader<R> {
+    reader: BufReader<R>,
+    decoder: Decoder,
+    /// Names the connection in warnings.
+    name: String,
+    buffer: Vec<u8>,
+    bad_lines: usize,
+}
+
+impl<R: AsyncRead + Unpin> MessageReader<R> {
+    pub fn new(reader: R, decoder: Decoder, name: &str) -> Self {
+        MessageReader {
+            reader: BufReader::new(reader),
+            decoder,
+            name: name.to_string(),
+            buffer: Vec::new(),
+            bad_lines: 0,
+        }
+    }
+
+    /// How many lines have been skipped.
+    pub fn bad_lines(&self) -> usize {
+        self.bad_lines
+    }
+
+    /// Reads the next line into the buffer, returning how long it really
+    /// was. No more than `MAX_LINE_LENGTH` bytes are kept.
+    async fn read_line(&mut self) -> Result<usize> {
+        self.buffer.clear();
+        let mut length = (&mut self.reader)
+            .take(MAX_LINE_LENGTH as u64)
+            .read_until(b'\n', &mut self.buffer)
+            .await?;
+
+        // Throw away the rest of a line that's too long
+        if length == MAX_LINE_LENGTH && !self.buffer.ends_with(b"\n") {
+            let mut rest = Vec::new();
+            loop {
+                rest.clear();
+                let read = (&mut self.reader)
+                    .take(MAX_LINE_LENGTH as u64)
+                    .read_until(b'\n', &mut rest)
+                    .await?;
+                length += read;
+                if read < MAX_LINE_LENGTH || rest.ends_with(b"\n") {
+                    break;
+                }
+            }
+        }
+
+        Ok(length)
+    }
+
+    fn parse(&self, length: usize) -> Result<Message, InvalidMessageError> {
+        if length > MAX_LINE_LENGTH {
+            return Err(InvalidMessageError::TooLong(length));
+        }
+
+        let line = trim_line_ending(&self.buffer);
+        irc::check_line_length(line)?;
+        self.decoder.decode(line).parse()
+    }
+
+    /// Returns the next message, or `None` once the server closes the
+    /// connection. Only errors reading from the server are returned.
+    pub async fn next_message(&mut self) -> Result<Option<Message>> {
+        loop {
+            let length = self.read_line().await?;
+            if length == 0 {
+                return Ok(None);
+            }
+
+            match self.parse(length) {
+                Ok(message) => return Ok(Some(message)),
+                Err(e) => {
+                    self.bad_lines += 1;
+                    warn!(
+                        "skipping bad line from {} ({} so far): {}: {:?}",
+                        self.name,
+                        self.bad_lines,
+                        e,
+                        String::from_utf8_lossy(&self.buffer[..self.buffer.len().min(64)]),
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::str::FromStr;
+
+    use crate::config::Network;
+
+    #[tokio::test]
+    async fn test_next_message() -> Result<()> {
+        let network: Network = toml::from_str(
+            "name = \"n\"\nnick_choices = [\"n\"]\nusername = \"u\"\nrealname = \"r\"",
+        )?;
+        let long = format!("PRIVMSG #a :{}\r\n", "x".repeat(MAX_LINE_LENGTH * 2));
+        let lines = [
+            "PING :1\r\n".as_bytes(),
+            b"\r\n",
+            long.as_bytes(),
+            b":jay PRIVMSG #a :caf\xe9\n",
+            b":\r\n",
+            b"PING :2",
+        ]
+        .concat();
+
+        let mut reader = MessageReader::new(&lines[..], Decoder::new(&network), "n");
+        let mut messages = Vec::new();
+        while let Some(message) = reader.next_message().await? {
+            messages.push(message);
+        }
+
+        assert_eq!(
+            messages,
+            vec![
+                Message::from_str("PING :1")?,
+                Message::from_str(":jay PRIVMSG #a :café")?,
+                Message::from_str("PING :2")?,
+            ]
+        );
+        assert_eq!(reader.bad_lines(), 3);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,268 @@
+//! Logs in to upstream networks with SASL while registering.
+
+use anyhow::{format_err, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use log::{info, warn};
+
+use super::cap::Capabilities;
+use super::config::{Sasl, SaslFailurePolicy, SaslMechanism};
+use super::irc::Message;
+
+/// AUTHENTICATE payloads longer than this are split across several lines.
+const AUTHENTICATE_CHUNK_LENGTH: usize = 400;
+
+/// Tracks a single SASL exchange, from requesting the capability through
+/// success or failure.
+pub struct Authentication {
+    config: Sasl,
+    finished: bool,
+}
+
+impl Authentication {
+    pub fn new(config: &Sasl) -> Self {
+        Authentication {
+            config: config.clone(),
+            finished: false,
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    fn mechanism_name(&self) -> &'static str {
+        match self.config.mechanism {
+            SaslMechanism::Plain => "PLAIN",
+            SaslMechanism::External => "EXTERNAL",
+        }
+    }
+
+    /// Checks that the server offers SASL with the configured mechanism,
+    /// returning why not otherwise.
+    pub fn check_available(&self, caps: &Capabilities) -> Result<(), String> {
+        if !caps.is_available("sasl") {
+            return Err("server doesn't support SASL".to_string());
+        }
+
+        // Servers needn't list their mechanisms, in which case we find out
+        // whether ours is supported by trying it
+        match caps.value("sasl") {
+            Some(mechanisms)
+                if !mechanisms
+                    .split(',')
+                    .any(|m| m.eq_ignore_ascii_case(self.mechanism_name())) =>
+            {
+                Err(format!(
+                    "server doesn't support {} (only {})",
+                    self.mechanism_name(),
+                    mechanisms
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The message that starts authenticating, once the server has
+    /// acknowledged the sasl capability.
+    pub fn begin(&self) -> Message {
+        Message::new(
+            None,
+            "AUTHENTICATE",
+            vec![self.mechanism_name().to_string()],
+        )
+    }
+
+    fn payload(&self) -> String {
+        match self.config.mechanism {
+            SaslMechanism::Plain => {
+                let account = self.config.account.as_deref().unwrap_or_default();
+                let password = self.config.password.as_deref().unwrap_or_default();
+                STANDARD.encode(format!("{}\0{}\0{}", account, account, password))
+            }
+            SaslMechanism::External => String::new(),
+        }
+    }
+
+    fn authenticate_messages(&self) -> Vec<Message> {
+        let payload = self.payload();
+        let authenticate =
+            |chunk: &str| Message::new(None, "AUTHENTICATE", vec![chunk.to_string()]);
+
+        let mut messages: Vec<Message> = payload
+            .as_bytes()
+            .chunks(AUTHENTICATE_CHUNK_LENGTH)
+            .map(|chunk| authenticate(std::str::from_utf8(chunk).unwrap_or_default()))
+            .collect();
+
+        // An empty or exactly chunk-sized final line says there's more to
+        // come, so the end has to be marked explicitly.
+        if payload.len().is_multiple_of(AUTHENTICATE_CHUNK_LENGTH) {
+            messages.push(authenticate("+"));
+        }
+
+        messages
+    }
+
+    /// Applies the failure policy, either giving up on the connection or
+    /// letting registration carry on without an account.
+    pub fn fail(&mut self, reason: &str) -> Result<()> {
+        self.finished = true;
+
+        match self.config.on_failure {
+            SaslFailurePolicy::Disconnect => {
+                Err(format_err!("SASL authentication failed: {}", reason))
+            }
+            SaslFailurePolicy::Continue => {
+                warn!("SASL authentication failed ({}), continuing", reason);
+                Ok(())
+            }
+        }
+    }
+
+    /// Handles a message from the server, returning the replies to send.
+    pub fn handle(&mut self, message: &Message) -> Result<Vec<Message>> {
+        let params = message.params();
+
+        if self.finished {
+            return Ok(Vec::new());
+        }
+
+        match message.command() {
+            "AUTHENTICATE" if params.first().map(|s| s.as_str()) == Some("+") => {
+                return Ok(self.authenticate_messages());
+            }
+            // RPL_LOGGEDIN
+            "900" => info!(
+                "logged in as {}",
+                params
+                    .get(2)
+                    .map(|s| s.as_str())
+                    .unwrap_or("unknown account")
+            ),
+            // RPL_SASLSUCCESS
+            "903" => self.finished = true,
+            // ERR_NICKLOCKED, ERR_SASLFAIL, ERR_SASLTOOLONG, ERR_SASLABORTED,
+            // ERR_SASLALREADY, and RPL_SASLMECHS
+            "902" | "904" | "905" | "906" | "907" | "908" => {
+                let reason = params.last().map(|s| s.as_str()).unwrap_or_default();
+                self.fail(&format!("{} {}", message.command(), reason))?;
+            }
+            _ => {}
+        }
+
+        Ok(Vec::new())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::str::FromStr;
+
+    use anyhow::Result;
+
+    fn config(mechanism: SaslMechanism, on_failure: SaslFailurePolicy) -> Sasl {
+        Sasl {
+            mechanism,
+            account: Some("jay".to_string()),
+            password: Some("hunter2".to_string()),
+            on_failure,
+        }
+    }
+
+    fn handle(authentication: &mut Authentication, line: &str) -> Result<Vec<String>> {
+        Ok(authentication
+            .handle(&Message::from_str(line)?)?
+            .iter()
+            .map(|message| message.to_string())
+            .collect())
+    }
+
+    #[test]
+    fn test_plain() -> Result<()> {
+        let mut authentication =
+            Authentication::new(&config(SaslMechanism::Plain, SaslFailurePolicy::Disconnect));
+
+        assert_eq!(authentication.begin().to_string(), "AUTHENTICATE PLAIN");
+        assert_eq!(
+            handle(&mut authentication, "AUTHENTICATE +")?,
+            vec!["AUTHENTICATE amF5AGpheQBodW50ZXIy"],
+        );
+        handle(
+            &mut authentication,
+            ":irc.test 903 * :SASL authentication successful",
+        )?;
+        assert!(authentication.is_finished());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_external() -> Result<()> {
+        let mut authentication = Authentication::new(&config(
+            SaslMechanism::External,
+            SaslFailurePolicy::Disconnect,
+        ));
+
+        assert_eq!(authentication.begin().to_string(), "AUTHENTICATE EXTERNAL");
+        assert_eq!(
+            handle(&mut authentication, "AUTHENTICATE +")?,
+            vec!["AUTHENTICATE +"],
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_available() -> Result<()> {
+        let authentication =
+            Authentication::new(&config(SaslMechanism::Plain, SaslFailurePolicy::Disconnect));
+        let mut caps = Capabilities::default();
+
+        assert!(authentication.check_available(&caps).is_err());
+        caps.update(&Message::from_str(":irc.test CAP * LS :sasl=EXTERNAL")?);
+        assert!(authentication.check_available(&caps).is_err());
+        caps.update(&Message::from_str(":irc.test CAP * NEW :sasl")?);
+        assert!(authentication.check_available(&caps).is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_long_payload_is_chunked() {
+        let mut config = config(SaslMechanism::Plain, SaslFailurePolicy::Disconnect);
+        // 300 bytes of credentials encode to exactly one full chunk
+        config.account = Some("a".repeat(149));
+        config.password = Some(String::new());
+
+        let messages = Authentication::new(&config).authenticate_messages();
+
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].params()[0].len(), AUTHENTICATE_CHUNK_LENGTH);
+        assert_eq!(messages[1].params()[0], "+");
+    }
+
+    #[test]
+    fn test_failure_policy() -> Result<()> {
+        let mut authentication =
+            Authentication::new(&config(SaslMechanism::Plain, SaslFailurePolicy::Disconnect));
+        assert!(handle(
+            &mut authentication,
+            ":irc.test 904 * :SASL authentication failed"
+        )
+        .is_err());
+
+        let mut authentication =
+            Authentication::new(&config(SaslMechanism::Plain, SaslFailurePolicy::Continue));
+        assert!(handle(
+            &mut authentication,
+            ":irc.test 904 * :SASL authentication failed"
+        )?
+        .is_empty());
+        assert!(authentication.is_finished());
+
+        Ok(())
+    }
+}
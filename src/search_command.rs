@@ -0,0 +1,136 @@
+//! Serves the IRCv3 `SEARCH` draft, advertised as `soju.im/search`, which
+//! lets clients search the logs of the network they're attached to.
+//!
+//! A search is a single parameter of attributes in the same form as
+//! message tags, for example
+//!
+//!   SEARCH in=#rust;from=jay;text=async\sawait;after=2026-10-01T00:00:00.000Z
+//!
+//! and its results are sent in a `soju.im/search` batch, oldest first.
+
+use super::cap::parse_server_time;
+use super::chathistory::{self, Fail, MAX_LIMIT};
+use super::irc::{Message, Tag};
+use super::log_manager::Search;
+use super::state::CaseMapping;
+
+/// The capability offered for searching, which is also the batch type.
+pub const CAPABILITY: &str = "soju.im/search";
+
+fn invalid_params(param: &str, description: &'static str) -> Fail {
+    Fail::new(
+        "SEARCH",
+        "INVALID_PARAMS",
+        vec![param.to_string()],
+        description,
+    )
+}
+
+/// Parses a SEARCH command from a client, folding names with
+/// `casemapping`.
+pub fn parse(message: &Message, casemapping: CaseMapping) -> Result<Search, Fail> {
+    let attributes = message.params().first().ok_or_else(|| {
+        Fail::new(
+            "SEARCH",
+            "NEED_MORE_PARAMS",
+            Vec::new(),
+            "Missing attributes",
+        )
+    })?;
+
+    let mut search = Search {
+        text: None,
+        from: None,
+        target: None,
+        start: None,
+        end: None,
+        limit: MAX_LIMIT,
+        casemapping,
+    };
+    for tag in Tag::parse_all(attributes) {
+        let value = tag
+            .value()
+            .ok_or_else(|| invalid_params(tag.key(), "Missing value"))?;
+
+        match tag.key() {
+            "text" => search.text = Some(value.to_string()),
+            "from" => search.from = Some(casemapping.fold(value)),
+            "in" => search.target = Some(casemapping.fold(value)),
+            "after" | "before" => {
+                let time = parse_server_time(value)
+                    .ok_or_else(|| invalid_params(value, "Invalid timestamp"))?;
+                if tag.key() == "after" {
+                    search.start = Some(time);
+                } else {
+                    search.end = Some(time);
+                }
+            }
+            "limit" => {
+                search.limit = match value.parse::<usize>() {
+                    Ok(limit) if limit > 0 => limit.min(MAX_LIMIT),
+                    _ => return Err(invalid_params(value, "Invalid limit")),
+                }
+            }
+            key => return Err(invalid_params(key, "Unknown attribute")),
+        }
+    }
+
+    Ok(search)
+}
+
+/// Frames the results of a search in a batch for the client.
+pub fn batch(results: Vec<Message>) -> Vec<Message> {
+    chathistory::frame(vec![CAPABILITY.to_string()], results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::str::FromStr;
+
+    use anyhow::{format_err, Result};
+
+    fn parse(line: &str) -> Result<Search> {
+        super::parse(&Message::from_str(line)?, CaseMapping::Rfc1459)
+            .map_err(|e| format_err!("{:?}", e))
+    }
+
+    #[test]
+    fn test_parse() -> Result<()> {
+        assert_eq!(
+            parse("SEARCH in=#Rust;from=Jay[m];text=async\\sawait;after=2026-10-01T00:00:00.000Z;limit=5")?,
+            Search {
+                text: Some("async await".to_string()),
+                from: Some("jay{m}".to_string()),
+                target: Some("#rust".to_string()),
+                start: parse_server_time("2026-10-01T00:00:00Z"),
+                end: None,
+                limit: 5,
+                casemapping: CaseMapping::Rfc1459,
+            },
+        );
+        assert_eq!(parse("SEARCH text=hi;limit=1000")?.limit, MAX_LIMIT);
+
+        let fail = |line: &str| -> Result<String> {
+            Ok(super::parse(&Message::from_str(line)?, CaseMapping::Ascii)
+                .unwrap_err()
+                .to_message()
+                .to_string())
+        };
+        assert_eq!(
+            fail("SEARCH")?,
+            ":bounce FAIL SEARCH NEED_MORE_PARAMS :Missing attributes"
+        );
+        assert_eq!(
+            fail("SEARCH before=yesterday")?,
+            ":bounce FAIL SEARCH INVALID_PARAMS yesterday :Invalid timestamp"
+        );
+        assert_eq!(
+            fail("SEARCH sort=asc")?,
+            ":bounce FAIL SEARCH INVALID_PARAMS sort :Unknown attribute"
+        );
+
+        Ok(())
+    }
+}
@@ -3,24 +3,183 @@ use std::net::ToSocketAddrs;
 use std::pin::Pin;
 use std::str::FromStr;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use anyhow::{format_err, Result};
 use futures::channel::mpsc::{channel, Receiver, Sender};
-use futures::future::{join, join_all};
+use futures::future::{join_all, select_all, FutureExt};
 use futures::lock::Mutex;
-use futures::StreamExt;
-use log::{debug, trace};
-use native_tls::TlsConnector;
-use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, BufReader};
+use futures::{SinkExt, StreamExt};
+use log::{debug, error, info, trace, warn};
+use native_tls::{Identity, TlsConnector};
+use rand::{thread_rng, Rng};
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpStream;
 use tokio::prelude::*;
-use tokio_tls;
+use tokio::sync::broadcast;
+use tokio::time::delay_for;
 
-use super::config::{Config, Network};
+use super::cap;
+use super::client::BOUNCER_PREFIX;
+use super::config::{Config, Network, NetworkChannel, NetworkServer};
+use super::encoding::Decoder;
 use super::irc::Message;
 use super::log_manager::LogManager;
+use super::nick;
+use super::reader::MessageReader;
+use super::state::NetworkState;
+
+/// Number of upstream messages buffered for attached clients. A client
+/// that falls further behind than this is disconnected.
+const CLIENT_BROADCAST_CAPACITY: usize = 1024;
+
+/// Communication queues for a single upstream network connection.
+#[derive(Clone)]
+pub struct NetworkQueues {
+    /// Messages to be written to the upstream server.
+    pub server_messages: Sender<Message>,
+    /// Messages from the upstream server, fanned out to every attached client.
+    pub client_messages: broadcast::Sender<Message>,
+    /// State of the connection, used to catch up newly attached clients.
+    pub state: Arc<Mutex<NetworkState>>,
+}
+
+pub type GuardedQueueMap = Arc<Mutex<BTreeMap<String, NetworkQueues>>>;
+
+pub fn queue_key(username: &str, network: &str) -> String {
+    format!("{}:{}", username, network)
+}
+
+/// Replies sent by the server during registration.
+const REGISTRATION_REPLIES: &[&str] = &[
+    "001", "002", "003", "004", "005", "250", "251", "252", "253", "254", "255", "265", "266",
+    "372", "375", "376", "422",
+];
+
+const RECONNECT_MIN_DELAY: Duration = Duration::from_secs(1);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(300);
+
+/// How often to check whether the preferred nick is available, on servers
+/// without MONITOR.
+const ISON_POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Connections that stay up at least this long reset the backoff.
+const RECONNECT_RESET_AFTER: Duration = Duration::from_secs(60);
+
+/// Exponential reconnection backoff with jitter.
+#[derive(Default)]
+struct Backoff {
+    attempts: u32,
+}
+
+impl Backoff {
+    fn reset(&mut self) {
+        self.attempts = 0;
+    }
+
+    /// Returns how long to wait before the next attempt. The delay doubles
+    /// with each attempt up to a maximum, and the upper half of it is
+    /// randomized so that networks don't reconnect in lockstep.
+    fn next_delay(&mut self) -> Duration {
+        let delay = RECONNECT_MIN_DELAY
+            .checked_mul(2u32.saturating_pow(self.attempts))
+            .map_or(RECONNECT_MAX_DELAY, |delay| delay.min(RECONNECT_MAX_DELAY));
+        self.attempts = self.attempts.saturating_add(1);
+
+        let half = delay.as_millis() as u64 / 2;
+        Duration::from_millis(half + thread_rng().gen_range(0, half + 1))
+    }
+}
+
+fn bouncer_notice(nick: Option<&str>, text: &str) -> Result<Message> {
+    Ok(Message::from_str(&format!(
+        ":{} NOTICE {} :{}",
+        BOUNCER_PREFIX,
+        nick.unwrap_or("*"),
+        text
+    ))?)
+}
+
+/// Maximum length of the channel list in a single JOIN.
+const JOIN_LINE_LENGTH: usize = 400;
+
+/// Builds JOIN messages for `channels`, batching as many channels into
+/// each message as fit. Keyed channels come first so that each key lines
+/// up with its channel.
+fn join_messages(channels: &[NetworkChannel]) -> Result<Vec<Message>> {
+    let mut channels: Vec<&NetworkChannel> = channels.iter().collect();
+    channels.sort_by_key(|channel| channel.key.is_none());
+
+    let mut messages = Vec::new();
+    let mut names: Vec<&str> = Vec::new();
+    let mut keys: Vec<&str> = Vec::new();
+    let mut length = 0;
+
+    for channel in channels {
+        let channel_length = channel.name.len() + channel.key.as_ref().map_or(0, |k| k.len()) + 2;
+        if !names.is_empty() && length + channel_length > JOIN_LINE_LENGTH {
+            messages.push(Message::from_str(
+                format!("JOIN {} {}", names.join(","), keys.join(",")).trim_end(),
+            )?);
+            names.clear();
+            keys.clear();
+            length = 0;
+        }
+
+        names.push(&channel.name);
+        if let Some(key) = &channel.key {
+            keys.push(key);
+        }
+        length += channel_length;
+    }
+
+    if !names.is_empty() {
+        messages.push(Message::from_str(
+            format!("JOIN {} {}", names.join(","), keys.join(",")).trim_end(),
+        )?);
+    }
+
+    Ok(messages)
+}
+
+async fn join_channels(
+    channels: &[NetworkChannel],
+    server_messages: &mut Sender<Message>,
+) -> Result<()> {
+    for message in join_messages(channels)? {
+        server_messages.send(message).await?;
+    }
 
-pub type GuardedQueueMap = Arc<Mutex<BTreeMap<String, Sender<Message>>>>;
+    Ok(())
+}
+
+/// Rejoins `channel` after the network's rejoin delay.
+fn schedule_rejoin(
+    network: &Network,
+    channel: &NetworkChannel,
+    mut server_messages: Sender<Message>,
+) {
+    let delay = Duration::from_secs(network.rejoin_delay_secs);
+    let channel = channel.clone();
+    let network_name = network.name.clone();
+
+    tokio::spawn(async move {
+        delay_for(delay).await;
+        debug!("rejoining {} on {}", channel.name, network_name);
+
+        match join_messages(&[channel]) {
+            Ok(messages) => {
+                for message in messages {
+                    // The connection has gone away; it'll rejoin on its own
+                    if server_messages.send(message).await.is_err() {
+                        break;
+                    }
+                }
+            }
+            Err(e) => error!("unable to rejoin on {}: {}", network_name, e),
+        }
+    });
+}
 
 fn respond_to_ping(message: Message, server_messages: &mut Sender<Message>) -> Result<()> {
     match message.params().last() {
@@ -37,127 +196,444 @@ async fn individual_network_read_worker(
     log_manager: Arc<Mutex<LogManager>>,
     server_reader: Pin<Box<dyn AsyncRead + Unpin>>,
     mut messages: Sender<Message>,
+    network_queues: &NetworkQueues,
+    mut negotiation: cap::Negotiation,
+    reconnecting: bool,
 ) -> Result<()> {
-    let server_reader = BufReader::new(server_reader);
-    let mut lines = server_reader.lines();
-    while let Some(line) = lines.next_line().await? {
-        let message = Message::from_str(&line)?;
+    let primary_nick = &config.nick_choices[0];
+    let mut nick_attempt = 0;
+    // Whether the bouncer is monitoring the primary nick, and whether it
+    // has asked for it back
+    let mut monitoring = false;
+    let mut regaining = false;
+    let client_messages = &network_queues.client_messages;
+
+    let mut reader = MessageReader::new(server_reader, Decoder::new(config), &config.name);
+    while let Some(mut message) = reader.next_message().await? {
+        if message.command() == "PING" {
+            // A missed PONG is the server's to complain about
+            if let Err(e) = respond_to_ping(message, &mut messages) {
+                warn!("failed to answer PING from {}: {}", config.name, e);
+            }
+            continue;
+        }
 
-        if message.command() == "PING".to_string() {
-            respond_to_ping(message, &mut messages)?;
+        trace!("[recv] {}", message);
+
+        // The log stays locked until the message has been broadcast so
+        // that an attaching client sees each message exactly once, either
+        // in its burst and replay or from the broadcast.
+        let mut log_manager = log_manager.lock().await;
+        let mut state = network_queues.state.lock().await;
+        let previous_nick = state.nick().map(|nick| nick.to_string());
+        let log_targets = state.log_targets(&message);
+        state.update(&message);
+
+        for reply in negotiation.handle(&message, &state)? {
+            messages.send(reply).await?;
+        }
+        if cap::is_negotiation(&message) {
+            continue;
+        }
+
+        // Clients attached across a reconnect have already been welcomed,
+        // so they only need to hear about what changed.
+        let mut forward = !(reconnecting && REGISTRATION_REPLIES.contains(&message.command()));
+        // Replies to what the bouncer asked for itself are nobody else's
+        // business, and aren't logged either
+        let mut own_reply = false;
+
+        match message.command() {
+            "001" => {
+                if reconnecting {
+                    if let (Some(previous_nick), Some(nick)) = (&previous_nick, state.nick()) {
+                        if previous_nick != nick {
+                            let _ = client_messages.send(Message::from_str(&format!(
+                                ":{} NICK {}",
+                                previous_nick, nick
+                            ))?);
+                        }
+                    }
+                    let _ = client_messages.send(bouncer_notice(
+                        state.nick(),
+                        &format!("Reconnected to {}", config.name),
+                    )?);
+                }
+
+                join_channels(&state.channels_to_join(&config.channels), &mut messages).await?;
+            }
+            // ERR_ERRONEUSNICKNAME, ERR_NICKNAMEINUSE, and ERR_NICKCOLLISION
+            "432" | "433" | "436" if !state.is_registered() => {
+                nick_attempt += 1;
+                let nick = nick::candidate(&config.nick_choices, nick_attempt, state.nick_length())
+                    .ok_or_else(|| format_err!("no nick was accepted by {}", config.name))?;
+                info!(
+                    "nick rejected on {} ({}), trying {}",
+                    config.name,
+                    message.command(),
+                    nick
+                );
+                messages
+                    .send(Message::from_str(&format!("NICK {}", nick))?)
+                    .await?;
+
+                // The bouncer picks the nick, so clients needn't know
+                forward = false;
+            }
+            // End of MOTD, by which point ISUPPORT has been received
+            "376" | "422"
+                if !state.is_own_nick(primary_nick) && state.isupport("MONITOR").is_some() =>
+            {
+                messages
+                    .send(Message::from_str(&format!("MONITOR + {}", primary_nick))?)
+                    .await?;
+                monitoring = true;
+            }
+            "303" | "730" | "731" => {
+                let own = match message.command() {
+                    "303" => state.take_ison_reply(),
+                    _ => {
+                        monitoring
+                            && nick::is_monitor_reply_for(
+                                &message,
+                                primary_nick,
+                                state.casemapping(),
+                            )
+                    }
+                };
+                if own {
+                    own_reply = true;
+                    if !regaining
+                        && !state.is_own_nick(primary_nick)
+                        && nick::reports_available(&message, primary_nick, state.casemapping())
+                    {
+                        info!("regaining nick {} on {}", primary_nick, config.name);
+                        messages
+                            .send(Message::new(None, "NICK", vec![primary_nick.clone()]))
+                            .await?;
+                        regaining = true;
+                    }
+                }
+            }
+            // Someone else took the nick first
+            "432" | "433" | "436" if regaining => {
+                regaining = false;
+                own_reply = true;
+            }
+            "NICK" => {
+                let regained = state.is_own_nick(primary_nick)
+                    && previous_nick.is_some_and(|previous| previous != *primary_nick);
+                if regained {
+                    regaining = false;
+                }
+                if regained && monitoring {
+                    messages
+                        .send(Message::from_str(&format!("MONITOR - {}", primary_nick))?)
+                        .await?;
+                    monitoring = false;
+                }
+            }
+            "KICK" if message.params().len() >= 2 => {
+                let (channel_name, kicked) = (&message.params()[0], &message.params()[1]);
+                let casemapping = state.casemapping();
+                let configured = config
+                    .channels
+                    .iter()
+                    .find(|c| casemapping.fold(&c.name) == casemapping.fold(channel_name));
+
+                if let Some(channel) = configured {
+                    if state.is_own_nick(kicked) {
+                        info!("kicked from {} on {}", channel_name, config.name);
+                        schedule_rejoin(config, channel, messages.clone());
+                    }
+                }
+            }
+            _ => {}
+        }
+        drop(state);
+
+        if own_reply {
             continue;
         }
 
         log_manager
-            .lock()
-            .await
-            .add_message(
-                &config.username,
-                &config.name,
-                /*channel=*/ None,
-                &message,
-            )
+            .add_message(&config.username, &config.name, &log_targets, &mut message)
             .await?;
 
-        trace!("[recv] {}", message);
+        if forward {
+            // This only fails when no clients are attached, in which case
+            // the message has already been persisted for later replay.
+            let _ = client_messages.send(message);
+        }
+    }
 
-        // TODO(jsvana): write messages to log here
+    if reader.bad_lines() > 0 {
+        info!(
+            "skipped {} bad lines from {} before disconnecting",
+            reader.bad_lines(),
+            config.name
+        );
     }
 
     Ok(())
 }
 
-// TODO(jsvana): This should be reading from a queue that
-// both `read_worker` and incoming client connections write
-// to, but then it needs to somehow be exposed to the
-// incoming connections. Maybe `ServerConnectionManager`
-// should hand out copies of the Sender-side of the queue
-// as necessary? Not sure how to reference them, though.
+/// Periodically checks whether the preferred nick has become available on
+/// servers that don't support MONITOR.
+async fn individual_network_nick_worker(
+    config: &Network,
+    mut messages: Sender<Message>,
+    state: Arc<Mutex<NetworkState>>,
+) -> Result<()> {
+    let primary_nick = &config.nick_choices[0];
+
+    loop {
+        delay_for(ISON_POLL_INTERVAL).await;
+
+        let poll = {
+            let mut state = state.lock().await;
+            let poll = state.is_registered()
+                && !state.is_own_nick(primary_nick)
+                && state.isupport("MONITOR").is_none();
+            if poll {
+                state.ison_sent();
+            }
+            poll
+        };
+
+        if poll {
+            messages
+                .send(Message::from_str(&format!("ISON {}", primary_nick))?)
+                .await?;
+        }
+    }
+}
+
+/// Writes `registration` to the server ahead of anything queued, then
+/// writes queued messages until the queue closes.
 async fn individual_network_write_worker(
-    _config: &Network,
-    mut server_writer: Pin<Box<dyn AsyncWrite>>,
-    mut messages: Receiver<Message>,
+    config: &Network,
+    mut server_writer: Pin<Box<dyn AsyncWrite + Unpin>>,
+    registration: Vec<Message>,
+    messages: &mut Receiver<Message>,
 ) -> Result<()> {
+    for message in registration {
+        write_message(config, &mut server_writer, message).await?;
+    }
     while let Some(message) = messages.next().await {
-        trace!("[send] {}", message);
-        server_writer
-            .write_all(format!("{}\r\n", message).as_bytes())
-            .await?;
+        write_message(config, &mut server_writer, message).await?;
     }
 
     Ok(())
 }
 
-async fn individual_network_worker(
-    log_manager: Arc<Mutex<LogManager>>,
-    queues: GuardedQueueMap,
+/// Writes `message` to the server, unless it couldn't be sent as it is.
+async fn write_message(
+    config: &Network,
+    server_writer: &mut Pin<Box<dyn AsyncWrite + Unpin>>,
+    message: Message,
+) -> Result<()> {
+    // Sent as it is, the server would read something else entirely
+    if let Err(e) = message.validate() {
+        warn!("not sending invalid message to {}: {}", config.name, e);
+        return Ok(());
+    }
+
+    trace!("[send] {}", message);
+    server_writer
+        .write_all(format!("{}\r\n", message).as_bytes())
+        .await?;
+
+    Ok(())
+}
+
+/// Runs a single connection to the network until it fails or is closed.
+async fn individual_network_connection(
     network: &Network,
+    server: &NetworkServer,
+    log_manager: &Arc<Mutex<LogManager>>,
+    network_queues: &NetworkQueues,
+    server_messages_rx: &mut Receiver<Message>,
+    reconnecting: bool,
 ) -> Result<()> {
-    let (server_reader, server_writer) = connect_to_network(network).await?;
+    let server_messages_tx = network_queues.server_messages.clone();
 
-    // TODO(jsvana): make buffer size configurable?
-    let (mut server_messages_tx, server_messages_rx) = channel::<Message>(10);
+    let (server_reader, server_writer) = connect_to_network(network, server).await?;
+
+    // Anything queued while disconnected was meant for the old session
+    while server_messages_rx.try_recv().is_ok() {}
 
-    server_messages_tx.try_send(Message::from_str(&format!(
+    // Clients waiting to send can fill the queue again straight away, so
+    // registration goes to the writer directly
+    let mut registration = vec![cap::Negotiation::start()];
+    if let Some(password) = &server.password {
+        // Passwords can hold spaces or start with a colon, so aren't parsed
+        registration.push(Message::new(None, "PASS", vec![password.clone()]));
+    }
+    registration.push(Message::from_str(&format!(
         "NICK {}",
         network.nick_choices[0]
-    ))?)?;
-    server_messages_tx.try_send(Message::from_str(&format!(
+    ))?);
+    registration.push(Message::from_str(&format!(
         "USER {} 0 * :{}",
         network.username, network.realname
-    ))?)?;
+    ))?);
 
-    queues.lock().await.insert(
-        format!("{}:{}", network.username, network.name),
+    let read = individual_network_read_worker(
+        network,
+        Arc::clone(log_manager),
+        server_reader,
         server_messages_tx.clone(),
+        network_queues,
+        cap::Negotiation::new(network.sasl.as_ref()),
+        reconnecting,
+    );
+    let write =
+        individual_network_write_worker(network, server_writer, registration, server_messages_rx);
+    let nick = individual_network_nick_worker(
+        network,
+        server_messages_tx,
+        Arc::clone(&network_queues.state),
     );
 
-    let (read_result, write_result) = join(
-        individual_network_read_worker(
-            &network,
-            log_manager,
-            server_reader,
-            server_messages_tx.clone(),
-        ),
-        individual_network_write_worker(&network, server_writer, server_messages_rx),
-    )
+    // Whichever worker finishes first ends the connection
+    let (result, _, _) = select_all(vec![
+        read.boxed_local(),
+        write.boxed_local(),
+        nick.boxed_local(),
+    ])
     .await;
 
-    read_result?;
-    write_result?;
+    result
+}
 
-    Ok(())
+/// Keeps a network connected for as long as the bouncer runs, reconnecting
+/// with backoff whenever the connection is lost. The queues handed out to
+/// clients outlive any single connection so that attached clients stay
+/// attached across reconnects.
+///
+/// A server that the bouncer registered with is tried again first after a
+/// disconnect; a server that fails before registration is skipped in favor
+/// of the next one configured.
+async fn individual_network_worker(
+    log_manager: Arc<Mutex<LogManager>>,
+    queues: GuardedQueueMap,
+    network: &Network,
+) -> Result<()> {
+    // TODO(jsvana): make buffer size configurable?
+    let (server_messages_tx, mut server_messages_rx) = channel::<Message>(10);
+    let (client_messages_tx, _) = broadcast::channel::<Message>(CLIENT_BROADCAST_CAPACITY);
+    let network_queues = NetworkQueues {
+        server_messages: server_messages_tx,
+        client_messages: client_messages_tx,
+        state: Arc::new(Mutex::new(NetworkState::default())),
+    };
+
+    queues.lock().await.insert(
+        queue_key(&network.username, &network.name),
+        network_queues.clone(),
+    );
+
+    let mut backoff = Backoff::default();
+    let mut reconnecting = false;
+    let mut server_index = 0;
+
+    loop {
+        let server = &network.servers[server_index];
+        let started = Instant::now();
+
+        let reason = match individual_network_connection(
+            network,
+            server,
+            &log_manager,
+            &network_queues,
+            &mut server_messages_rx,
+            reconnecting,
+        )
+        .await
+        {
+            Ok(()) => "Connection closed".to_string(),
+            Err(e) => e.to_string(),
+        };
+
+        if started.elapsed() >= RECONNECT_RESET_AFTER {
+            backoff.reset();
+        }
+        let delay = backoff.next_delay();
+
+        {
+            // Locked in the same order as the read worker so attaching
+            // clients see either the old channels or the PARTs, not both.
+            let _log_manager = log_manager.lock().await;
+            let mut state = network_queues.state.lock().await;
+
+            if !state.is_registered() {
+                server_index = (server_index + 1) % network.servers.len();
+            }
+
+            warn!(
+                "disconnected from {} ({}): {}; reconnecting to {} in {:?}",
+                network.name,
+                server.address(),
+                reason,
+                network.servers[server_index].address(),
+                delay
+            );
+
+            for message in state.disconnected(&reason) {
+                let _ = network_queues.client_messages.send(message);
+            }
+            let _ = network_queues.client_messages.send(bouncer_notice(
+                state.nick(),
+                &format!(
+                    "Disconnected from {}: {}. Reconnecting in {:.1} seconds",
+                    network.name,
+                    reason,
+                    delay.as_secs_f64()
+                ),
+            )?);
+        }
+
+        delay_for(delay).await;
+        reconnecting = true;
+    }
 }
 
 // TODO(jsvana): maybe wrap in a struct?
 async fn connect_to_network(
     network: &Network,
+    server: &NetworkServer,
 ) -> Result<(
     Pin<Box<dyn AsyncRead + Unpin>>,
     Pin<Box<dyn AsyncWrite + Unpin>>,
 )> {
-    let addr = network
-        .server
+    let addr = server
         .address()
-        .to_socket_addrs()
-        .unwrap()
+        .to_socket_addrs()?
         .next()
-        .unwrap();
+        .ok_or_else(|| format_err!("No addresses found for {}", server.address()))?;
 
-    if network.server.ssl {
+    if server.ssl {
         // Occasionally we simply hang here without making progress.
         // Not sure why yet.
         let socket = TcpStream::connect(&addr).await?;
 
-        let cx = TlsConnector::builder().build().unwrap();
+        let mut builder = TlsConnector::builder();
+        if let Some(certificate) = &network.client_certificate {
+            builder.identity(Identity::from_pkcs12(
+                &std::fs::read(&certificate.path)?,
+                &certificate.password,
+            )?);
+        }
+        let cx = builder.build()?;
         let cx = tokio_tls::TlsConnector::from(cx);
 
-        let socket = cx.connect(&network.server.hostname, socket).await?;
+        let socket = cx.connect(&server.hostname, socket).await?;
 
         debug!(
             "SSL connection to {} ({}) established",
             network.name,
-            network.server.address(),
+            server.address(),
         );
 
         let (read_socket, write_socket) = tokio::io::split(socket);
@@ -172,7 +648,7 @@ async fn connect_to_network(
         debug!(
             "unencrypted connection to {} ({}) established",
             network.name,
-            network.server.address(),
+            server.address(),
         );
 
         let (read_socket, write_socket) = tokio::io::split(socket);
@@ -203,3 +679,74 @@ pub async fn connections_worker(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn channel(name: &str, key: Option<&str>) -> NetworkChannel {
+        NetworkChannel {
+            name: name.to_string(),
+            key: key.map(|k| k.to_string()),
+            encoding: None,
+        }
+    }
+
+    #[test]
+    fn test_join_messages_keys_first() -> Result<()> {
+        let messages =
+            join_messages(&[channel("#open", None), channel("#secret", Some("hunter2"))])?;
+
+        assert_eq!(
+            messages,
+            vec![Message::from_str("JOIN #secret,#open hunter2")?],
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_join_messages_batches() -> Result<()> {
+        let channels: Vec<NetworkChannel> = (0..100)
+            .map(|i| channel(&format!("#channel{}", i), None))
+            .collect();
+
+        let messages = join_messages(&channels)?;
+
+        assert!(messages.len() > 1);
+        assert_eq!(
+            messages
+                .iter()
+                .map(|m| m.params()[0].split(',').count())
+                .sum::<usize>(),
+            100,
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_backoff_grows_and_caps() {
+        let mut backoff = Backoff::default();
+
+        for attempt in 0..20 {
+            let delay = backoff.next_delay();
+            let expected = RECONNECT_MIN_DELAY
+                .checked_mul(2u32.pow(attempt.min(10)))
+                .unwrap()
+                .min(RECONNECT_MAX_DELAY);
+            assert!(delay >= expected / 2);
+            assert!(delay <= expected);
+        }
+
+        backoff.reset();
+        assert!(backoff.next_delay() <= RECONNECT_MIN_DELAY);
+    }
+
+    #[test]
+    fn test_join_messages_none() -> Result<()> {
+        assert!(join_messages(&[])?.is_empty());
+
+        Ok(())
+    }
+}
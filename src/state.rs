@@ -0,0 +1,917 @@
+//! Tracks the state of an upstream network connection as seen through
+//! its message stream, so that clients attaching after registration can
+//! be told which nick they have and which channels they're in.
+
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use super::cap::Capabilities;
+use super::client::BOUNCER_PREFIX;
+use super::config::NetworkChannel;
+use super::irc::{Message, Prefix};
+
+/// Maximum number of ISUPPORT tokens sent per synthesized RPL_ISUPPORT.
+const ISUPPORT_TOKENS_PER_LINE: usize = 13;
+
+/// Maximum length of the names list in a synthesized RPL_NAMREPLY.
+const NAMES_LINE_LENGTH: usize = 400;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CaseMapping {
+    Ascii,
+    Rfc1459,
+    StrictRfc1459,
+}
+
+impl CaseMapping {
+    /// Folds `name` to the form used to compare nicks and channels.
+    pub fn fold(self, name: &str) -> String {
+        name.chars()
+            .map(|c| match (self, c) {
+                (_, 'A'..='Z') => c.to_ascii_lowercase(),
+                (CaseMapping::Rfc1459, '~') => '^',
+                (CaseMapping::Rfc1459, '[') | (CaseMapping::StrictRfc1459, '[') => '{',
+                (CaseMapping::Rfc1459, ']') | (CaseMapping::StrictRfc1459, ']') => '}',
+                (CaseMapping::Rfc1459, '\\') | (CaseMapping::StrictRfc1459, '\\') => '|',
+                _ => c,
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug)]
+struct Member {
+    nick: String,
+    /// Membership prefixes (e.g. "@+"), ordered from highest to lowest.
+    prefixes: String,
+}
+
+#[derive(Debug)]
+struct Channel {
+    name: String,
+    /// The key needed to join, if the bouncer knows it.
+    key: Option<String>,
+    topic: Option<String>,
+    /// Members keyed by folded nick.
+    members: BTreeMap<String, Member>,
+    /// Whether the most recent RPL_NAMREPLY burst has ended.
+    names_complete: bool,
+}
+
+impl Channel {
+    fn new(name: &str, key: Option<String>) -> Self {
+        Channel {
+            name: name.to_string(),
+            key,
+            topic: None,
+            members: BTreeMap::new(),
+            names_complete: true,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct NetworkState {
+    /// Whether the server has welcomed the bouncer on the current connection.
+    registered: bool,
+    nick: Option<String>,
+    /// The bouncer's full `nick!user@host`, once the server has shown it.
+    own_prefix: Option<String>,
+    server_name: Option<String>,
+    /// RPL_WELCOME through RPL_MYINFO as received from the server.
+    welcome: Vec<Message>,
+    /// ISUPPORT tokens in the order they were advertised.
+    isupport: Vec<(String, Option<String>)>,
+    /// Joined channels keyed by folded name.
+    channels: BTreeMap<String, Channel>,
+    /// Keys clients have joined channels with, keyed by folded name, until
+    /// the server confirms the JOIN.
+    join_keys: BTreeMap<String, String>,
+    /// Channels that were joined when the connection was lost, to join
+    /// again once reconnected.
+    rejoin: Vec<NetworkChannel>,
+    /// Capabilities negotiated on the current connection.
+    caps: Capabilities,
+    /// ISONs the bouncer has sent that the server hasn't answered yet.
+    pending_isons: usize,
+}
+
+impl NetworkState {
+    pub fn is_registered(&self) -> bool {
+        self.registered
+    }
+
+    pub fn nick(&self) -> Option<&str> {
+        self.nick.as_deref()
+    }
+
+    pub fn caps(&self) -> &Capabilities {
+        &self.caps
+    }
+
+    /// Notes the keys of channels a client is joining, so that they can be
+    /// rejoined after reconnecting.
+    pub fn joining(&mut self, message: &Message) {
+        let params = message.params();
+        if message.command() != "JOIN" || params.len() < 2 {
+            return;
+        }
+
+        let casemapping = self.casemapping();
+        for (channel, key) in params[0].split(',').zip(params[1].split(',')) {
+            if !key.is_empty() {
+                self.join_keys
+                    .insert(casemapping.fold(channel), key.to_string());
+            }
+        }
+    }
+
+    /// Returns the channels to join once registered: the configured ones,
+    /// followed by any others that were joined when the connection was
+    /// lost.
+    pub fn channels_to_join(&mut self, configured: &[NetworkChannel]) -> Vec<NetworkChannel> {
+        let casemapping = self.casemapping();
+        let mut channels = configured.to_vec();
+        for channel in std::mem::take(&mut self.rejoin) {
+            let name = casemapping.fold(&channel.name);
+            if !channels
+                .iter()
+                .any(|existing| casemapping.fold(&existing.name) == name)
+            {
+                channels.push(channel);
+            }
+        }
+
+        channels
+    }
+
+    /// Notes that the bouncer sent an ISON of its own.
+    pub fn ison_sent(&mut self) {
+        self.pending_isons += 1;
+    }
+
+    /// Returns whether an RPL_ISON answers one of the bouncer's own ISONs
+    /// rather than a client's. Servers answer in order, so the oldest
+    /// outstanding ISON is the one being answered.
+    pub fn take_ison_reply(&mut self) -> bool {
+        match self.pending_isons {
+            0 => false,
+            _ => {
+                self.pending_isons -= 1;
+                true
+            }
+        }
+    }
+
+    pub fn isupport(&self, token: &str) -> Option<&str> {
+        self.isupport
+            .iter()
+            .find(|(name, _)| name == token)
+            .map(|(_, value)| value.as_deref().unwrap_or_default())
+    }
+
+    /// Returns the longest nick the server allows, or the nine characters
+    /// RFC 2812 allows until the server says otherwise.
+    pub fn nick_length(&self) -> usize {
+        self.isupport("NICKLEN")
+            .and_then(|length| length.parse().ok())
+            .unwrap_or(9)
+    }
+
+    pub fn casemapping(&self) -> CaseMapping {
+        match self.isupport("CASEMAPPING") {
+            Some("ascii") => CaseMapping::Ascii,
+            Some("strict-rfc1459") => CaseMapping::StrictRfc1459,
+            _ => CaseMapping::Rfc1459,
+        }
+    }
+
+    /// Returns the channel membership prefix symbols from highest to lowest.
+    pub fn prefix_symbols(&self) -> String {
+        self.prefix_modes()
+            .iter()
+            .map(|(_, symbol)| *symbol)
+            .collect()
+    }
+
+    /// Returns the bouncer's full `nick!user@host` if known, or just its
+    /// nick otherwise.
+    pub fn own_prefix(&self) -> Option<&str> {
+        self.own_prefix.as_deref().or_else(|| self.nick())
+    }
+
+    /// Returns (mode, prefix symbol) pairs from highest to lowest.
+    fn prefix_modes(&self) -> Vec<(char, char)> {
+        let value = self.isupport("PREFIX").unwrap_or("(ov)@+");
+        let close = match value.find(')') {
+            Some(close) if value.starts_with('(') => close,
+            _ => return Vec::new(),
+        };
+
+        value[1..close]
+            .chars()
+            .zip(value[close + 1..].chars())
+            .collect()
+    }
+
+    /// Returns the type A, B, and C channel modes, all of which take a
+    /// parameter when set.
+    fn parameter_modes(&self) -> (String, String, String) {
+        let value = self.isupport("CHANMODES").unwrap_or("b,k,l,imnpst");
+        let mut types = value.split(',').map(|t| t.to_string());
+        (
+            types.next().unwrap_or_default(),
+            types.next().unwrap_or_default(),
+            types.next().unwrap_or_default(),
+        )
+    }
+
+    pub fn is_channel(&self, name: &str) -> bool {
+        let chantypes = self.isupport("CHANTYPES").unwrap_or("#&");
+        name.starts_with(|c| chantypes.contains(c))
+    }
+
+    pub fn is_own_nick(&self, nick: &str) -> bool {
+        match &self.nick {
+            Some(own) => self.casemapping().fold(own) == self.casemapping().fold(nick),
+            None => false,
+        }
+    }
+
+    /// Returns the folded names of the channels and queries a message from
+    /// the server should be logged under, or nothing if it's about the
+    /// network as a whole. Must be called before `update`, while the
+    /// channels a quitting user shared with the bouncer are still known.
+    pub fn log_targets(&self, message: &Message) -> Vec<String> {
+        let params = message.params();
+        let source = message.prefix().map(|p| p.entity()).unwrap_or_default();
+        let casemapping = self.casemapping();
+
+        let targets: Vec<&str> = match (message.command(), params.first()) {
+            ("PRIVMSG", Some(target)) | ("NOTICE", Some(target)) | ("TAGMSG", Some(target)) => {
+                // STATUSMSG targets such as "@#channel" belong to the channel
+                let symbols = self.prefix_symbols();
+                let channel = target.trim_start_matches(|c| symbols.contains(c));
+
+                if self.is_channel(channel) {
+                    vec![channel]
+                } else if self.is_own_nick(source) {
+                    vec![target.as_str()]
+                } else if self.is_server(source) {
+                    Vec::new()
+                } else {
+                    vec![source]
+                }
+            }
+            ("JOIN", Some(channels)) | ("PART", Some(channels)) => channels.split(',').collect(),
+            ("KICK", Some(channel)) | ("TOPIC", Some(channel)) | ("MODE", Some(channel))
+                if self.is_channel(channel) =>
+            {
+                vec![channel.as_str()]
+            }
+            ("QUIT", _) | ("NICK", _) => {
+                let key = casemapping.fold(source);
+                self.channels
+                    .values()
+                    .filter(|channel| channel.members.contains_key(&key))
+                    .map(|channel| channel.name.as_str())
+                    .collect()
+            }
+            _ => Vec::new(),
+        };
+
+        targets
+            .into_iter()
+            .filter(|target| !target.is_empty())
+            .map(|target| casemapping.fold(target))
+            .collect()
+    }
+
+    /// Whether a message source is a server rather than a user. Nicks
+    /// can't contain dots, which server names almost always do.
+    fn is_server(&self, source: &str) -> bool {
+        source.is_empty() || source.contains('.') || self.server_name.as_deref() == Some(source)
+    }
+
+    fn channel_mut(&mut self, name: &str) -> Option<&mut Channel> {
+        let key = self.casemapping().fold(name);
+        self.channels.get_mut(&key)
+    }
+
+    /// Updates the state from a message received from the server.
+    pub fn update(&mut self, message: &Message) {
+        let params = message.params();
+        let source = message.prefix().map(|p| p.entity().to_string());
+        let source = source.as_deref().unwrap_or_default();
+
+        match (message.command(), params.len()) {
+            ("001", 1..=usize::MAX) => {
+                // Capabilities are negotiated before the welcome, and the
+                // channels to rejoin are only joined after it
+                let caps = std::mem::take(&mut self.caps);
+                let rejoin = std::mem::take(&mut self.rejoin);
+                *self = NetworkState::default();
+                self.caps = caps;
+                self.rejoin = rejoin;
+                self.registered = true;
+                self.server_name = message.prefix().map(|p| p.entity().to_string());
+                self.nick = Some(params[0].clone());
+                self.welcome.push(message.clone());
+            }
+            ("002", 1..=usize::MAX) | ("003", 1..=usize::MAX) | ("004", 1..=usize::MAX) => {
+                self.welcome.push(message.clone())
+            }
+            ("005", 2..=usize::MAX) => {
+                for token in &params[1..params.len() - 1] {
+                    self.update_isupport(token);
+                }
+            }
+            ("NICK", 1..=usize::MAX) => {
+                let new_nick = &params[0];
+                if self.is_own_nick(source) {
+                    self.nick = Some(new_nick.clone());
+                    if let Some(own_prefix) = &self.own_prefix {
+                        let rest = own_prefix.find('!').map_or("", |i| &own_prefix[i..]);
+                        self.own_prefix = Some(format!("{}{}", new_nick, rest));
+                    }
+                }
+
+                let casemapping = self.casemapping();
+                for channel in self.channels.values_mut() {
+                    if let Some(mut member) = channel.members.remove(&casemapping.fold(source)) {
+                        member.nick = new_nick.clone();
+                        channel.members.insert(casemapping.fold(new_nick), member);
+                    }
+                }
+            }
+            ("JOIN", 1..=usize::MAX) => {
+                if self.is_own_nick(source) {
+                    self.own_prefix = message.prefix().map(|p| p.to_string());
+                    let name = self.casemapping().fold(&params[0]);
+                    let key = self.join_keys.remove(&name);
+                    self.channels.insert(name, Channel::new(&params[0], key));
+                }
+
+                let key = self.casemapping().fold(source);
+                if let Some(channel) = self.channel_mut(&params[0]) {
+                    channel.members.insert(
+                        key,
+                        Member {
+                            nick: source.to_string(),
+                            prefixes: String::new(),
+                        },
+                    );
+                }
+            }
+            ("PART", 1..=usize::MAX) => self.remove_member(&params[0], source),
+            ("KICK", 2..=usize::MAX) => self.remove_member(&params[0], &params[1]),
+            ("QUIT", _) => {
+                let key = self.casemapping().fold(source);
+                for channel in self.channels.values_mut() {
+                    channel.members.remove(&key);
+                }
+            }
+            ("TOPIC", 2..=usize::MAX) => self.set_topic(&params[0], &params[1]),
+            ("331", 2..=usize::MAX) => self.set_topic(&params[1], ""),
+            ("332", 3..=usize::MAX) => self.set_topic(&params[1], &params[2]),
+            ("353", 4..=usize::MAX) => self.add_names(&params[2], &params[3]),
+            ("366", 2..=usize::MAX) => {
+                if let Some(channel) = self.channel_mut(&params[1]) {
+                    channel.names_complete = true;
+                }
+            }
+            ("MODE", 2..=usize::MAX) => self.update_modes(&params[0], &params[1..]),
+            ("CAP", _) => self.caps.update(message),
+            _ => {}
+        }
+    }
+
+    fn update_isupport(&mut self, token: &str) {
+        if let Some(name) = token.strip_prefix('-') {
+            self.isupport.retain(|(existing, _)| existing != name);
+            return;
+        }
+
+        let mut parts = token.splitn(2, '=');
+        let name = parts.next().unwrap_or_default().to_string();
+        let value = parts.next().map(|v| v.to_string());
+
+        match self
+            .isupport
+            .iter_mut()
+            .find(|(existing, _)| *existing == name)
+        {
+            Some(existing) => existing.1 = value,
+            None => self.isupport.push((name, value)),
+        }
+    }
+
+    fn remove_member(&mut self, channel_name: &str, nick: &str) {
+        if self.is_own_nick(nick) {
+            let key = self.casemapping().fold(channel_name);
+            self.channels.remove(&key);
+            return;
+        }
+
+        let key = self.casemapping().fold(nick);
+        if let Some(channel) = self.channel_mut(channel_name) {
+            channel.members.remove(&key);
+        }
+    }
+
+    fn set_topic(&mut self, channel_name: &str, topic: &str) {
+        if let Some(channel) = self.channel_mut(channel_name) {
+            channel.topic = if topic.is_empty() {
+                None
+            } else {
+                Some(topic.to_string())
+            };
+        }
+    }
+
+    fn add_names(&mut self, channel_name: &str, names: &str) {
+        let symbols = self.prefix_symbols();
+        let casemapping = self.casemapping();
+
+        let channel = match self.channel_mut(channel_name) {
+            Some(channel) => channel,
+            None => return,
+        };
+
+        // A new burst replaces whatever the last one said
+        if channel.names_complete {
+            channel.members.clear();
+            channel.names_complete = false;
+        }
+
+        for name in names.split_whitespace() {
+            let nick_start = name.find(|c| !symbols.contains(c)).unwrap_or(name.len());
+            let (prefixes, nick) = name.split_at(nick_start);
+            // Servers with userhost-in-names send full prefixes
+            let nick = nick.split('!').next().unwrap_or_default();
+            if nick.is_empty() {
+                continue;
+            }
+
+            channel.members.insert(
+                casemapping.fold(nick),
+                Member {
+                    nick: nick.to_string(),
+                    prefixes: prefixes.to_string(),
+                },
+            );
+        }
+    }
+
+    fn update_modes(&mut self, target: &str, changes: &[String]) {
+        let prefix_modes = self.prefix_modes();
+        let (type_a, type_b, type_c) = self.parameter_modes();
+        let casemapping = self.casemapping();
+
+        let channel = match self.channel_mut(target) {
+            Some(channel) => channel,
+            None => return,
+        };
+
+        let mut args = changes[1..].iter();
+        let mut adding = true;
+        for mode in changes[0].chars() {
+            match mode {
+                '+' => adding = true,
+                '-' => adding = false,
+                _ => {
+                    if let Some((_, symbol)) = prefix_modes.iter().find(|(m, _)| *m == mode) {
+                        let nick = match args.next() {
+                            Some(nick) => nick,
+                            None => continue,
+                        };
+                        if let Some(member) = channel.members.get_mut(&casemapping.fold(nick)) {
+                            let mut prefixes: Vec<char> = member.prefixes.chars().collect();
+                            prefixes.retain(|p| p != symbol);
+                            if adding {
+                                prefixes.push(*symbol);
+                            }
+                            member.prefixes = prefix_modes
+                                .iter()
+                                .map(|(_, s)| *s)
+                                .filter(|s| prefixes.contains(s))
+                                .collect();
+                        }
+                    } else if mode == 'k' && type_b.contains(mode) {
+                        let key = args.next();
+                        channel.key = key.filter(|_| adding).cloned();
+                    } else if type_a.contains(mode)
+                        || type_b.contains(mode)
+                        || (adding && type_c.contains(mode))
+                    {
+                        args.next();
+                    }
+                }
+            }
+        }
+    }
+
+    /// Forgets the joined channels once the connection has been lost,
+    /// keeping them to rejoin after reconnecting, and returns PARTs that
+    /// tell attached clients to do the same. The
+    /// welcome and ISUPPORT are kept for clients attaching before the
+    /// bouncer reconnects.
+    pub fn disconnected(&mut self, reason: &str) -> Vec<Message> {
+        self.registered = false;
+        self.caps = Capabilities::default();
+        self.pending_isons = 0;
+        self.join_keys.clear();
+
+        let nick = match &self.nick {
+            Some(nick) => nick.clone(),
+            None => return Vec::new(),
+        };
+        let own_prefix = self.own_prefix.clone().unwrap_or(nick);
+
+        let channels = std::mem::take(&mut self.channels);
+        self.rejoin
+            .extend(channels.values().map(|channel| NetworkChannel {
+                name: channel.name.clone(),
+                key: channel.key.clone(),
+                encoding: None,
+            }));
+
+        channels
+            .values()
+            .map(|channel| {
+                Message::new(
+                    Prefix::from_str(&own_prefix).ok(),
+                    "PART",
+                    vec![channel.name.clone(), reason.to_string()],
+                )
+            })
+            .collect()
+    }
+
+    /// Builds the messages a newly attached client needs to catch up: the
+    /// welcome burst, ISUPPORT, and a JOIN, topic, and names list for
+    /// every joined channel. Returns nothing until the server has
+    /// welcomed the bouncer, as the client will see the real burst then.
+    pub fn burst(&self) -> Vec<Message> {
+        let nick = match &self.nick {
+            Some(nick) => nick.clone(),
+            None => return Vec::new(),
+        };
+
+        let server_name = self.server_name.as_deref().unwrap_or(BOUNCER_PREFIX);
+        let server = || Prefix::from_str(server_name).ok();
+
+        let mut messages = Vec::new();
+
+        for message in self.welcome.iter() {
+            let mut params = message.params().clone();
+            params[0] = nick.clone();
+            messages.push(Message::new(server(), message.command(), params));
+        }
+
+        let tokens: Vec<String> = self
+            .isupport
+            .iter()
+            .map(|(name, value)| match value {
+                Some(value) => format!("{}={}", name, value),
+                None => name.clone(),
+            })
+            .collect();
+        for chunk in tokens.chunks(ISUPPORT_TOKENS_PER_LINE) {
+            let mut params = vec![nick.clone()];
+            params.extend(chunk.iter().cloned());
+            params.push("are supported by this server".to_string());
+            messages.push(Message::new(server(), "005", params));
+        }
+
+        messages.push(Message::new(
+            server(),
+            "422",
+            vec![nick.clone(), "MOTD File is missing".to_string()],
+        ));
+
+        let own_prefix = self.own_prefix.as_deref().unwrap_or(&nick);
+        for channel in self.channels.values() {
+            messages.push(Message::new(
+                Prefix::from_str(own_prefix).ok(),
+                "JOIN",
+                vec![channel.name.clone()],
+            ));
+
+            if let Some(topic) = &channel.topic {
+                messages.push(Message::new(
+                    server(),
+                    "332",
+                    vec![nick.clone(), channel.name.clone(), topic.clone()],
+                ));
+            }
+
+            let mut names = Vec::new();
+            for member in channel.members.values() {
+                let highest = member.prefixes.chars().next().map(|c| c.to_string());
+                names.push(format!("{}{}", highest.unwrap_or_default(), member.nick));
+            }
+
+            let mut line = String::new();
+            for name in names {
+                if !line.is_empty() && line.len() + name.len() + 1 > NAMES_LINE_LENGTH {
+                    messages.push(self.names_reply(server(), &nick, &channel.name, &line));
+                    line.clear();
+                }
+                if !line.is_empty() {
+                    line.push(' ');
+                }
+                line.push_str(&name);
+            }
+            if !line.is_empty() {
+                messages.push(self.names_reply(server(), &nick, &channel.name, &line));
+            }
+
+            messages.push(Message::new(
+                server(),
+                "366",
+                vec![
+                    nick.clone(),
+                    channel.name.clone(),
+                    "End of /NAMES list".to_string(),
+                ],
+            ));
+        }
+
+        messages
+    }
+
+    fn names_reply(
+        &self,
+        server: Option<Prefix>,
+        nick: &str,
+        channel: &str,
+        names: &str,
+    ) -> Message {
+        Message::new(
+            server,
+            "353",
+            vec![
+                nick.to_string(),
+                "=".to_string(),
+                channel.to_string(),
+                names.to_string(),
+            ],
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use anyhow::Result;
+
+    fn update_all(state: &mut NetworkState, lines: &[&str]) -> Result<()> {
+        for line in lines {
+            state.update(&Message::from_str(line)?);
+        }
+
+        Ok(())
+    }
+
+    fn burst_lines(state: &NetworkState) -> Vec<String> {
+        state.burst().iter().map(|m| m.to_string()).collect()
+    }
+
+    #[test]
+    fn test_casemapping_fold() {
+        assert_eq!(CaseMapping::Rfc1459.fold("Jay[m]~"), "jay{m}^");
+        assert_eq!(CaseMapping::StrictRfc1459.fold("Jay[m]~"), "jay{m}~");
+        assert_eq!(CaseMapping::Ascii.fold("Jay[m]~"), "jay[m]~");
+    }
+
+    #[test]
+    fn test_burst_before_welcome() {
+        assert!(NetworkState::default().burst().is_empty());
+    }
+
+    #[test]
+    fn test_burst_channel() -> Result<()> {
+        let mut state = NetworkState::default();
+        update_all(
+            &mut state,
+            &[
+                ":irc.test 001 jay :Welcome",
+                ":irc.test 005 jay PREFIX=(ov)@+ CASEMAPPING=ascii :are supported by this server",
+                ":jay!jsvana@localhost JOIN #bounce",
+                ":irc.test 332 jay #bounce :the topic",
+                ":irc.test 353 jay = #bounce :@belak jay",
+                ":irc.test 366 jay #bounce :End of /NAMES list",
+                ":belak!b@h MODE #bounce +v jay",
+                ":other!o@h JOIN #bounce",
+            ],
+        )?;
+
+        assert_eq!(
+            burst_lines(&state),
+            vec![
+                ":irc.test 001 jay Welcome",
+                ":irc.test 005 jay PREFIX=(ov)@+ CASEMAPPING=ascii :are supported by this server",
+                ":irc.test 422 jay :MOTD File is missing",
+                ":jay!jsvana@localhost JOIN #bounce",
+                ":irc.test 332 jay #bounce :the topic",
+                ":irc.test 353 jay = #bounce :@belak +jay other",
+                ":irc.test 366 jay #bounce :End of /NAMES list",
+            ],
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_nick_change_and_part() -> Result<()> {
+        let mut state = NetworkState::default();
+        update_all(
+            &mut state,
+            &[
+                ":irc.test 001 jay :Welcome",
+                ":jay!jsvana@localhost JOIN #a",
+                ":jay!jsvana@localhost JOIN #b",
+                ":jay!jsvana@localhost NICK jay_",
+                ":jay_!jsvana@localhost PART #b",
+            ],
+        )?;
+
+        assert_eq!(
+            burst_lines(&state),
+            vec![
+                ":irc.test 001 jay_ Welcome",
+                ":irc.test 422 jay_ :MOTD File is missing",
+                ":jay_!jsvana@localhost JOIN #a",
+                ":irc.test 353 jay_ = #a jay_",
+                ":irc.test 366 jay_ #a :End of /NAMES list",
+            ],
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_disconnected() -> Result<()> {
+        let mut state = NetworkState::default();
+        update_all(
+            &mut state,
+            &[
+                ":irc.test 001 jay :Welcome",
+                ":jay!jsvana@localhost JOIN #a",
+            ],
+        )?;
+
+        let parts: Vec<String> = state
+            .disconnected("Connection closed")
+            .iter()
+            .map(|m| m.to_string())
+            .collect();
+        assert_eq!(
+            parts,
+            vec![":jay!jsvana@localhost PART #a :Connection closed"]
+        );
+        assert_eq!(
+            burst_lines(&state),
+            vec![
+                ":irc.test 001 jay Welcome",
+                ":irc.test 422 jay :MOTD File is missing",
+            ],
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rejoin() -> Result<()> {
+        let mut state = NetworkState::default();
+        update_all(&mut state, &[":irc.test 001 jay :Welcome"])?;
+        state.joining(&Message::from_str("JOIN #Secret,#b hunter2")?);
+        update_all(
+            &mut state,
+            &[
+                ":jay!jsvana@localhost JOIN #secret",
+                ":jay!jsvana@localhost JOIN #b",
+                ":jay!jsvana@localhost JOIN #c",
+                ":op!o@h MODE #c +k pw",
+                ":jay!jsvana@localhost JOIN #d",
+                ":jay!jsvana@localhost PART #d",
+            ],
+        )?;
+        state.disconnected("Connection closed");
+        update_all(&mut state, &[":irc.test 001 jay :Welcome back"])?;
+
+        let configured = vec![NetworkChannel {
+            name: "#B".to_string(),
+            key: None,
+            encoding: None,
+        }];
+        let channels: Vec<(String, Option<String>)> = state
+            .channels_to_join(&configured)
+            .into_iter()
+            .map(|channel| (channel.name, channel.key))
+            .collect();
+        assert_eq!(
+            channels,
+            vec![
+                ("#B".to_string(), None),
+                ("#c".to_string(), Some("pw".to_string())),
+                ("#secret".to_string(), Some("hunter2".to_string())),
+            ]
+        );
+
+        // Only rejoined once
+        assert_eq!(state.channels_to_join(&configured).len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_ison_replies() {
+        let mut state = NetworkState::default();
+        assert!(!state.take_ison_reply());
+
+        state.ison_sent();
+        state.ison_sent();
+        assert!(state.take_ison_reply());
+
+        // Outstanding ISONs won't be answered on the next connection
+        state.disconnected("Connection closed");
+        assert!(!state.take_ison_reply());
+    }
+
+    #[test]
+    fn test_kicked() -> Result<()> {
+        let mut state = NetworkState::default();
+        update_all(
+            &mut state,
+            &[
+                ":irc.test 001 jay :Welcome",
+                ":jay!jsvana@localhost JOIN #a",
+                ":op!o@h KICK #A jay :bye",
+            ],
+        )?;
+
+        assert_eq!(
+            burst_lines(&state),
+            vec![
+                ":irc.test 001 jay Welcome",
+                ":irc.test 422 jay :MOTD File is missing",
+            ],
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_log_targets() -> Result<()> {
+        let mut state = NetworkState::default();
+        update_all(
+            &mut state,
+            &[
+                ":irc.test 001 jay :Welcome",
+                ":irc.test 005 jay CHANTYPES=# PREFIX=(ov)@+ :are supported",
+                ":jay!j@host JOIN #Rust",
+                ":irc.test 353 jay = #Rust :jay @Alice[m]",
+                ":irc.test 366 jay #Rust :End of /NAMES list",
+                ":jay!j@host JOIN #other",
+            ],
+        )?;
+
+        let targets = |line: &str| -> Result<Vec<String>> {
+            Ok(state.log_targets(&Message::from_str(line)?))
+        };
+
+        assert_eq!(
+            targets(":Alice[m]!a@host PRIVMSG #RUST :hi")?,
+            vec!["#rust"]
+        );
+        assert_eq!(
+            targets(":Alice[m]!a@host NOTICE @#Rust :ops")?,
+            vec!["#rust"]
+        );
+        assert_eq!(
+            targets(":Alice[m]!a@host PRIVMSG jay :hi")?,
+            vec!["alice{m}"]
+        );
+        assert_eq!(
+            targets(":jay!j@host PRIVMSG Alice[m] :hi")?,
+            vec!["alice{m}"]
+        );
+        assert_eq!(
+            targets(":irc.test NOTICE jay :hello")?,
+            Vec::<String>::new()
+        );
+        assert_eq!(targets(":irc.test 372 jay :- MOTD")?, Vec::<String>::new());
+        assert_eq!(
+            targets(":Alice[m]!a@host MODE #Rust +v jay")?,
+            vec!["#rust"]
+        );
+        assert_eq!(targets(":jay!j@host MODE jay +i")?, Vec::<String>::new());
+        assert_eq!(targets(":Alice[m]!a@host QUIT :bye")?, vec!["#rust"]);
+        assert_eq!(targets(":jay!j@host NICK jay_")?, vec!["#other", "#rust"]);
+
+        Ok(())
+    }
+}